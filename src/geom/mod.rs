@@ -3,6 +3,7 @@
 
 pub mod aabb;
 pub mod intersect;
+pub mod noise;
 pub mod octree;
 pub mod raster;
 pub mod scene;
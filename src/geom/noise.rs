@@ -0,0 +1,147 @@
+//! Deterministic, seeded noise functions over 3D space, for breaking up the uniformity of
+//! effects on large surfaces.
+
+use ::cgmath::Vector3;
+
+/// Gradient directions for Perlin noise, the midpoints of the edges of a cube.
+const GRADIENTS : [[f32; 3]; 12] = [
+    [1.0, 1.0, 0.0], [-1.0, 1.0, 0.0], [1.0, -1.0, 0.0], [-1.0, -1.0, 0.0],
+    [1.0, 0.0, 1.0], [-1.0, 0.0, 1.0], [1.0, 0.0, -1.0], [-1.0, 0.0, -1.0],
+    [0.0, 1.0, 1.0], [0.0, -1.0, 1.0], [0.0, 1.0, -1.0], [0.0, -1.0, -1.0]
+];
+
+/// Mixes the given values into a pseudo-random 32 bit number.
+pub fn hash(seed: u32, x: i32, y: i32, z: i32) -> u32 {
+    let mut h = seed ^ 0x9e37_79b9;
+    for &v in &[x as u32, y as u32, z as u32] {
+        h ^= v.wrapping_mul(0x85eb_ca6b);
+        h = h.rotate_left(13).wrapping_mul(5).wrapping_add(0xe654_6b64);
+    }
+
+    // Finalizer of MurmurHash3
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2_ae35);
+    h ^ (h >> 16)
+}
+
+/// Maps a hash onto the interval 0..1.
+fn unit(hash: u32) -> f32 {
+    (hash >> 8) as f32 / (1u32 << 24) as f32
+}
+
+/// Gets a random value in 0..1 for the exact given position, which is always the same for
+/// the same position and seed, but uncorrelated even for very close positions.
+pub fn white(seed: u32, position: Vector3<f32>) -> f32 {
+    unit(hash(seed, position.x.to_bits() as i32, position.y.to_bits() as i32, position.z.to_bits() as i32))
+}
+
+/// Gets smooth gradient noise in the interval 0..1 that varies on the scale of one unit.
+///
+/// Scale the position to change the size of features.
+pub fn perlin(seed: u32, position: Vector3<f32>) -> f32 {
+    let cell = Vector3::new(position.x.floor(), position.y.floor(), position.z.floor());
+    let local = position - cell;
+    let (x, y, z) = (cell.x as i32, cell.y as i32, cell.z as i32);
+
+    let fade = |t: f32| t * t * t * (t * (t * 6.0 - 15.0) + 10.0);
+    let lerp = |a: f32, b: f32, t: f32| a + t * (b - a);
+
+    let corner = |dx: i32, dy: i32, dz: i32| {
+        let gradient = GRADIENTS[(hash(seed, x + dx, y + dy, z + dz) % 12) as usize];
+        gradient[0] * (local.x - dx as f32) + gradient[1] * (local.y - dy as f32) + gradient[2] * (local.z - dz as f32)
+    };
+
+    let (u, v, w) = (fade(local.x), fade(local.y), fade(local.z));
+
+    let value = lerp(
+        lerp(
+            lerp(corner(0, 0, 0), corner(1, 0, 0), u),
+            lerp(corner(0, 1, 0), corner(1, 1, 0), u),
+            v
+        ),
+        lerp(
+            lerp(corner(0, 0, 1), corner(1, 0, 1), u),
+            lerp(corner(0, 1, 1), corner(1, 1, 1), u),
+            v
+        ),
+        w
+    );
+
+    // Gradient noise with these gradients stays within -1..1
+    (0.5 + 0.5 * value).clamp(0.0, 1.0)
+}
+
+/// Gets cellular noise in the interval 0..1, the distance to the nearest of randomly placed
+/// feature points, about one per unit cube. Values are low close to feature points, forming
+/// spots, and high in between.
+///
+/// Scale the position to change the size of spots.
+pub fn worley(seed: u32, position: Vector3<f32>) -> f32 {
+    let cell = Vector3::new(position.x.floor(), position.y.floor(), position.z.floor());
+    let (x, y, z) = (cell.x as i32, cell.y as i32, cell.z as i32);
+
+    let mut nearest_sqr = f32::INFINITY;
+
+    for dx in -1..2 {
+        for dy in -1..2 {
+            for dz in -1..2 {
+                let h = hash(seed, x + dx, y + dy, z + dz);
+                let feature = Vector3::new(
+                    (x + dx) as f32 + unit(h),
+                    (y + dy) as f32 + unit(hash(h, 1, 0, 0)),
+                    (z + dz) as f32 + unit(hash(h, 0, 1, 0))
+                );
+
+                let offset = feature - position;
+                nearest_sqr = nearest_sqr.min(offset.x * offset.x + offset.y * offset.y + offset.z * offset.z);
+            }
+        }
+    }
+
+    nearest_sqr.sqrt().min(1.0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn positions() -> Vec<Vector3<f32>> {
+        (0..200).map(|i| Vector3::new(i as f32 * 0.173, i as f32 * -0.311, i as f32 * 0.057)).collect()
+    }
+
+    #[test]
+    fn test_noise_in_unit_interval_and_deterministic() {
+        for position in positions() {
+            for &noise in &[white, perlin, worley] {
+                let value = noise(7, position);
+                assert!(value >= 0.0 && value <= 1.0, "Noise out of range: {}", value);
+                assert_eq!(value, noise(7, position));
+            }
+        }
+    }
+
+    #[test]
+    fn test_seed_changes_noise() {
+        let differing = positions().into_iter()
+            .filter(|&p| perlin(1, p) != perlin(2, p))
+            .count();
+
+        assert!(differing > 150);
+    }
+
+    #[test]
+    fn test_perlin_is_smooth() {
+        for position in positions() {
+            let step = Vector3::new(0.001, 0.0, 0.0);
+            assert!((perlin(3, position) - perlin(3, position + step)).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn test_white_noise_is_spread() {
+        let mean = positions().into_iter().map(|p| white(0, p)).sum::<f32>() / 200.0;
+        assert!((mean - 0.5).abs() < 0.1, "Mean of white noise should be about 0.5, got {}", mean);
+    }
+}
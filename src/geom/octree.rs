@@ -65,6 +65,19 @@ fn octants(aabb: &Aabb) -> [Aabb; 8] {
 impl<T> Octree<T>
     where T : Spatial
 {
+    /// Iterates over all objects in the octree, in the same order for octrees built from the
+    /// same objects in the same order.
+    pub fn iter<'a>(&'a self) -> Box<Iterator<Item = &'a T> + 'a> {
+        Box::new(
+            self.data.iter()
+                .chain(
+                    self.children.iter()
+                        .filter_map(|c| c.as_ref())
+                        .flat_map(|c| c.iter())
+                )
+        )
+    }
+
     #[cfg(test)]
     fn node_count(&self) -> usize {
         1 + self.children.iter()
@@ -0,0 +1,138 @@
+use ::geom::tri::Triangle;
+use ::geom::vtx::{Position, Normal, Texcoords};
+
+use ::cgmath::Vector3;
+use ::cgmath::prelude::*;
+
+/// Generates approximately `budget` surface samples, distributing them over the triangles
+/// in proportion to their area multiplied with a density weight per triangle.
+///
+/// Each triangle receives its share of the budget rounded with the largest remainder method,
+/// so exactly `budget` samples are generated if any triangle has a positive weight. Positions
+/// within a triangle are random, like with `sample_with_density`.
+///
+/// To create a sample from a chosen surface position, the passed function is invoked.
+pub fn sample_adaptive<I, V, W, F, S>(triangles: I, budget: usize, triangle_density_weight: W, triangle_and_sample_pos_to_sample: F) -> Vec<S>
+    where I : IntoIterator<Item = Triangle<V>>,
+        V : Position,
+        W : Fn(&Triangle<V>) -> f32,
+        F : Fn(&Triangle<V>, Vector3<f32>) -> S
+{
+    let triangles : Vec<Triangle<V>> = triangles.into_iter().collect();
+    let weights : Vec<f32> = triangles.iter()
+        .map(|t| t.area() * triangle_density_weight(t).max(0.0))
+        .collect();
+
+    let counts = apportion(&weights, budget);
+
+    info!("Adaptively sampling {} surfels on {} triangles...", budget, triangles.len());
+
+    let mut samples = Vec::with_capacity(budget);
+
+    for (tri, count) in triangles.iter().zip(counts) {
+        (0..count).for_each(|_| samples.push(triangle_and_sample_pos_to_sample(tri, tri.sample_position())));
+    }
+
+    samples
+}
+
+/// Divides a total amount into integer parts proportional to the given weights with the
+/// largest remainder method, so the parts sum up to exactly the total.
+///
+/// Returns only zeroes if all weights are zero.
+pub fn apportion(weights: &[f32], total: usize) -> Vec<usize> {
+    let weight_sum : f64 = weights.iter().map(|&w| w as f64).sum();
+
+    if weight_sum <= 0.0 {
+        return vec![0; weights.len()];
+    }
+
+    let quotas : Vec<f64> = weights.iter()
+        .map(|&w| (w as f64) / weight_sum * (total as f64))
+        .collect();
+
+    let mut counts : Vec<usize> = quotas.iter().map(|q| q.floor() as usize).collect();
+    let assigned : usize = counts.iter().sum();
+
+    // Hand out the rest to the parts that lost the most by rounding down
+    let mut by_remainder : Vec<usize> = (0..weights.len()).collect();
+    by_remainder.sort_by(|&a, &b| {
+        let remainder_a = quotas[a] - quotas[a].floor();
+        let remainder_b = quotas[b] - quotas[b].floor();
+        remainder_b.partial_cmp(&remainder_a).unwrap()
+    });
+
+    for &idx in by_remainder.iter().take(total.saturating_sub(assigned)) {
+        counts[idx] += 1;
+    }
+
+    counts
+}
+
+/// Estimates the curvature of a triangle from the angle between its vertex normals divided by
+/// its size, in radians per world unit. Flat triangles get zero.
+pub fn triangle_curvature<V>(triangle: &Triangle<V>) -> f32
+    where V : Position + Normal
+{
+    let size = triangle.area().sqrt();
+
+    if size <= 0.0 {
+        return 0.0;
+    }
+
+    let normals = [
+        triangle.vertices[0].normal().normalize(),
+        triangle.vertices[1].normal().normalize(),
+        triangle.vertices[2].normal().normalize()
+    ];
+
+    let min_cos = normals[0].dot(normals[1])
+        .min(normals[1].dot(normals[2]))
+        .min(normals[2].dot(normals[0]));
+
+    min_cos.clamp(-1.0, 1.0).acos() / size
+}
+
+/// Calculates how many texels of a texture with the given resolution cover one world unit of
+/// length on the triangle, by comparing the area of the triangle in texture space and in
+/// world space.
+pub fn texels_per_unit<V>(triangle: &Triangle<V>, texture_width: usize, texture_height: usize) -> f32
+    where V : Position + Texcoords
+{
+    let world_area = triangle.area();
+
+    if world_area <= 0.0 {
+        return 0.0;
+    }
+
+    let uv0 = triangle.vertices[0].texcoords();
+    let uv1 = triangle.vertices[1].texcoords();
+    let uv2 = triangle.vertices[2].texcoords();
+
+    let uv_area = 0.5 * ((uv1 - uv0).perp_dot(uv2 - uv0)).abs();
+    let texel_area = uv_area * (texture_width as f32) * (texture_height as f32);
+
+    (texel_area / world_area).sqrt()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_apportion_hits_total() {
+        let counts = apportion(&[1.0, 1.0, 1.0], 10);
+        assert_eq!(counts.iter().sum::<usize>(), 10);
+        assert!(counts.iter().all(|&c| c == 3 || c == 4));
+    }
+
+    #[test]
+    fn test_apportion_is_proportional() {
+        assert_eq!(apportion(&[3.0, 1.0, 0.0], 8), vec![6, 2, 0]);
+    }
+
+    #[test]
+    fn test_apportion_without_weight() {
+        assert_eq!(apportion(&[0.0, 0.0], 8), vec![0, 0]);
+    }
+}
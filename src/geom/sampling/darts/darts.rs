@@ -1,12 +1,10 @@
 
 use super::vtx::SparseVertex;
 
-use ::geom::sampling::TriangleBins;
+use ::geom::sampling::{TriangleBins, PointGrid};
 use ::geom::tri::Triangle;
 use ::geom::vtx::Position;
 
-use ::kdtree::kdtree::Kdtree;
-
 use ::cgmath::Vector3;
 
 use std::f32::consts::PI;
@@ -17,7 +15,9 @@ pub struct Darts {
     min_point_distance: f64,
     /// Do not split a triangle if the resulting subfragments would have a smaller area than this
     disregard_area: f32,
-    previous_samples: Option<Kdtree<SparseVertex>>
+    /// Spatial hash with a cell size of the minimum distance, so neighbourhood queries only
+    /// need to look at adjacent cells
+    previous_samples: PointGrid<()>
 }
 
 impl Darts
@@ -43,46 +43,34 @@ impl Darts
 
         Darts {
             active_triangles,
-            previous_samples: None,
+            previous_samples: PointGrid::new(min_point_distance as f64),
             min_point_distance: min_point_distance as f64,
             disregard_area
         }
     }
 
     fn meets_minimum_distance_requirement(&self, vtx: &SparseVertex) -> bool {
-        if let Some(ref previous_samples) = self.previous_samples {
-            !previous_samples.has_neighbor_in_range(vtx, self.min_point_distance)
-        } else {
-            true
-        }
+        !self.previous_samples.has_neighbor_in_range(&vtx.position, self.min_point_distance)
     }
 
     fn add_sample(&mut self, vtx: SparseVertex) {
-        if let Some(ref mut previous_samples) = self.previous_samples {
-            previous_samples.insert_node(vtx);
-        } else {
-            self.previous_samples = Some(Kdtree::new(&mut [ vtx ]));
-        }
+        self.previous_samples.insert(vtx.position, ());
     }
 
+    /// A fragment is covered if it lies completely inside the sphere around a previous sample,
+    /// so no new sample could ever be placed on it.
     fn is_covered(&self, fragment: &Triangle<SparseVertex>) -> bool {
-        if let Some(ref previous_samples) = self.previous_samples {
-            let proposed_covering_point = fragment.minimum_bounding_sphere_center();
-
-            let nearest = previous_samples.nearest_search(&SparseVertex {
-                mother_triangle_idx: None,
-                position: [proposed_covering_point.x as f64, proposed_covering_point.y as f64, proposed_covering_point.z as f64]
-            });
-            let nearest_position = Vector3::new(
-                nearest.position[0] as f32,
-                nearest.position[1] as f32,
-                nearest.position[2] as f32
-            );
-
-            fragment.is_inside_sphere(nearest_position, 0.5 * self.min_point_distance as f32)
-        } else {
-            false
-        }
+        let covering_radius = 0.5 * self.min_point_distance;
+        let center = fragment.minimum_bounding_sphere_center();
+
+        // Only samples closer to the bounding sphere center than the covering radius can contain
+        // the whole fragment
+        self.previous_samples.within(&[center.x as f64, center.y as f64, center.z as f64], covering_radius)
+            .into_iter()
+            .any(|(position, _)| fragment.is_inside_sphere(
+                Vector3::new(position[0] as f32, position[1] as f32, position[2] as f32),
+                covering_radius as f32
+            ))
     }
 }
 
@@ -25,11 +25,13 @@ pub fn throw_darts<I, V, F, S>(triangles: I, minimum_sample_distance: f32, trian
     let start_time = Instant::now();
 
     let mut sampled = 0;
+    // Log about five times, but do not divide by zero for tiny scenes
+    let log_interval = (fat_triangle_count / 5).max(1);
 
     let samples = Darts::new(fat_triangles.iter(), minimum_sample_distance)
         .inspect(|_| {
             sampled += 1;
-            if sampled % (fat_triangle_count / 5) == 0 {
+            if sampled % log_interval == 0 {
                 info!("{} points sampled...", sampled);
             }
         })
@@ -40,3 +42,79 @@ pub fn throw_darts<I, V, F, S>(triangles: I, minimum_sample_distance: f32, trian
 
     samples
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::cgmath::prelude::*;
+    use ::geom::sampling::PointGrid;
+
+    #[test]
+    fn test_darts_keep_minimum_distance_on_single_triangle() {
+        let triangle = Triangle::new(
+            Vector3::new(0.0, 0.0, 0.0),
+            Vector3::new(1.0, 0.0, 0.0),
+            Vector3::new(0.0, 1.0, 0.0)
+        );
+
+        let samples = throw_darts(vec![triangle], 0.05, |_, position| position);
+
+        assert!(samples.len() > 50, "Expected a dense sampling, got {} samples", samples.len());
+        for (idx, a) in samples.iter().enumerate() {
+            for b in samples.iter().skip(idx + 1) {
+                assert!(a.distance(*b) >= 0.05);
+            }
+        }
+    }
+
+    /// Square plane in the XY plane made of two triangles per unit square.
+    fn plane(edge: usize) -> Vec<Triangle<Vector3<f32>>> {
+        (0..edge * edge)
+            .flat_map(|idx| {
+                let (x, y) = ((idx % edge) as f32, (idx / edge) as f32);
+                vec![
+                    Triangle::new(Vector3::new(x, y, 0.0), Vector3::new(x + 1.0, y, 0.0), Vector3::new(x, y + 1.0, 0.0)),
+                    Triangle::new(Vector3::new(x + 1.0, y, 0.0), Vector3::new(x + 1.0, y + 1.0, 0.0), Vector3::new(x, y + 1.0, 0.0))
+                ]
+            })
+            .collect()
+    }
+
+    /// Samples millions of surfels at the spacing of the buddha scene, takes about half a minute
+    /// in debug builds. Run with `cargo test --release -- --ignored`.
+    #[test]
+    #[ignore]
+    fn test_darts_scale_linearly_with_sample_count() {
+        let distance = 0.02;
+        let timed = |edge| {
+            let start = Instant::now();
+            let samples = throw_darts(plane(edge), distance, |_, position| position);
+            let elapsed = start.elapsed();
+            let seconds = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) * 1e-9;
+            (samples, seconds)
+        };
+
+        let (small, small_seconds) = timed(8);
+        let (large, large_seconds) = timed(32);
+
+        // Sixteen times the area gives roughly sixteen times the samples
+        let count_ratio = large.len() as f64 / small.len() as f64;
+        assert!(count_ratio > 14.0 && count_ratio < 18.0, "Unexpected sample count ratio {}", count_ratio);
+        assert!(large.len() > 1_000_000, "Expected millions of samples, got {}", large.len());
+
+        // Time per sample must not grow much with the sample count
+        let slowdown = (large_seconds / large.len() as f64) / (small_seconds / small.len() as f64);
+        assert!(
+            slowdown < 2.0,
+            "Time per sample grew {}x from {} samples in {}s to {} samples in {}s",
+            slowdown, small.len(), small_seconds, large.len(), large_seconds
+        );
+
+        let mut grid = PointGrid::new(f64::from(distance));
+        for sample in &large {
+            let position = [f64::from(sample.x), f64::from(sample.y), f64::from(sample.z)];
+            assert!(!grid.has_neighbor_in_range(&position, f64::from(distance) * 0.999));
+            grid.insert(position, ());
+        }
+    }
+}
@@ -4,8 +4,6 @@ use ::cgmath::Vector3;
 
 use std::ops::{Mul, Add};
 
-use ::kdtree::kdtree::KdtreePointTrait;
-
 /// Vertex consisting of position and the index of a triangle that
 /// this vertex originated from. By having a small vertex type, we can
 /// more cheaply create new triangles and interpolate vertices.
@@ -58,10 +56,3 @@ impl Add for SparseVertex {
         }
     }
 }
-
-impl KdtreePointTrait for SparseVertex {
-    #[inline]
-    fn dims(&self) -> &[f64] {
-        &self.position
-    }
-}
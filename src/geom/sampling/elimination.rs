@@ -0,0 +1,242 @@
+use ::geom::tri::Triangle;
+use ::geom::vtx::Position;
+
+use super::adaptive::apportion;
+use super::grid::PointGrid;
+
+use ::cgmath::Vector3;
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::time::Instant;
+
+/// How many more random points are generated than the target amount before eliminating.
+const OVERSAMPLING : usize = 5;
+
+/// Exponent of the weight function, as recommended by Yuksel.
+const WEIGHT_EXPONENT : i32 = 8;
+
+/// Generates exactly `target_count` samples with blue noise properties using weighted sample
+/// elimination (Yuksel 2015, "Sample Elimination for Generating Poisson Disk Sample Sets").
+///
+/// First, five times as many random points as requested are placed on the triangles, in
+/// proportion to their area. Then, the points with the most close neighbours are removed
+/// one after another until the target count is reached.
+///
+/// To create a sample from a chosen surface position, the passed function is invoked.
+pub fn eliminate_samples<I, V, F, S>(triangles: I, target_count: usize, triangle_and_sample_pos_to_sample: F) -> Vec<S>
+    where I : IntoIterator<Item = Triangle<V>>,
+        V : Position,
+        F : Fn(&Triangle<V>, Vector3<f32>) -> S
+{
+    let triangles : Vec<Triangle<V>> = triangles.into_iter().collect();
+    let areas : Vec<f32> = triangles.iter().map(|t| t.area()).collect();
+    let total_area : f32 = areas.iter().sum();
+
+    info!("Eliminating samples down to {}...", target_count);
+    let start_time = Instant::now();
+
+    // Remember the triangle of each candidate for making the final samples
+    let candidates : Vec<(usize, Vector3<f32>)> = apportion(&areas, OVERSAMPLING * target_count)
+        .into_iter()
+        .enumerate()
+        .flat_map(|(tri_idx, count)| {
+            let tri = &triangles[tri_idx];
+            (0..count).map(move |_| (tri_idx, tri.sample_position()))
+        })
+        .collect();
+
+    let positions : Vec<Vector3<f32>> = candidates.iter().map(|&(_, p)| p).collect();
+    let kept = eliminate(&positions, target_count, max_poisson_radius(total_area, target_count));
+
+    info!("Ok, took {}s", start_time.elapsed().as_secs());
+
+    kept.into_iter()
+        .map(|idx| {
+            let (tri_idx, position) = candidates[idx];
+            triangle_and_sample_pos_to_sample(&triangles[tri_idx], position)
+        })
+        .collect()
+}
+
+/// Calculates how many samples are needed to cover the given surface area with the given
+/// spacing between neighbouring samples in a hexagonal packing.
+pub fn sample_count_for_spacing(total_area: f32, spacing: f32) -> usize {
+    assert!(spacing > 0.0, "Sample spacing must be positive, got {}", spacing);
+    // Each sample takes up a hexagon with an inner diameter of the spacing
+    (2.0 * total_area / (3.0_f32.sqrt() * spacing * spacing)).round() as usize
+}
+
+/// Calculates the maximum Poisson disk radius r_max of Yuksel, the radius of non-overlapping disks
+/// around the given amount of samples in a hexagonal packing on a surface with the given area.
+///
+/// Neighbouring samples of such a packing are two radii apart.
+fn max_poisson_radius(total_area: f32, sample_count: usize) -> f32 {
+    if sample_count == 0 {
+        return 0.0;
+    }
+
+    (total_area / (2.0 * 3.0_f32.sqrt() * sample_count as f32)).sqrt()
+}
+
+/// Eliminates points until only the target count is left and returns the indexes of the
+/// remaining points.
+fn eliminate(positions: &[Vector3<f32>], target_count: usize, max_radius: f32) -> Vec<usize> {
+    if positions.len() <= target_count || max_radius <= 0.0 {
+        return (0..positions.len().min(target_count)).collect();
+    }
+
+    let neighbourhood = 2.0 * max_radius as f64;
+    let neighbours = find_neighbours(positions, neighbourhood);
+
+    let weight = |distance: f64| (1.0 - distance / neighbourhood).powi(WEIGHT_EXPONENT) as f32;
+
+    let mut weights : Vec<f32> = neighbours.iter()
+        .map(|n| n.iter().map(|&(_, d)| weight(d)).sum())
+        .collect();
+    let mut eliminated = vec![false; positions.len()];
+
+    let mut heap : BinaryHeap<Candidate> = weights.iter()
+        .enumerate()
+        .map(|(idx, &weight)| Candidate { weight, idx })
+        .collect();
+
+    let mut remaining = positions.len();
+
+    while remaining > target_count {
+        let Candidate { weight: candidate_weight, idx } = heap.pop()
+            .expect("Heap should not run out before reaching the target count");
+
+        // Skip entries that were pushed before the weight decreased
+        if eliminated[idx] || candidate_weight != weights[idx] {
+            continue;
+        }
+
+        eliminated[idx] = true;
+        remaining -= 1;
+
+        for &(neighbour_idx, distance) in &neighbours[idx] {
+            if !eliminated[neighbour_idx] {
+                weights[neighbour_idx] -= weight(distance);
+                heap.push(Candidate { weight: weights[neighbour_idx], idx: neighbour_idx });
+            }
+        }
+    }
+
+    (0..positions.len())
+        .filter(|&idx| !eliminated[idx])
+        .collect()
+}
+
+/// Finds the indexes of and distances to all other points within the given distance of each point.
+fn find_neighbours(positions: &[Vector3<f32>], distance: f64) -> Vec<Vec<(usize, f64)>> {
+    let to_array = |p: &Vector3<f32>| [p.x as f64, p.y as f64, p.z as f64];
+
+    let mut grid = PointGrid::new(distance);
+    for (idx, position) in positions.iter().enumerate() {
+        grid.insert(to_array(position), idx);
+    }
+
+    positions.iter()
+        .enumerate()
+        .map(|(idx, position)| {
+            let position = to_array(position);
+            grid.within(&position, distance)
+                .into_iter()
+                .filter(|&(_, &other_idx)| other_idx != idx)
+                .map(|(other, &other_idx)| {
+                    let dx = other[0] - position[0];
+                    let dy = other[1] - position[1];
+                    let dz = other[2] - position[2];
+                    (other_idx, (dx * dx + dy * dy + dz * dz).sqrt())
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Entry in the elimination heap, ordered by weight.
+struct Candidate {
+    weight: f32,
+    idx: usize
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Candidate) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Candidate) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Candidate) -> Ordering {
+        self.weight.partial_cmp(&other.weight)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| self.idx.cmp(&other.idx))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::cgmath::prelude::*;
+
+    fn unit_square() -> Vec<Triangle<Vector3<f32>>> {
+        vec![
+            Triangle::new(Vector3::new(0.0, 0.0, 0.0), Vector3::new(1.0, 0.0, 0.0), Vector3::new(1.0, 1.0, 0.0)),
+            Triangle::new(Vector3::new(0.0, 0.0, 0.0), Vector3::new(1.0, 1.0, 0.0), Vector3::new(0.0, 1.0, 0.0))
+        ]
+    }
+
+    fn min_distance(samples: &[Vector3<f32>]) -> f32 {
+        let mut min = ::std::f32::INFINITY;
+        for (idx, a) in samples.iter().enumerate() {
+            for b in samples.iter().skip(idx + 1) {
+                min = min.min(a.distance(*b));
+            }
+        }
+        min
+    }
+
+    #[test]
+    fn test_elimination_hits_target_count() {
+        let samples = eliminate_samples(unit_square(), 200, |_, p| p);
+        assert_eq!(samples.len(), 200);
+    }
+
+    #[test]
+    fn test_elimination_spreads_samples() {
+        let samples = eliminate_samples(unit_square(), 200, |_, p| p);
+        let r_max = max_poisson_radius(1.0, 200);
+
+        // Yuksel reports minimum distances of well above half of r_max
+        assert!(min_distance(&samples) > 0.4 * r_max, "Samples too close: {} with r_max {}", min_distance(&samples), r_max);
+    }
+
+    #[test]
+    fn test_count_for_spacing_matches_spacing() {
+        let spacing = 0.05;
+        let samples = eliminate_samples(unit_square(), sample_count_for_spacing(1.0, spacing), |_, p| p);
+
+        let mean_nearest = samples.iter()
+            .map(|a| samples.iter()
+                .filter(|&b| b != a)
+                .map(|b| a.distance(*b))
+                .fold(::std::f32::INFINITY, f32::min))
+            .sum::<f32>() / samples.len() as f32;
+
+        // Elimination does not reach a perfect hexagonal packing, neighbours end up at about
+        // 80% of the spacing on average
+        assert!(
+            mean_nearest > 0.7 * spacing && mean_nearest < 1.1 * spacing,
+            "Mean distance to nearest neighbour {} should be close to the spacing {}", mean_nearest, spacing
+        );
+    }
+}
@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+
+type Cell = (i64, i64, i64);
+
+/// Spatial hash of points, bucketing them into cubic cells for fast range queries.
+///
+/// Unlike a kd-tree, insertion is constant time and does not degrade with the order of
+/// insertion, which makes it a good fit for incrementally generated sample sets. Queries are
+/// fastest for radiuses in the order of the cell size.
+pub struct PointGrid<T> {
+    cell_size: f64,
+    cells: HashMap<Cell, Vec<([f64; 3], T)>>
+}
+
+impl<T> PointGrid<T> {
+    /// Creates an empty grid with the given edge length of cells.
+    pub fn new(cell_size: f64) -> PointGrid<T> {
+        assert!(cell_size > 0.0, "Cell size of point grid must be positive, got {}", cell_size);
+
+        PointGrid {
+            cell_size,
+            cells: HashMap::new()
+        }
+    }
+
+    pub fn insert(&mut self, position: [f64; 3], data: T) {
+        let cell = self.cell_of(&position);
+        self.cells.entry(cell)
+            .or_default()
+            .push((position, data));
+    }
+
+    /// Checks if any point is strictly closer to the given position than the given distance.
+    ///
+    /// Stops at the first such point, so queries in densely sampled regions stay cheap.
+    pub fn has_neighbor_in_range(&self, position: &[f64; 3], distance: f64) -> bool {
+        let distance_sqr = distance * distance;
+
+        self.any_candidate(position, distance, |point, _| squared_distance(point, position) < distance_sqr)
+    }
+
+    /// Gets the positions and data of all points within the given distance of the given position.
+    pub fn within(&self, position: &[f64; 3], distance: f64) -> Vec<(&[f64; 3], &T)> {
+        let distance_sqr = distance * distance;
+        let mut result = Vec::new();
+
+        self.any_candidate(position, distance, |point, data| {
+            if squared_distance(point, position) <= distance_sqr {
+                result.push((point, data));
+            }
+            false
+        });
+
+        result
+    }
+
+    /// Invokes the given predicate on the points in cells that overlap the cube around the position
+    /// with the given half edge length, until it returns true for one of them.
+    ///
+    /// Returns whether the predicate held for any point.
+    fn any_candidate<'a, F>(&'a self, position: &[f64; 3], distance: f64, mut predicate: F) -> bool
+        where F : FnMut(&'a [f64; 3], &'a T) -> bool
+    {
+        let min = self.cell_of(&[position[0] - distance, position[1] - distance, position[2] - distance]);
+        let max = self.cell_of(&[position[0] + distance, position[1] + distance, position[2] + distance]);
+
+        for x in min.0..(max.0 + 1) {
+            for y in min.1..(max.1 + 1) {
+                for z in min.2..(max.2 + 1) {
+                    if let Some(points) = self.cells.get(&(x, y, z)) {
+                        if points.iter().any(|(point, data)| predicate(point, data)) {
+                            return true;
+                        }
+                    }
+                }
+            }
+        }
+
+        false
+    }
+
+    fn cell_of(&self, position: &[f64; 3]) -> Cell {
+        (
+            (position[0] / self.cell_size).floor() as i64,
+            (position[1] / self.cell_size).floor() as i64,
+            (position[2] / self.cell_size).floor() as i64
+        )
+    }
+}
+
+fn squared_distance(a: &[f64; 3], b: &[f64; 3]) -> f64 {
+    let dx = a[0] - b[0];
+    let dy = a[1] - b[1];
+    let dz = a[2] - b[2];
+    dx * dx + dy * dy + dz * dz
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_neighbors_across_cells() {
+        let mut grid = PointGrid::new(1.0);
+        grid.insert([0.95, 0.0, 0.0], 0);
+        grid.insert([-3.0, 0.0, 0.0], 1);
+
+        assert!(grid.has_neighbor_in_range(&[1.05, 0.0, 0.0], 0.2));
+        assert!(!grid.has_neighbor_in_range(&[1.5, 0.0, 0.0], 0.2));
+
+        let within : Vec<i32> = grid.within(&[-2.5, 0.0, 0.0], 1.0).into_iter().map(|(_, &d)| d).collect();
+        assert_eq!(within, vec![1]);
+    }
+
+    /// Grid with points on the integer lattice in a cube with the given edge length.
+    fn lattice(edge: i32) -> PointGrid<()> {
+        let mut grid = PointGrid::new(1.0);
+        for x in 0..edge {
+            for y in 0..edge {
+                for z in 0..edge {
+                    grid.insert([x as f64, y as f64, z as f64], ());
+                }
+            }
+        }
+        grid
+    }
+
+    #[test]
+    fn test_query_cost_independent_of_point_count() {
+        let visited = |grid: &PointGrid<()>, position: &[f64; 3]| {
+            let mut visited = 0;
+            grid.any_candidate(position, 1.0, |_, _| { visited += 1; false });
+            visited
+        };
+
+        let small = lattice(8);
+        let large = lattice(64);
+        let center = [4.5, 4.5, 4.5];
+
+        // Same density, so the same amount of points is looked at regardless of the total count
+        assert_eq!(visited(&small, &center), visited(&large, &center));
+        assert_eq!(visited(&large, &center), 27);
+
+        // Stops at the first point in range
+        let mut visited = 0;
+        assert!(large.any_candidate(&center, 1.0, |_, _| { visited += 1; true }));
+        assert_eq!(visited, 1);
+
+        assert!(large.has_neighbor_in_range(&[31.5, 31.5, 31.5], 1.0));
+        assert!(!large.has_neighbor_in_range(&[31.5, 31.5, 31.5], 0.5));
+    }
+}
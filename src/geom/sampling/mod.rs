@@ -1,10 +1,16 @@
+mod adaptive;
 mod darts;
 mod density;
+mod elimination;
+mod grid;
 mod sphere;
 mod triangle_bins;
 
+pub use self::adaptive::{sample_adaptive, triangle_curvature, texels_per_unit};
 pub use self::darts::{Darts, throw_darts};
 pub use self::density::sample_with_density;
+pub use self::elimination::{eliminate_samples, sample_count_for_spacing};
+pub use self::grid::PointGrid;
 pub use self::sphere::{
     uniform_on_unit_sphere,
     uniform_on_unit_z_hemisphere
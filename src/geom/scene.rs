@@ -9,12 +9,15 @@ use ::cgmath::{Vector2, Vector3};
 
 use super::tri;
 use super::vtx;
+use super::octree::Octree;
 use super::intersect::IntersectRay;
 use super::spatial::Spatial;
 use super::aabb::Aabb;
 
 use std::f32::{INFINITY, NEG_INFINITY, NAN};
 use std::ops::{Mul, Add};
+use std::hash::Hasher;
+use std::collections::hash_map::DefaultHasher;
 
 pub type Triangle = tri::Triangle<Vertex>;
 
@@ -110,6 +113,21 @@ impl Scene {
         }
     }
 
+    /// Creates a scene without materials holding a single entity with the given mesh,
+    /// which refers to the material at index zero.
+    #[cfg(test)]
+    pub fn with_mesh(mesh: Mesh) -> Scene {
+        let mut scene = Scene::empty();
+        scene.entities.push(Entity {
+            name: String::from("mesh"),
+            entity_idx: 0,
+            material_idx: 0,
+            original_material_idx: 0,
+            mesh
+        });
+        scene
+    }
+
     /// Loads the obj file at the given file system path into a newly created scene
     /// All contained models will be merged into a single mesh
     pub fn load_from_file(obj_file_path: &str) -> Scene {
@@ -289,3 +307,39 @@ impl Entity {
         )
     }
 }
+
+/// Hashes the vertex positions of the triangles in the octree, so that values computed from the
+/// geometry of the scene can tell if triangles were added, removed or moved since.
+pub fn geometry_fingerprint(octree: &Octree<Triangle>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+
+    for triangle in octree.iter() {
+        for vertex in &triangle.vertices {
+            hasher.write_u32(vertex.position.x.to_bits());
+            hasher.write_u32(vertex.position.y.to_bits());
+            hasher.write_u32(vertex.position.z.to_bits());
+        }
+    }
+
+    hasher.finish()
+}
+
+/// Creates a horizontal triangle around the given center, reaching `extent` units out on the
+/// x and z axes. The vertex normals point up for a positive `normal_y` and down otherwise, and
+/// the triangle is wound so that its face normal points the same way.
+#[cfg(test)]
+pub fn horizontal_triangle(center: Vector3<f32>, extent: f32, normal_y: f32) -> Triangle {
+    let vertex = |x: f32, z: f32| Vertex {
+        position: Vector3::new(center.x + x, center.y, center.z + z),
+        normal: Vector3::new(0.0, normal_y, 0.0),
+        texcoords: Vector2::new(0.0, 0.0),
+        material_idx: 0,
+        entity_idx: 0
+    };
+
+    if normal_y > 0.0 {
+        Triangle::new(vertex(-extent, -extent), vertex(0.0, extent), vertex(extent, -extent))
+    } else {
+        Triangle::new(vertex(-extent, -extent), vertex(extent, -extent), vertex(0.0, extent))
+    }
+}
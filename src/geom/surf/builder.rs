@@ -2,14 +2,16 @@ use super::*;
 
 use ::cgmath::{Vector2, Vector3};
 use ::cgmath::prelude::*;
-use ::nearest_kdtree::KdTree;
-use ::geom::sampling::{throw_darts, sample_with_density};
+use ::geom::sampling::{throw_darts, sample_with_density, sample_adaptive, triangle_curvature, texels_per_unit, eliminate_samples, sample_count_for_spacing};
 use ::geom::scene::Triangle;
+use ::substance::{SubstanceRef, SubstanceRegistry};
 
 use std::collections::HashMap;
 
 pub struct SurfaceBuilder {
     samples: Vec<Surfel>,
+    /// Capacities and delta modifiers shared by the generated surfels
+    surfel_materials: Vec<SurfelMaterial>,
     /// Initial deterioration rate of the probability of a gammaton moving further in a straight line
     delta_straight: f32,
     /// Initial deterioration rate of the probability of a gammaton moving in a piecewise approximated parabolic path
@@ -18,8 +20,28 @@ pub struct SurfaceBuilder {
     delta_flow: f32,
     /// Holds the initial amount of substances as numbers in the interval 0..1
     substances: Vec<f32>,
+    /// Declared substances that can be referred to by name, empty if substances have no names
+    substance_registry: SubstanceRegistry,
     deposition_rates: Vec<f32>,
+    /// Deposition rates of single substances, applied to `deposition_rates` in order
+    deposition_rate_overrides: Vec<(SubstanceRef, f32)>,
+    /// Maximum amount of each substance a surfel can absorb, 1.0 for all substances if empty
+    capacities: Vec<f32>,
+    /// Capacities of single substances, applied to `capacities` in order
+    capacity_overrides: Vec<(SubstanceRef, f32)>,
+    /// Make the deltas depend on substances
+    delta_modifiers: Vec<DeltaModifier>,
+    /// Textures that initialize surfel properties, applied in order
+    texture_bindings: Vec<TextureBinding>,
     sampling: SurfelSampling,
+    /// Relative surfel density of the material for adaptive sampling
+    sampling_weight: f32,
+    /// How much adaptive sampling increases density on curved triangles
+    curvature_weight: f32,
+    /// How much adaptive sampling increases density with texels per world unit
+    texel_weight: f32,
+    /// Width and height of the textures of the material, for adaptive sampling
+    texture_resolution: Option<(usize, usize)>,
     material_overrides: HashMap<String, Box<SurfaceBuilder>>
 }
 
@@ -31,19 +53,40 @@ enum SurfelSampling {
     PerSqrUnit(f32),
     /// Uses dart throwing algorithm to generate a poisson disk set with givne minimum distance.
     /// Slower than `PerSqrUnit`, but surfels are more evenly spaced.
-    MinimumDistance(f32)
+    MinimumDistance(f32),
+    /// Distributes the given total amount of surfels over all triangles, denser on curved triangles,
+    /// on materials with higher texture resolution and on materials with a higher sampling weight.
+    Adaptive(usize),
+    /// Oversamples randomly and then eliminates samples with weighted sample elimination until
+    /// exactly the given amount is left. Evenly spaced like `MinimumDistance`, but with a predictable
+    /// count and faster on meshes with many tiny triangles.
+    EliminationCount(usize),
+    /// Like `EliminationCount`, but calculates the count from the given desired spacing between
+    /// neighbouring surfels and the surface area of the scene.
+    EliminationSpacing(f32)
 }
 
 impl SurfaceBuilder {
     pub fn new() -> SurfaceBuilder {
         SurfaceBuilder {
             samples: Vec::new(),
+            surfel_materials: Vec::new(),
             delta_straight: 0.0,
             delta_parabolic: 0.0,
             delta_flow: 0.0,
             substances: Vec::new(),
+            substance_registry: SubstanceRegistry::new(),
             deposition_rates: Vec::new(),
+            deposition_rate_overrides: Vec::new(),
+            capacities: Vec::new(),
+            capacity_overrides: Vec::new(),
+            delta_modifiers: Vec::new(),
+            texture_bindings: Vec::new(),
             sampling: SurfelSampling::MinimumDistance(0.1),
+            sampling_weight: 1.0,
+            curvature_weight: 0.0,
+            texel_weight: 0.0,
+            texture_resolution: None,
             material_overrides: HashMap::new()
         }
     }
@@ -53,8 +96,15 @@ impl SurfaceBuilder {
     {
         let derived_builder = SurfaceBuilder {
             samples: Vec::new(),
+            surfel_materials: Vec::new(),
             substances: self.substances.clone(),
+            substance_registry: self.substance_registry.clone(),
             deposition_rates: self.deposition_rates.clone(),
+            deposition_rate_overrides: self.deposition_rate_overrides.clone(),
+            capacities: self.capacities.clone(),
+            capacity_overrides: self.capacity_overrides.clone(),
+            delta_modifiers: self.delta_modifiers.clone(),
+            texture_bindings: self.texture_bindings.clone(),
             material_overrides: HashMap::new(),
             ..self
         };
@@ -67,17 +117,143 @@ impl SurfaceBuilder {
         self
     }
 
+    /// Gets the parameters that surfels get from this builder, without material overrides.
+    ///
+    /// Substances referred to by name are resolved against the declared substances here.
+    ///
+    /// # Panics
+    /// If a parameter refers to a substance by a name that was not declared.
+    pub fn profile(&self) -> SurfelProfile {
+        SurfelProfile {
+            delta_straight: self.delta_straight,
+            delta_parabolic: self.delta_parabolic,
+            delta_flow: self.delta_flow,
+            deposition_rates: self.resolved_deposition_rates(),
+            material: SurfelMaterial {
+                capacities: self.resolved_capacities(),
+                delta_modifiers: self.delta_modifiers.iter()
+                    .map(|m| m.clone().resolve(&self.substance_registry))
+                    .collect()
+            }
+        }
+    }
+
+    /// Gets the texture bindings of this builder, with substances resolved to indexes.
+    fn resolved_texture_bindings(&self) -> Vec<TextureBinding> {
+        self.texture_bindings.iter()
+            .map(|b| b.clone().resolve(&self.substance_registry))
+            .collect()
+    }
+
+    fn resolved_deposition_rates(&self) -> Vec<f32> {
+        let mut deposition_rates = self.deposition_rates.clone();
+        for &(ref substance, rate) in &self.deposition_rate_overrides {
+            let idx = self.substance_registry.resolve(substance);
+            let len = self.substance_count().max(idx + 1);
+
+            while deposition_rates.len() < len {
+                deposition_rates.push(0.0);
+            }
+
+            deposition_rates[idx] = rate;
+        }
+        deposition_rates
+    }
+
+    fn resolved_capacities(&self) -> Vec<f32> {
+        let mut capacities = self.capacities.clone();
+        for &(ref substance, capacity) in &self.capacity_overrides {
+            let idx = self.substance_registry.resolve(substance);
+            let len = self.substance_count().max(idx + 1);
+
+            while capacities.len() < len {
+                capacities.push(1.0);
+            }
+
+            capacities[idx] = capacity;
+        }
+        capacities
+    }
+
+    /// Gets the parameters of each material override by material name. Overrides for names
+    /// that are not used by any material of the scene are included, so that they can serve
+    /// as profiles for surfels that change their material during the simulation.
+    pub fn material_profiles(&self) -> HashMap<String, SurfelProfile> {
+        self.material_overrides.iter()
+            .map(|(name, builder)| (name.clone(), builder.profile()))
+            .collect()
+    }
+
     /// Sets the default delta straight. Can be overriden per material.
     pub fn delta_straight(mut self, delta_straight: f32) -> SurfaceBuilder {
         self.delta_straight = delta_straight;
         self
     }
 
-    /// Sets the default deposition rate if not overridden per material
+    /// Sets the default deposition rate if not overridden per material.
+    ///
+    /// The deposition rate is also the rate at which surfels absorb substances: it is the
+    /// fraction of the load of a settling ton that is transferred to the surfel, until the
+    /// surfel reaches its capacity.
     pub fn deposition_rates<D>(mut self, deposition_rates: D) -> SurfaceBuilder
         where D : IntoIterator<Item = f32>
     {
         self.deposition_rates = deposition_rates.into_iter().collect();
+        self.deposition_rate_overrides.clear();
+        self
+    }
+
+    /// Sets the deposition rate of a single substance, by index or by name. Substances without
+    /// a deposition rate get none of the substances carried by settling tons.
+    ///
+    /// Names are resolved when surfels are generated, panicking if the name was not declared.
+    pub fn deposition_rate<S : Into<SubstanceRef>>(mut self, substance: S, rate: f32) -> SurfaceBuilder {
+        self.deposition_rate_overrides.push((substance.into(), rate));
+        self
+    }
+
+    /// Sets the maximum amount of each substance that surfels can absorb, if not overridden per
+    /// material. Surfels that are filled up to capacity with the substances carried by a ton cause
+    /// the ton to run off instead of settling.
+    ///
+    /// Porous materials like brick should get a high capacity, while glass or metal should get a
+    /// low one. If not set, surfels can hold up to 1.0 of each substance.
+    pub fn capacities<C>(mut self, capacities: C) -> SurfaceBuilder
+        where C : IntoIterator<Item = f32>
+    {
+        self.capacities = capacities.into_iter().collect();
+        self.capacity_overrides.clear();
+        self
+    }
+
+    /// Sets the capacity of a single substance, by index or by name, see `capacities`.
+    ///
+    /// Names are resolved when surfels are generated, panicking if the name was not declared.
+    pub fn capacity<S : Into<SubstanceRef>>(mut self, substance: S, capacity: f32) -> SurfaceBuilder {
+        self.capacity_overrides.push((substance.into(), capacity));
+        self
+    }
+
+    /// Adds a modifier that scales the deltas of surfels with the amount of a substance carried
+    /// by interacting tons or present on the surfel, e.g. to let sediment-laden water flow
+    /// shorter distances. Modifiers added before overriding a material also apply to the material.
+    ///
+    /// Names are resolved when surfels are generated, panicking if the name was not declared.
+    pub fn add_delta_modifier(mut self, modifier: DeltaModifier) -> SurfaceBuilder {
+        self.delta_modifiers.push(modifier);
+        self
+    }
+
+    /// Initializes a surfel property from a texture sampled at the texcoords of the surfel,
+    /// replacing the value set on the builder. Use this in `override_material` to seed
+    /// e.g. initial grime from a dirt map or deposition rates from a cavity map.
+    ///
+    /// Only applies to surfaces sampled from a scene, since surfaces built from points have
+    /// no texcoords. Bindings are applied in the order they were added.
+    ///
+    /// Names are resolved when surfels are generated, panicking if the name was not declared.
+    pub fn bind_texture(mut self, binding: TextureBinding) -> SurfaceBuilder {
+        self.texture_bindings.push(binding);
         self
     }
 
@@ -99,6 +275,27 @@ impl SurfaceBuilder {
         self
     }
 
+    /// Makes the given substances available by name to the methods that refer to substances.
+    /// Surfels start with the initial amount of each substance, unless set otherwise on the
+    /// builder afterwards.
+    ///
+    /// Declaring substances does not give surfels capacities, use `capacities` or `capacity`
+    /// to make tons run off surfels that are filled up.
+    pub fn declare_substances(mut self, substances: &SubstanceRegistry) -> SurfaceBuilder {
+        self.substances = substances.initial_amounts();
+        self.substance_registry = substances.clone();
+        self
+    }
+
+    /// Gets the amount of substances surfels carry, as far as known to the builder.
+    fn substance_count(&self) -> usize {
+        if self.substance_registry.is_empty() {
+            self.substances.len()
+        } else {
+            self.substance_registry.len()
+        }
+    }
+
     pub fn min_sample_distance(mut self, min_sample_distance: f32) -> SurfaceBuilder {
         self.sampling = SurfelSampling::MinimumDistance(min_sample_distance);
         self
@@ -109,22 +306,78 @@ impl SurfaceBuilder {
         self
     }
 
+    /// Samples exactly the given amount of evenly spaced surfels using weighted sample elimination.
+    pub fn sample_elimination(mut self, surfel_count: usize) -> SurfaceBuilder {
+        self.sampling = SurfelSampling::EliminationCount(surfel_count);
+        self
+    }
+
+    /// Samples evenly spaced surfels with weighted sample elimination, with the amount of surfels
+    /// chosen so that neighbouring surfels are approximately the given distance apart.
+    pub fn sample_elimination_spacing(mut self, spacing: f32) -> SurfaceBuilder {
+        self.sampling = SurfelSampling::EliminationSpacing(spacing);
+        self
+    }
+
+    /// Switches to adaptive sampling, which distributes exactly the given amount of surfels over
+    /// the scene. Density varies with `sampling_weight`, `curvature_weight` and `texel_weight`,
+    /// and is uniform over the surface area if none of them are set.
+    ///
+    /// Like `sample_density`, surfels are placed randomly within triangles and can clump together.
+    pub fn surfel_budget(mut self, total_surfels: usize) -> SurfaceBuilder {
+        self.sampling = SurfelSampling::Adaptive(total_surfels);
+        self
+    }
+
+    /// Sets the relative density of surfels for adaptive sampling, e.g. set a high weight in
+    /// `override_material` for detailed statues and a low one for huge ground planes. Defaults to 1.0.
+    pub fn sampling_weight(mut self, weight: f32) -> SurfaceBuilder {
+        self.sampling_weight = weight;
+        self
+    }
+
+    /// Makes adaptive sampling denser on curved triangles. Density is multiplied with
+    /// `1 + curvature_weight * curvature`, with the curvature estimated in radians of normal
+    /// deviation per world unit. Defaults to 0.0, ignoring curvature.
+    pub fn curvature_weight(mut self, weight: f32) -> SurfaceBuilder {
+        self.curvature_weight = weight;
+        self
+    }
+
+    /// Makes adaptive sampling denser where textures have more texels per world unit. Density is
+    /// multiplied with `1 + texel_weight * texels_per_unit`, where texels per unit are calculated
+    /// with the texture resolution of the material. Defaults to 0.0, ignoring textures.
+    pub fn texel_weight(mut self, weight: f32) -> SurfaceBuilder {
+        self.texel_weight = weight;
+        self
+    }
+
+    /// Sets the resolution of the textures of a material, used by adaptive sampling with a
+    /// texel weight. Materials without a resolution are treated as having zero texels per unit.
+    pub fn texture_resolution(mut self, width: usize, height: usize) -> SurfaceBuilder {
+        self.texture_resolution = Some((width, height));
+        self
+    }
+
     /// Creates a surface from only points
     /// Only useful for debugging, since you can make a surface and dump it.
     pub fn add_surface_from_points<P>(mut self, points: P) -> SurfaceBuilder
     where
         P : IntoIterator<Item = Vector3<f32>> {
 
+        let profile = self.profile();
+        let surfel_material_idx = add_material(&mut self.surfel_materials, profile.material);
         let prototype_surfel = Surfel {
             position: Vector3::new(-1.0, -1.0, -1.0),
             normal: Vector3::new(0.0, 0.0, 0.0),
             texcoords: Vector2::new(-1.0, -1.0),
             entity_idx: 0,
-            delta_straight: self.delta_straight,
-            delta_parabolic: self.delta_parabolic,
-            delta_flow: self.delta_flow,
+            delta_straight: profile.delta_straight,
+            delta_parabolic: profile.delta_parabolic,
+            delta_flow: profile.delta_flow,
             substances: self.substances.clone(),
-            deposition_rates: self.deposition_rates.clone()
+            deposition_rates: profile.deposition_rates,
+            surfel_material_idx
         };
 
         let surfels = points.into_iter()
@@ -150,8 +403,15 @@ impl SurfaceBuilder {
     pub fn add_surface_from_scene(mut self, scene: &Scene) -> SurfaceBuilder {
         let boxed_self = Box::new(SurfaceBuilder {
             samples: Vec::new(),
+            surfel_materials: Vec::new(),
             substances: self.substances.clone(),
+            substance_registry: self.substance_registry.clone(),
             deposition_rates: self.deposition_rates.clone(),
+            deposition_rate_overrides: self.deposition_rate_overrides.clone(),
+            capacities: self.capacities.clone(),
+            capacity_overrides: self.capacity_overrides.clone(),
+            delta_modifiers: self.delta_modifiers.clone(),
+            texture_bindings: self.texture_bindings.clone(),
             material_overrides: HashMap::new(),
             ..self
         });
@@ -164,8 +424,21 @@ impl SurfaceBuilder {
                     .collect()
             };
 
+            // Resolve names once per material instead of once per surfel
+            let resolved_per_material : Vec<(SurfelProfile, Vec<TextureBinding>)> = builder_per_material.iter()
+                .map(|b| (b.profile(), b.resolved_texture_bindings()))
+                .collect();
+
+            // Surfels of materials with the same capacities and modifiers share them
+            let surfel_materials = &mut self.surfel_materials;
+            let surfel_material_idxs : Vec<usize> = resolved_per_material.iter()
+                .map(|(profile, _)| add_material(surfel_materials, profile.material.clone()))
+                .collect();
+
             let make_surfel = |t : &Triangle, position| {
-                let material_builder = builder_per_material[t.vertices[0].material_idx];
+                let material_idx = t.vertices[0].material_idx;
+                let material_builder = builder_per_material[material_idx];
+                let (ref profile, ref texture_bindings) = resolved_per_material[material_idx];
 
                 let mut texcoords = t.interpolate_at(position, |v| v.texcoords);
 
@@ -182,28 +455,53 @@ impl SurfaceBuilder {
                     texcoords.y = 1.0;
                 }
 
-                // TODO this would be a good place to read initital surface properties from a texture
-
                 let normal = t.interpolate_at(position, |v| v.normal);
                 let normal = normal.normalize(); // normalize since interpolation can cause distortions
 
-                Surfel {
+                let mut surfel = Surfel {
                     position,
                     normal,
                     texcoords,
                     entity_idx: t.vertices[0].entity_idx,
-                    delta_straight: material_builder.delta_straight,
-                    delta_parabolic: material_builder.delta_parabolic,
-                    delta_flow: material_builder.delta_flow,
+                    delta_straight: profile.delta_straight,
+                    delta_parabolic: profile.delta_parabolic,
+                    delta_flow: profile.delta_flow,
                     substances: material_builder.substances.clone(),
-                    deposition_rates: material_builder.deposition_rates.clone()
+                    deposition_rates: profile.deposition_rates.clone(),
+                    surfel_material_idx: surfel_material_idxs[material_idx]
+                };
+
+                for binding in texture_bindings {
+                    binding.apply(&mut surfel);
                 }
+
+                surfel
             };
 
             self.samples.extend(
                 match &boxed_self.sampling {
                     &SurfelSampling::MinimumDistance(dist) => throw_darts(scene.triangles(), dist, make_surfel),
-                    &SurfelSampling::PerSqrUnit(per_sqr_unit) => sample_with_density(scene.triangles(), per_sqr_unit, make_surfel)
+                    &SurfelSampling::PerSqrUnit(per_sqr_unit) => sample_with_density(scene.triangles(), per_sqr_unit, make_surfel),
+                    &SurfelSampling::Adaptive(budget) => {
+                        let density_weight = |t : &Triangle| {
+                            let material_builder = builder_per_material[t.vertices[0].material_idx];
+                            let texels_per_unit = match material_builder.texture_resolution {
+                                Some((width, height)) => texels_per_unit(t, width, height),
+                                None => 0.0
+                            };
+
+                            material_builder.sampling_weight *
+                                (1.0 + material_builder.curvature_weight * triangle_curvature(t)) *
+                                (1.0 + material_builder.texel_weight * texels_per_unit)
+                        };
+
+                        sample_adaptive(scene.triangles(), budget, density_weight, make_surfel)
+                    },
+                    &SurfelSampling::EliminationCount(count) => eliminate_samples(scene.triangles(), count, make_surfel),
+                    &SurfelSampling::EliminationSpacing(spacing) => {
+                        let total_area = scene.triangles().map(|t| t.area()).sum();
+                        eliminate_samples(scene.triangles(), sample_count_for_spacing(total_area, spacing), make_surfel)
+                    }
                 }
             );
         }
@@ -213,19 +511,6 @@ impl SurfaceBuilder {
 
     /// Consumes the builder to create a new surface that is returned.
     pub fn build(self) -> Surface {
-        let spatial_idx = {
-            let mut tree = KdTree::new(3);
-
-            self.samples.iter()
-                .enumerate()
-                .for_each(|(idx, s)| tree.add(
-                    [s.position.x as f64, s.position.y as f64, s.position.z as f64],
-                    idx
-                ).unwrap());
-
-            tree
-        };
-
-        Surface { samples: self.samples, spatial_idx }
+        Surface::new(self.samples, self.surfel_materials)
     }
 }
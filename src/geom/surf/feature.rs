@@ -0,0 +1,200 @@
+//! Geometric features of the surface, e.g. for seeding grime in cavities
+//! before the simulation starts.
+
+use std::f32;
+use std::f32::consts::PI;
+
+use ::cgmath::Vector3;
+use ::cgmath::prelude::*;
+use ::rand::{Rng, SeedableRng, XorShiftRng};
+
+use ::geom::octree::Octree;
+use ::geom::scene::Triangle;
+
+use super::Surface;
+
+/// Offset along the normal for rays starting on the surface so they do not hit
+/// the triangle they start on.
+const RAY_OFFSET : f32 = 0.00001;
+
+/// Seed for the rays of features that are estimated with random rays, so that computing a
+/// feature twice for the same scene gives the same values.
+const RAY_SEED : [u32; 4] = [0x193a_6754, 0xa8a7_d469, 0x9783_0e05, 0x113b_a7bb];
+
+/// A property of the geometry around surfels that can be computed for a whole surface.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum GeometricFeature {
+    /// Fraction of the given amount of cosine-weighted rays over the hemisphere of each
+    /// surfel that hit geometry within the given distance, in the interval 0..1.
+    /// One means fully occluded, e.g. in a deep crevice.
+    AmbientOcclusion { rays: u32, distance: f32 },
+    /// Mean curvature estimated from the surfels within the given radius, multiplied with the
+    /// radius and clamped to -1..1. Positive values indicate cavities, negative values edges
+    /// and bumps, and zero flat areas. A convex sphere with the given radius gets -1.
+    Curvature { radius: f32 },
+    /// Fraction of the given amount of cosine-weighted rays over the hemisphere of each surfel
+    /// that escape the scene towards the sky, i.e. have a positive Y component and do not hit
+    /// anything. Zero for undersides and fully sheltered surfels, near one for open ground.
+    SkyExposure { rays: u32 },
+    /// Height of the surfel, mapped from the lowest to the highest surfel onto the interval 0..1.
+    Height
+}
+
+impl GeometricFeature {
+    /// Computes the feature for each surfel of the given surface, using the given octree of
+    /// scene triangles for features that need ray casting. Rays are cast with a fixed seed, so
+    /// the result only changes with the surface and the scene.
+    pub fn compute(&self, surface: &Surface, octree: &Octree<Triangle>) -> Vec<f32> {
+        let mut rng = XorShiftRng::from_seed(RAY_SEED);
+
+        match *self {
+            GeometricFeature::AmbientOcclusion { rays, distance } => surface.samples.iter()
+                .map(|s| hemisphere_fraction(&mut rng, s.position, s.normal, rays, |origin, direction| {
+                    octree.line_segment_intersection_target_and_parameter(origin, direction, distance).is_some()
+                }))
+                .collect(),
+            GeometricFeature::Curvature { radius } => curvature(surface, radius),
+            GeometricFeature::SkyExposure { rays } => surface.samples.iter()
+                .map(|s| hemisphere_fraction(&mut rng, s.position, s.normal, rays, |origin, direction| {
+                    direction.y > 0.0 && octree.ray_intersection_target_and_parameter(origin, direction).is_none()
+                }))
+                .collect(),
+            GeometricFeature::Height => height(surface)
+        }
+    }
+}
+
+/// Casts the given amount of cosine-weighted rays over the hemisphere around the normal and
+/// returns the fraction for which the predicate holds.
+fn hemisphere_fraction<R, F>(rng: &mut R, position: Vector3<f32>, normal: Vector3<f32>, rays: u32, predicate: F) -> f32
+    where R : Rng,
+        F : Fn(Vector3<f32>, Vector3<f32>) -> bool
+{
+    if rays == 0 {
+        return 0.0;
+    }
+
+    let origin = position + RAY_OFFSET * normal;
+    let (tangent, binormal) = orthonormal_basis(normal);
+
+    let hits = (0..rays)
+        .filter(|_| {
+            // Malley's method: uniform on the disk, projected up onto the hemisphere
+            let radius_sqr = rng.next_f32();
+            let angle = 2.0 * PI * rng.next_f32();
+            let radius = radius_sqr.sqrt();
+            let height = (1.0 - radius_sqr).max(0.0).sqrt();
+
+            let direction = radius * angle.cos() * tangent + radius * angle.sin() * binormal + height * normal;
+            predicate(origin, direction)
+        })
+        .count();
+
+    hits as f32 / rays as f32
+}
+
+/// Finds two vectors that form an orthonormal basis together with the given normal.
+fn orthonormal_basis(normal: Vector3<f32>) -> (Vector3<f32>, Vector3<f32>) {
+    let helper = if normal.x.abs() < 0.9 {
+        Vector3::new(1.0, 0.0, 0.0)
+    } else {
+        Vector3::new(0.0, 1.0, 0.0)
+    };
+
+    let tangent = normal.cross(helper).normalize();
+    let binormal = normal.cross(tangent);
+
+    (tangent, binormal)
+}
+
+fn curvature(surface: &Surface, radius: f32) -> Vec<f32> {
+    surface.samples.iter()
+        .map(|surfel| {
+            let (sum, count) = surface.find_within_sphere_indexes(surfel.position, radius)
+                .into_iter()
+                .map(|idx| surface.samples[idx].position - surfel.position)
+                .filter(|offset| offset.magnitude2() > f32::EPSILON)
+                // Curvature of the circle through both points that touches the tangent plane
+                .map(|offset| 2.0 * surfel.normal.dot(offset) / offset.magnitude2())
+                .fold((0.0, 0), |(sum, count), curvature| (sum + curvature, count + 1));
+
+            if count == 0 {
+                0.0
+            } else {
+                (radius * sum / count as f32).clamp(-1.0, 1.0)
+            }
+        })
+        .collect()
+}
+
+fn height(surface: &Surface) -> Vec<f32> {
+    let (min, max) = surface.samples.iter()
+        .fold(
+            (f32::INFINITY, f32::NEG_INFINITY),
+            |(min, max), s| (min.min(s.position.y), max.max(s.position.y))
+        );
+
+    let extent = max - min;
+
+    surface.samples.iter()
+        .map(|s| if extent > 0.0 { (s.position.y - min) / extent } else { 0.0 })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::geom::surf::SurfaceBuilder;
+    use ::geom::scene::horizontal_triangle;
+
+    /// Octree with a single triangle far away, for features that do not cast rays
+    fn distant_octree() -> Octree<Triangle> {
+        vec![horizontal_triangle(Vector3::new(0.0, 100.0, 0.0), 1.0, 1.0)].into_iter().collect()
+    }
+
+    fn sphere_surface(radius: f32) -> Surface {
+        let points : Vec<Vector3<f32>> = (0..2000)
+            .map(|_| ::geom::sampling::uniform_on_unit_sphere() * radius)
+            .collect();
+
+        let mut surface = SurfaceBuilder::new()
+            .add_surface_from_points(points)
+            .build();
+
+        for surfel in surface.samples.iter_mut() {
+            surfel.normal = surfel.position.normalize();
+        }
+
+        surface
+    }
+
+    #[test]
+    fn test_convex_sphere_has_negative_curvature() {
+        let surface = sphere_surface(1.0);
+        let octree = distant_octree();
+
+        let curvatures = GeometricFeature::Curvature { radius: 0.5 }.compute(&surface, &octree);
+        let mean = curvatures.iter().sum::<f32>() / curvatures.len() as f32;
+
+        assert!((mean + 0.5).abs() < 0.1, "Expected curvature around -0.5 for sphere of radius 1, got {}", mean);
+    }
+
+    #[test]
+    fn test_ray_features_are_reproducible() {
+        let surface = sphere_surface(1.0);
+        let octree = distant_octree();
+        let exposure = GeometricFeature::SkyExposure { rays: 16 };
+
+        assert_eq!(exposure.compute(&surface, &octree), exposure.compute(&surface, &octree));
+    }
+
+    #[test]
+    fn test_height_is_normalized() {
+        let surface = SurfaceBuilder::new()
+            .add_surface_from_points(vec![Vector3::new(0.0, -2.0, 0.0), Vector3::new(0.0, 0.0, 0.0), Vector3::new(0.0, 2.0, 0.0)])
+            .build();
+        let octree = distant_octree();
+
+        assert_eq!(GeometricFeature::Height.compute(&surface, &octree), vec![0.0, 0.5, 1.0]);
+    }
+}
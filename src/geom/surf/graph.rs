@@ -0,0 +1,124 @@
+use std::f32;
+
+use ::cgmath::prelude::*;
+
+use super::Surface;
+
+/// Precomputed neighbourhood of every surfel of a surface, for effects that spread
+/// substances along the surface.
+///
+/// Each surfel is connected to up to `k` of its nearest surfels within a maximum distance.
+/// Surfels whose normals differ by more than a threshold are not connected, so that substances
+/// do not leak through thin walls from one side to the other. Edges are symmetric: if a surfel
+/// is the neighbour of another surfel, the other surfel is also its neighbour.
+pub struct SurfelGraph {
+    /// Index of and distance to each neighbour of each surfel
+    neighbours: Vec<Vec<(usize, f32)>>,
+    /// Revision of the surface the graph was built for
+    revision: u64
+}
+
+impl SurfelGraph {
+    /// Builds a graph connecting each surfel with up to `k` nearest surfels within `max_distance`
+    /// that have a normal with a dot product of at least `min_normal_dot` with its own normal.
+    ///
+    /// A `min_normal_dot` of zero excludes surfels facing away, e.g. on the other side of a
+    /// thin wall, while -1 disables the check.
+    pub fn new(surface: &Surface, k: usize, max_distance: f32, min_normal_dot: f32) -> SurfelGraph {
+        let surfel_count = surface.samples.len();
+        let query_count = (k + 1).min(surfel_count);
+
+        let mut neighbours : Vec<Vec<(usize, f32)>> = vec![Vec::new(); surfel_count];
+
+        for (idx, surfel) in surface.samples.iter().enumerate() {
+            let nearest = surface.nearest_n_idxs(surfel.position, query_count);
+
+            for (distance_sqr, neighbour_idx) in nearest {
+                let distance = (distance_sqr as f32).sqrt();
+                let neighbour = &surface.samples[neighbour_idx];
+
+                if neighbour_idx == idx || distance > max_distance || surfel.normal.dot(neighbour.normal) < min_normal_dot {
+                    continue;
+                }
+
+                // Make edges symmetric, k-nearest is not
+                if !neighbours[idx].iter().any(|&(n, _)| n == neighbour_idx) {
+                    neighbours[idx].push((neighbour_idx, distance));
+                    neighbours[neighbour_idx].push((idx, distance));
+                }
+            }
+        }
+
+        SurfelGraph {
+            neighbours,
+            revision: surface.revision()
+        }
+    }
+
+    /// Gets the indexes of and distances to the neighbours of the surfel with the given index.
+    pub fn neighbours(&self, surfel_idx: usize) -> &[(usize, f32)] {
+        &self.neighbours[surfel_idx]
+    }
+
+    /// Checks if the graph was built for the current state of the given surface, so that
+    /// surfel indexes and positions are still valid.
+    pub fn is_current(&self, surface: &Surface) -> bool {
+        self.revision == surface.revision() && self.neighbours.len() == surface.samples.len()
+    }
+
+    /// Iterates over all edges once, as pairs of surfel indexes with the lower index first,
+    /// and the distance between them.
+    pub fn edges<'a>(&'a self) -> impl Iterator<Item = (usize, usize, f32)> + 'a {
+        self.neighbours.iter()
+            .enumerate()
+            .flat_map(|(idx, neighbours)| neighbours.iter()
+                .filter(move |&&(neighbour_idx, _)| neighbour_idx > idx)
+                .map(move |&(neighbour_idx, distance)| (idx, neighbour_idx, distance)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::geom::surf::SurfaceBuilder;
+    use ::cgmath::Vector3;
+
+    #[test]
+    fn test_thin_wall_is_not_connected() {
+        let mut surface = SurfaceBuilder::new()
+            .add_surface_from_points(vec![
+                Vector3::new(0.0, 0.0, 0.0),
+                Vector3::new(0.1, 0.0, 0.0),
+                // Other side of the wall
+                Vector3::new(0.0, 0.0, -0.01)
+            ])
+            .build();
+
+        surface.samples[0].normal = Vector3::new(0.0, 0.0, 1.0);
+        surface.samples[1].normal = Vector3::new(0.0, 0.0, 1.0);
+        surface.samples[2].normal = Vector3::new(0.0, 0.0, -1.0);
+
+        let graph = SurfelGraph::new(&surface, 2, f32::INFINITY, 0.0);
+
+        assert_eq!(graph.neighbours(0).len(), 1);
+        assert_eq!(graph.neighbours(0)[0].0, 1);
+        assert!(graph.neighbours(2).is_empty());
+        assert_eq!(graph.edges().count(), 1);
+        assert!(graph.is_current(&surface));
+    }
+
+    #[test]
+    fn test_edges_are_symmetric() {
+        let surface = SurfaceBuilder::new()
+            .add_surface_from_points((0..10).map(|x| Vector3::new(x as f32 * x as f32, 0.0, 0.0)))
+            .build();
+
+        let graph = SurfelGraph::new(&surface, 1, f32::INFINITY, -1.0);
+
+        for idx in 0..surface.samples.len() {
+            for &(neighbour_idx, _) in graph.neighbours(idx) {
+                assert!(graph.neighbours(neighbour_idx).iter().any(|&(n, _)| n == idx));
+            }
+        }
+    }
+}
@@ -1,7 +1,22 @@
 mod builder;
+mod feature;
+mod graph;
+mod modifier;
+mod profile;
+mod proximity;
+mod report;
+mod texture;
 
 pub use self::builder::SurfaceBuilder;
+pub use self::feature::GeometricFeature;
+pub use self::graph::SurfelGraph;
+pub use self::modifier::{DeltaModifier, SubstanceOrigin};
+pub use self::profile::SurfelProfile;
+pub use self::proximity::{Proximity, SurfacePoint};
+pub use self::report::{SamplingReport, EntityCoverage};
+pub use self::texture::{TextureBinding, SurfelField, sample_luminance};
 
+use std::f32;
 use std::io;
 use std::slice;
 
@@ -11,14 +26,35 @@ use ::nearest_kdtree::distance::squared_euclidean;
 
 use super::scene::Scene;
 
+/// How many surfels are looked at per requested surfel when searching nearest surfels
+/// that are near by some other measure than Euclidean distance.
+const NEAR_CANDIDATES_PER_RESULT : usize = 8;
+
 type Iter<'a> = slice::Iter<'a, Surfel>;
 type IterMut<'a> = slice::IterMut<'a, Surfel>;
 //type WithinSphereSurfelIter<'a> = iter::Filter<slice::IterMut<'a, Surfel>>;
 
 /// Represents the surface of a mesh as a point-based model
+///
+/// Surfels may be freely modified through `samples`, but adding, removing or moving surfels
+/// must go through `insert`, `remove` and `update_position` to keep spatial queries consistent.
 pub struct Surface {
     pub samples: Vec<Surfel>,
-    pub spatial_idx: KdTree<usize, [f64; 3]>
+    /// Parameters shared by many surfels, referred to by `Surfel::surfel_material_idx`
+    pub materials: Vec<SurfelMaterial>,
+    spatial_idx: KdTree<IndexEntry, [f64; 3]>,
+    /// Stamp of the current entry in the spatial index for each surfel, older entries are stale
+    stamps: Vec<u64>,
+    /// Amount of outdated entries in the spatial index
+    stale_entries: usize,
+    revision: u64
+}
+
+/// Entry in the spatial index of a surface that is only valid if the stamp matches the
+/// current stamp of the surfel with the index.
+struct IndexEntry {
+    idx: usize,
+    stamp: u64
 }
 
 /// Represents an element of the surface of an object
@@ -34,13 +70,51 @@ pub struct Surfel {
     pub delta_parabolic: f32,
     /// Deterioration rate of the probability of a gammaton flowing in a tangent direction
     pub delta_flow: f32,
-    /// Holds the amount of substances as numbers in the interval 0..1
+    /// Holds the amount of substances as numbers in the interval 0..1, or up to the capacity of the substance
     pub substances: Vec<f32>,
     /// Weights for the transport of substances from a settled ton to a surfel
-    pub deposition_rates: Vec<f32>
+    pub deposition_rates: Vec<f32>,
+    /// Index of the capacities and delta modifiers of the surfel in the materials of the surface
+    pub surfel_material_idx: usize
+}
+
+/// Parameters that are the same for all surfels of a material, stored once per surface instead
+/// of on each surfel.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SurfelMaterial {
+    /// Maximum amount of each substance the surfel can absorb, e.g. high for porous brick
+    /// and low for glass. If empty, or shorter than the substances, the capacity is 1.0.
+    pub capacities: Vec<f32>,
+    /// Modify the deltas depending on the substances carried by interacting tons and on the surfel
+    pub delta_modifiers: Vec<DeltaModifier>
 }
 
 impl Surface {
+    /// Creates a surface from the given surfels and builds a spatial index for them. Each surfel
+    /// must refer to one of the given materials.
+    pub fn new(samples: Vec<Surfel>, materials: Vec<SurfelMaterial>) -> Surface {
+        assert!(
+            samples.iter().all(|s| s.surfel_material_idx < materials.len()),
+            "Surfels refer to {} materials, but only {} were given",
+            samples.iter().map(|s| s.surfel_material_idx + 1).max().unwrap_or(0), materials.len()
+        );
+
+        let mut surface = Surface {
+            stamps: vec![0; samples.len()],
+            samples,
+            materials,
+            spatial_idx: KdTree::new(3),
+            stale_entries: 0,
+            revision: 0
+        };
+
+        for idx in 0..surface.samples.len() {
+            surface.index_surfel(idx);
+        }
+
+        surface
+    }
+
     pub fn dump<S : io::Write>(&self, sink: &mut S) -> io::Result<usize> {
         let mut written : usize = 0;
 
@@ -68,38 +142,27 @@ impl Surface {
     }
 
     pub fn nearest_mut<'a>(&'a mut self, from: Vector3<f32>) -> &'a mut Surfel {
-        assert!(!self.samples.is_empty());
-
-        let x = from.x as f64;
-        let y = from.y as f64;
-        let z = from.z as f64;
-
-        let (_, &nearest_idx) = self.spatial_idx.nearest(&[x, y, z], 1, &squared_euclidean).unwrap()[0];
+        let nearest_idx = self.nearest_idx(from);
         &mut self.samples[nearest_idx]
     }
 
     pub fn nearest<'a>(&'a self, from: Vector3<f32>) -> &'a Surfel {
-        assert!(!self.samples.is_empty());
+        &self.samples[self.nearest_idx(from)]
+    }
 
-        let x = from.x as f64;
-        let y = from.y as f64;
-        let z = from.z as f64;
+    /// Finds the index of the surfel closest to the given position.
+    pub fn nearest_idx(&self, from: Vector3<f32>) -> usize {
+        assert!(!self.samples.is_empty());
 
-        let (_, &nearest_idx) = self.spatial_idx.nearest(&[x, y, z], 1, &squared_euclidean).unwrap()[0];
-        &self.samples[nearest_idx]
+        self.nearest_n_idxs(from, 1)[0].1
     }
 
     pub fn nearest_n<'a>(&'a self, from: Vector3<f32>, count: usize) -> Vec<(f32, &'a Surfel)> {
         assert!(self.samples.len() >= count);
 
-        let x = from.x as f64;
-        let y = from.y as f64;
-        let z = from.z as f64;
-
-        let nearest_idxs = self.spatial_idx.nearest(&[x, y, z], count, &squared_euclidean).unwrap();
-
-        nearest_idxs.iter()
-            .map(|n| (n.0 as f32, &self.samples[*n.1]))
+        self.nearest_n_idxs(from, count)
+            .into_iter()
+            .map(|(dist, idx)| (dist as f32, &self.samples[idx]))
             .collect()
     }
 
@@ -108,13 +171,10 @@ impl Surface {
     }
 
     pub fn find_within_sphere<'a>(&'a self, center: Vector3<f32>, radius: f32) -> Vec<&'a Surfel> {
-        let radius_sqr = (radius * radius) as f64;
-
-        self.spatial_idx.within(
-            &[center.x as f64, center.y as f64, center.z as f64],
-            radius_sqr,
-            &squared_euclidean
-        ).unwrap().iter().map(move |&(_, &idx)| &self.samples[idx]).collect()
+        self.find_within_sphere_indexes(center, radius)
+            .into_iter()
+            .map(|idx| &self.samples[idx])
+            .collect()
     }
 
     pub fn iter_mut<'a>(&'a mut self) -> IterMut {
@@ -128,7 +188,308 @@ impl Surface {
             &[center.x as f64, center.y as f64, center.z as f64],
             radius_sqr,
             &squared_euclidean
-        ).unwrap().iter().map(move |&(_, &idx)| idx).collect()
+        ).unwrap()
+            .into_iter()
+            .filter(|&(_, entry)| self.is_current(entry))
+            .map(|(_, entry)| entry.idx)
+            .collect()
+    }
+
+    /// Finds the indexes of the surfels within the given radius of a point on the surface that
+    /// are near to it by the given measure, e.g. only the surfels on the same side of a thin wall.
+    pub fn find_near_indexes(&self, point: &SurfacePoint, radius: f32, proximity: Proximity) -> Vec<usize> {
+        let candidates = self.find_within_sphere_indexes(point.position, radius)
+            .into_iter()
+            .filter(|&idx| proximity.admits(point, &self.samples[idx]))
+            .collect();
+
+        proximity.connected(point, &self.samples, candidates, radius)
+    }
+
+    /// Like `nearest_n_idxs`, but skips surfels that are not near to the point by the given measure.
+    ///
+    /// Only a limited number of the nearest surfels are considered, so fewer than `count` surfels
+    /// may be returned, or none at all if the point is far away from any surfel it admits.
+    pub fn nearest_n_near_idxs(&self, point: &SurfacePoint, count: usize, proximity: Proximity) -> Vec<(f64, usize)> {
+        if proximity == Proximity::Euclidean {
+            return self.nearest_n_idxs(point.position, count);
+        }
+
+        let candidates : Vec<(f64, usize)> = self.nearest_n_idxs(point.position, count * NEAR_CANDIDATES_PER_RESULT)
+            .into_iter()
+            .filter(|&(_, idx)| proximity.admits(point, &self.samples[idx]))
+            .collect();
+
+        let connected = proximity.connected(
+            point,
+            &self.samples,
+            candidates.iter().map(|&(_, idx)| idx).collect(),
+            f32::INFINITY
+        );
+
+        candidates.into_iter()
+            .filter(|&(_, idx)| connected.contains(&idx))
+            .take(count)
+            .collect()
+    }
+
+    /// Gets the index of a material with the given parameters, adding it to the materials of
+    /// the surface if no material with the same parameters exists yet.
+    pub fn add_material(&mut self, material: SurfelMaterial) -> usize {
+        add_material(&mut self.materials, material)
+    }
+
+    /// Gets the shared parameters of the given surfel.
+    pub fn material(&self, surfel: &Surfel) -> &SurfelMaterial {
+        &self.materials[surfel.surfel_material_idx]
+    }
+
+    /// Adds a surfel to the surface and returns its index. The surfel must refer to a material
+    /// of this surface, e.g. one returned from `add_material`.
+    pub fn insert(&mut self, surfel: Surfel) -> usize {
+        assert!(surfel.surfel_material_idx < self.materials.len(), "Inserted surfel refers to unknown material");
+        let idx = self.samples.len();
+        self.samples.push(surfel);
+        self.stamps.push(0);
+        self.index_surfel(idx);
+        idx
+    }
+
+    /// Removes the surfel with the given index and returns it.
+    ///
+    /// The last surfel takes the place of the removed one, so its index changes to the index
+    /// of the removed surfel, while all other indexes stay valid.
+    ///
+    /// Panics if the index is out of bounds.
+    pub fn remove(&mut self, idx: usize) -> Surfel {
+        let removed = self.samples.swap_remove(idx);
+        self.stamps.swap_remove(idx);
+        // The old entry of the removed surfel is now stale
+        self.stale_entries += 1;
+
+        if idx < self.samples.len() {
+            // The old entry of the moved surfel is now stale, too
+            self.stale_entries += 1;
+            self.index_surfel(idx);
+        } else {
+            self.revision += 1;
+        }
+
+        self.rebuild_if_mostly_stale();
+        removed
+    }
+
+    /// Moves the surfel with the given index to a new position.
+    ///
+    /// Use this instead of writing to the position directly, since otherwise spatial queries
+    /// would still find the surfel at its old position.
+    pub fn update_position(&mut self, idx: usize, position: Vector3<f32>) {
+        self.samples[idx].position = position;
+        self.stale_entries += 1;
+        self.index_surfel(idx);
+        self.rebuild_if_mostly_stale();
+    }
+
+    /// Gets a number that changes whenever surfels are inserted, removed or moved, e.g. for
+    /// invalidating data that is cached per surfel index.
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// Finds the squared distances and indexes of the given amount of surfels nearest to the given
+    /// position, from nearest to farthest.
+    pub fn nearest_n_idxs(&self, from: Vector3<f32>, count: usize) -> Vec<(f64, usize)> {
+        let point = [from.x as f64, from.y as f64, from.z as f64];
+
+        self.spatial_idx.iter_nearest(&point, &squared_euclidean)
+            .unwrap()
+            .filter(|&(_, entry)| self.is_current(entry))
+            .map(|(dist, entry)| (dist, entry.idx))
+            .take(count)
+            .collect()
+    }
+
+    /// Adds a new entry for the surfel with the given index to the spatial index, invalidating
+    /// older entries with the same index.
+    fn index_surfel(&mut self, idx: usize) {
+        self.revision += 1;
+        self.stamps[idx] = self.revision;
+
+        let position = self.samples[idx].position;
+        self.spatial_idx.add(
+            [position.x as f64, position.y as f64, position.z as f64],
+            IndexEntry { idx, stamp: self.revision }
+        ).unwrap();
+    }
+
+    fn is_current(&self, entry: &IndexEntry) -> bool {
+        entry.idx < self.stamps.len() && self.stamps[entry.idx] == entry.stamp
+    }
+
+    /// The kd-tree does not support removal, so outdated entries are left in the tree
+    /// and skipped in queries until they make up more than half of the tree.
+    fn rebuild_if_mostly_stale(&mut self) {
+        if self.stale_entries > self.samples.len() {
+            self.spatial_idx = KdTree::new(3);
+            self.stale_entries = 0;
+
+            for idx in 0..self.samples.len() {
+                self.index_surfel(idx);
+            }
+        }
     }
 }
 
+impl Surfel {
+    /// Calculates the straight, parabolic and flow deltas for an interaction with a ton carrying
+    /// the given substances, with all delta modifiers of the given material of the surfel applied.
+    pub fn deltas(&self, material: &SurfelMaterial, carried: &[f32]) -> (f32, f32, f32) {
+        material.delta_modifiers.iter()
+            .fold(
+                (self.delta_straight, self.delta_parabolic, self.delta_flow),
+                |deltas, modifier| modifier.apply(deltas, carried, &self.substances)
+            )
+    }
+
+    /// Gets the deposition rate of the substance with the given index, which is zero if the
+    /// surfel has no rate set for it.
+    pub fn deposition_rate(&self, substance_idx: usize) -> f32 {
+        self.deposition_rates.get(substance_idx)
+            .cloned()
+            .unwrap_or(0.0)
+    }
+
+    /// Gets the amount of the substance with the given index the surfel can still absorb, with
+    /// the capacity from the given material of the surfel.
+    pub fn room(&self, material: &SurfelMaterial, substance_idx: usize) -> f32 {
+        (material.capacity(substance_idx) - self.substances[substance_idx]).max(0.0)
+    }
+
+    /// Calculates how saturated the surfel is with respect to the substances carried by a ton,
+    /// in the interval 0..1, with the capacities from the given material of the surfel.
+    ///
+    /// The fill level of each substance is weighted by the amount of that substance in the given
+    /// load, so a surfel full of dirt is not saturated for a ton carrying only water. Substances
+    /// the surfel cannot hold at all count as saturated. Returns zero for an empty load.
+    ///
+    /// Surfels without capacities never saturate, so that tons settle on them regardless of how
+    /// much they hold. Surfels only get capacities when set on the surface builder.
+    pub fn saturation(&self, material: &SurfelMaterial, load: &[f32]) -> f32 {
+        let total_load : f32 = load.iter().sum();
+
+        if material.capacities.is_empty() || total_load <= 0.0 {
+            return 0.0;
+        }
+
+        let weighted_fill : f32 = load.iter()
+            .enumerate()
+            .map(|(idx, &amount)| {
+                let capacity = material.capacity(idx);
+                let fill = if capacity > 0.0 {
+                    (self.substances[idx] / capacity).min(1.0)
+                } else {
+                    1.0
+                };
+
+                amount * fill
+            })
+            .sum();
+
+        weighted_fill / total_load
+    }
+}
+
+impl SurfelMaterial {
+    /// Gets the maximum amount of the substance with the given index a surfel can hold.
+    pub fn capacity(&self, substance_idx: usize) -> f32 {
+        self.capacities.get(substance_idx)
+            .cloned()
+            .unwrap_or(1.0)
+    }
+}
+
+/// Finds the index of a material with the same parameters in the given materials, or adds it
+/// at the end. There are only a few materials, so a linear search is fine.
+fn add_material(materials: &mut Vec<SurfelMaterial>, material: SurfelMaterial) -> usize {
+    match materials.iter().position(|m| *m == material) {
+        Some(idx) => idx,
+        None => {
+            materials.push(material);
+            materials.len() - 1
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn line_surface(count: usize) -> Surface {
+        SurfaceBuilder::new()
+            .substances(&vec![0.0])
+            .add_surface_from_points((0..count).map(|x| Vector3::new(x as f32, 0.0, 0.0)))
+            .build()
+    }
+
+    #[test]
+    fn test_remove_moves_last_surfel() {
+        let mut surface = line_surface(4);
+
+        let removed = surface.remove(1);
+
+        assert_eq!(removed.position, Vector3::new(1.0, 0.0, 0.0));
+        assert_eq!(surface.samples.len(), 3);
+        assert_eq!(surface.nearest_idx(Vector3::new(3.0, 0.0, 0.0)), 1);
+        assert_eq!(surface.nearest_idx(Vector3::new(2.1, 0.0, 0.0)), 2);
+        assert!(surface.find_within_sphere_indexes(Vector3::new(1.0, 0.0, 0.0), 0.5).is_empty());
+    }
+
+    #[test]
+    fn test_surfels_share_materials() {
+        let mut surface = SurfaceBuilder::new()
+            .substances(&vec![0.0])
+            .capacities(vec![0.5])
+            .add_surface_from_points((0..3).map(|x| Vector3::new(x as f32, 0.0, 0.0)))
+            .add_surface_from_points(vec![Vector3::new(5.0, 0.0, 0.0)])
+            .build();
+
+        assert_eq!(surface.materials.len(), 1);
+        assert!(surface.samples.iter().all(|s| s.surfel_material_idx == 0));
+        assert_eq!(surface.material(&surface.samples[3]).capacity(0), 0.5);
+
+        let glass = SurfelMaterial { capacities: vec![0.1], delta_modifiers: Vec::new() };
+        let glass_idx = surface.add_material(glass.clone());
+        assert_eq!(glass_idx, 1);
+        assert_eq!(surface.add_material(glass), glass_idx, "Equal materials are only added once");
+    }
+
+    #[test]
+    fn test_insert_and_update_position() {
+        let mut surface = line_surface(2);
+        let prototype = surface.remove(1);
+
+        let idx = surface.insert(Surfel { position: Vector3::new(5.0, 0.0, 0.0), ..prototype });
+        assert_eq!(surface.find_within_sphere_indexes(Vector3::new(5.0, 0.0, 0.0), 0.5), vec![idx]);
+
+        surface.update_position(idx, Vector3::new(-5.0, 0.0, 0.0));
+        assert!(surface.find_within_sphere_indexes(Vector3::new(5.0, 0.0, 0.0), 0.5).is_empty());
+        assert_eq!(surface.nearest_idx(Vector3::new(-4.0, 0.0, 0.0)), idx);
+        assert_eq!(surface.nearest_n(Vector3::new(0.0, 0.0, 0.0), 2).len(), 2);
+    }
+
+    #[test]
+    fn test_queries_stay_consistent_after_many_updates() {
+        let mut surface = line_surface(10);
+        let revision = surface.revision();
+
+        for round in 0..5 {
+            for idx in 0..10 {
+                surface.update_position(idx, Vector3::new(idx as f32, round as f32 + 1.0, 0.0));
+            }
+        }
+
+        assert!(surface.revision() > revision);
+        assert_eq!(surface.find_within_sphere_indexes(Vector3::new(3.0, 5.0, 0.0), 0.5), vec![3]);
+        assert!(surface.find_within_sphere_indexes(Vector3::new(3.0, 0.0, 0.0), 0.5).is_empty());
+    }
+}
@@ -0,0 +1,150 @@
+//! Modifiers that make the motion deltas of surfels depend on substances.
+
+use ::substance::{SubstanceRef, SubstanceRegistry};
+
+/// Where the amount of substance that drives a `DeltaModifier` is taken from.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SubstanceOrigin {
+    /// The substance carried by the ton interacting with the surfel, e.g. sediment in water
+    Carried,
+    /// The substance on the surfel itself, e.g. moss on a wall
+    Surface
+}
+
+/// Scales the motion deltas of a surfel in proportion to the amount of a substance,
+/// either carried by the interacting ton or present on the surfel.
+///
+/// For an amount `a` of the substance, each delta is multiplied by `1 + factor * a`,
+/// so a positive factor makes the ton lose the ability to move that way faster,
+/// while a negative one helps it keep moving. Deltas never become negative.
+///
+/// For example, to make sediment-laden water flow shorter distances and let moss stop
+/// flow sooner:
+///
+/// ```ignore
+/// builder
+///     .add_delta_modifier(DeltaModifier::carried("sediment").flow(2.0))
+///     .override_material("wall", |m| m.add_delta_modifier(DeltaModifier::on_surface("moss").flow(4.0)))
+/// ```
+///
+/// Substances referred to by name are resolved when the modifier is added to a `SurfaceBuilder`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeltaModifier {
+    pub origin: SubstanceOrigin,
+    pub substance: SubstanceRef,
+    /// Factor for the deterioration of the probability to move in a straight line
+    pub straight: f32,
+    /// Factor for the deterioration of the probability to move in a parabolic path
+    pub parabolic: f32,
+    /// Factor for the deterioration of the probability to flow
+    pub flow: f32
+}
+
+impl DeltaModifier {
+    /// Creates a modifier driven by the given substance carried by the interacting ton
+    /// that does not modify any deltas yet.
+    pub fn carried<S : Into<SubstanceRef>>(substance: S) -> DeltaModifier {
+        DeltaModifier::new(SubstanceOrigin::Carried, substance.into())
+    }
+
+    /// Creates a modifier driven by the given substance on the surfel that does not
+    /// modify any deltas yet.
+    pub fn on_surface<S : Into<SubstanceRef>>(substance: S) -> DeltaModifier {
+        DeltaModifier::new(SubstanceOrigin::Surface, substance.into())
+    }
+
+    fn new(origin: SubstanceOrigin, substance: SubstanceRef) -> DeltaModifier {
+        DeltaModifier {
+            origin,
+            substance,
+            straight: 0.0,
+            parabolic: 0.0,
+            flow: 0.0
+        }
+    }
+
+    /// Replaces a reference to a substance by name with its index in the given substances.
+    ///
+    /// # Panics
+    /// If the substance was not declared.
+    pub fn resolve(mut self, substances: &SubstanceRegistry) -> DeltaModifier {
+        self.substance = SubstanceRef::Idx(substances.resolve(&self.substance));
+        self
+    }
+
+    /// Gets the index of the substance that drives the modifier.
+    ///
+    /// # Panics
+    /// If the substance is referred to by a name that was not resolved yet.
+    pub fn substance_idx(&self) -> usize {
+        match self.substance {
+            SubstanceRef::Idx(idx) => idx,
+            SubstanceRef::Name(ref name) => panic!("Delta modifier refers to substance {}, which was not resolved", name)
+        }
+    }
+
+    pub fn straight(mut self, factor: f32) -> DeltaModifier {
+        self.straight = factor;
+        self
+    }
+
+    pub fn parabolic(mut self, factor: f32) -> DeltaModifier {
+        self.parabolic = factor;
+        self
+    }
+
+    pub fn flow(mut self, factor: f32) -> DeltaModifier {
+        self.flow = factor;
+        self
+    }
+
+    /// Applies the modifier to the given straight, parabolic and flow deltas.
+    pub fn apply(&self, deltas: (f32, f32, f32), carried: &[f32], on_surface: &[f32]) -> (f32, f32, f32) {
+        let amount = match self.origin {
+            SubstanceOrigin::Carried => carried.get(self.substance_idx()),
+            SubstanceOrigin::Surface => on_surface.get(self.substance_idx())
+        }.cloned().unwrap_or(0.0);
+
+        let scale = |delta: f32, factor: f32| (delta * (1.0 + factor * amount)).max(0.0);
+
+        (
+            scale(deltas.0, self.straight),
+            scale(deltas.1, self.parabolic),
+            scale(deltas.2, self.flow)
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::substance::Substance;
+
+    #[test]
+    fn test_modifier_scales_with_amount() {
+        let modifier = DeltaModifier::carried(1).flow(2.0).straight(-2.0);
+        let deltas = modifier.apply((0.1, 0.1, 0.1), &[1.0, 0.5], &[0.0, 0.0]);
+
+        assert!((deltas.0 - 0.0).abs() < 0.00001);
+        assert!((deltas.1 - 0.1).abs() < 0.00001);
+        assert!((deltas.2 - 0.2).abs() < 0.00001);
+    }
+
+    #[test]
+    fn test_resolve_by_name() {
+        let mut substances = SubstanceRegistry::new();
+        substances.add(Substance::new("water"));
+        substances.add(Substance::new("sediment"));
+
+        let modifier = DeltaModifier::carried("sediment").flow(1.0).resolve(&substances);
+        assert_eq!(modifier.substance_idx(), 1);
+    }
+
+    #[test]
+    fn test_surface_modifier_ignores_carried_substances() {
+        let modifier = DeltaModifier::on_surface(0).flow(1.0);
+        let deltas = modifier.apply((0.1, 0.1, 0.1), &[1.0], &[0.0]);
+
+        assert_eq!(deltas, (0.1, 0.1, 0.1));
+    }
+}
@@ -0,0 +1,122 @@
+use super::{Surface, Surfel, SurfelMaterial};
+
+/// The parameters that determine how surfels of a material interact with tons, as configured
+/// on a `SurfaceBuilder` or one of its material overrides.
+///
+/// Applying a profile to a surfel makes it behave like a surfel of the other material, e.g.
+/// when rust has eaten through a coat of paint, while keeping its substances and position.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SurfelProfile {
+    pub delta_straight: f32,
+    pub delta_parabolic: f32,
+    pub delta_flow: f32,
+    pub deposition_rates: Vec<f32>,
+    /// Capacities and delta modifiers, which surfels share through the materials of the surface
+    pub material: SurfelMaterial
+}
+
+impl SurfelProfile {
+    /// Gets the index of the shared parameters of this profile in the materials of the given
+    /// surface, adding them if no surfel used them so far.
+    pub fn material_idx(&self, surface: &mut Surface) -> usize {
+        surface.add_material(self.material.clone())
+    }
+
+    /// Replaces the parameters of the surfel with the ones from this profile, where the given
+    /// index is the one returned from `material_idx` for the surface of the surfel.
+    pub fn apply(&self, surfel: &mut Surfel, material_idx: usize) {
+        surfel.delta_straight = self.delta_straight;
+        surfel.delta_parabolic = self.delta_parabolic;
+        surfel.delta_flow = self.delta_flow;
+        surfel.deposition_rates.clone_from(&self.deposition_rates);
+        surfel.surfel_material_idx = material_idx;
+    }
+
+    /// Checks if the parameters of the surfel already match this profile, with the given index
+    /// returned from `material_idx`.
+    pub fn is_applied(&self, surfel: &Surfel, material_idx: usize) -> bool {
+        surfel.surfel_material_idx == material_idx &&
+            surfel.delta_straight == self.delta_straight &&
+            surfel.delta_parabolic == self.delta_parabolic &&
+            surfel.delta_flow == self.delta_flow &&
+            surfel.deposition_rates == self.deposition_rates
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ::geom::surf::{SurfaceBuilder, DeltaModifier};
+    use ::substance::{Substance, SubstanceRegistry};
+    use ::cgmath::Vector3;
+
+    #[test]
+    fn test_apply_override_profile() {
+        let builder = SurfaceBuilder::new()
+            .substances(&vec![0.7])
+            .delta_flow(0.1)
+            .override_material("rusted", |b| b.delta_flow(0.5).deposition_rates(vec![0.2]));
+
+        let profiles = builder.material_profiles();
+        let rusted = &profiles["rusted"];
+        let mut surface = builder
+            .add_surface_from_points(vec![Vector3::new(0.0, 0.0, 0.0)])
+            .build();
+        let material_idx = rusted.material_idx(&mut surface);
+        let surfel = &mut surface.samples[0];
+
+        assert!(!rusted.is_applied(surfel, material_idx));
+        rusted.apply(surfel, material_idx);
+
+        assert!(rusted.is_applied(surfel, material_idx));
+        assert_eq!(surfel.delta_flow, 0.5);
+        assert_eq!(surfel.deposition_rates, vec![0.2]);
+        assert_eq!(surfel.substances, vec![0.7]);
+    }
+
+    #[test]
+    fn test_named_substance_parameters() {
+        let mut substances = SubstanceRegistry::new();
+        substances.add(Substance::new("water"));
+        substances.add(Substance::new("dirt").range(0.0, 2.0));
+        substances.add(Substance::new("moss"));
+
+        let profile = SurfaceBuilder::new()
+            .declare_substances(&substances)
+            .deposition_rate("dirt", 0.3)
+            .capacity("water", 0.5)
+            .add_delta_modifier(DeltaModifier::on_surface("moss").flow(1.0))
+            .profile();
+
+        assert_eq!(profile.deposition_rates, vec![0.0, 0.3, 0.0]);
+        // Capacities not set explicitly stay at 1.0, regardless of substance ranges
+        assert_eq!(profile.material.capacities, vec![0.5, 1.0, 1.0]);
+        assert_eq!(profile.material.delta_modifiers[0].substance_idx(), 2);
+    }
+
+    #[test]
+    fn test_names_are_resolved_when_building() {
+        let mut substances = SubstanceRegistry::new();
+        substances.add(Substance::new("water"));
+        substances.add(Substance::new("dirt"));
+
+        // Refer to the substances before declaring them
+        let profile = SurfaceBuilder::new()
+            .deposition_rate("dirt", 0.3)
+            .capacity("water", 0.5)
+            .add_delta_modifier(DeltaModifier::on_surface("dirt").flow(1.0))
+            .declare_substances(&substances)
+            .profile();
+
+        assert_eq!(profile.deposition_rates, vec![0.0, 0.3]);
+        assert_eq!(profile.material.capacities, vec![0.5, 1.0]);
+        assert_eq!(profile.material.delta_modifiers[0].substance_idx(), 1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_undeclared_names_panic_when_building() {
+        SurfaceBuilder::new()
+            .deposition_rate("dirt", 0.3)
+            .profile();
+    }
+}
@@ -0,0 +1,186 @@
+use std::f32;
+
+use ::cgmath::Vector3;
+use ::cgmath::prelude::*;
+
+use ::geom::scene::Triangle;
+
+use super::Surfel;
+
+/// A point on the surface of the scene, e.g. where a ton hit a triangle, together with what is
+/// needed to tell apart surfels on the same side of thin geometry from those on the other side.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SurfacePoint {
+    pub position: Vector3<f32>,
+    /// Normal of the surface at the point
+    pub normal: Vector3<f32>,
+    /// Index of the entity the point lies on
+    pub entity_idx: usize
+}
+
+/// Determines which surfels around a point on the surface count as near to it.
+///
+/// By Euclidean distance alone, a point on one side of a thin wall or railing is also near to the
+/// surfels on the opposite side. The other modes exclude such surfels by comparing normals and
+/// entities, or by requiring a path along the surface from the point to the surfel.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Proximity {
+    /// All surfels count, regardless of orientation or entity.
+    Euclidean,
+    /// Only surfels with normals that have at least the given dot product with the normal at
+    /// the point count. Zero excludes surfels facing away from the point.
+    Oriented { min_normal_dot: f32 },
+    /// Like `Oriented`, but surfels must also belong to the same entity as the point.
+    SameEntity { min_normal_dot: f32 },
+    /// Like `SameEntity`, but surfels must also be reachable from the point by hopping from surfel
+    /// to surfel with steps of at most `max_step`. The length of the shortest such path approximates
+    /// the geodesic distance and is limited by the query radius, which also excludes surfels on other
+    /// mesh islands of the same entity. The step should be a little larger than the surfel spacing.
+    Geodesic { min_normal_dot: f32, max_step: f32 }
+}
+
+impl SurfacePoint {
+    /// Makes a surface point at the given position on the given triangle, with a normal
+    /// interpolated from the vertex normals.
+    pub fn on_triangle(triangle: &Triangle, position: Vector3<f32>) -> SurfacePoint {
+        SurfacePoint {
+            position,
+            normal: triangle.interpolate_at(position, |v| v.normal).normalize(),
+            entity_idx: triangle.vertices[0].entity_idx
+        }
+    }
+}
+
+impl Proximity {
+    /// Checks the orientation and entity of the surfel against the point.
+    ///
+    /// For geodesic proximity, connectivity is checked separately with `connected`.
+    pub fn admits(&self, point: &SurfacePoint, surfel: &Surfel) -> bool {
+        match *self {
+            Proximity::Euclidean => true,
+            Proximity::Oriented { min_normal_dot } => point.normal.dot(surfel.normal) >= min_normal_dot,
+            Proximity::SameEntity { min_normal_dot } | Proximity::Geodesic { min_normal_dot, .. } =>
+                surfel.entity_idx == point.entity_idx && point.normal.dot(surfel.normal) >= min_normal_dot
+        }
+    }
+
+    /// For geodesic proximity, keeps only the candidate surfels that can be reached from the point
+    /// with a path no longer than `max_distance`, only hopping over other candidates. Paths start
+    /// at candidates within one step of the point.
+    ///
+    /// Other modes keep all candidates. The order of the candidates is preserved.
+    pub fn connected(&self, point: &SurfacePoint, samples: &[Surfel], candidates: Vec<usize>, max_distance: f32) -> Vec<usize> {
+        let max_step = match *self {
+            Proximity::Geodesic { max_step, .. } => max_step,
+            _ => return candidates
+        };
+
+        let positions : Vec<Vector3<f32>> = candidates.iter()
+            .map(|&idx| samples[idx].position)
+            .collect();
+
+        let mut distances : Vec<f32> = positions.iter()
+            .map(|p| {
+                let distance = p.distance(point.position);
+                if distance <= max_step { distance } else { f32::INFINITY }
+            })
+            .collect();
+
+        let mut reached = vec![false; candidates.len()];
+
+        // Dijkstra without a heap, there are only ever a few candidates in the radius of a query
+        loop {
+            let closest = (0..candidates.len())
+                .filter(|&i| !reached[i] && distances[i].is_finite() && distances[i] <= max_distance)
+                .min_by(|&a, &b| distances[a].partial_cmp(&distances[b]).unwrap());
+
+            let current = match closest {
+                Some(current) => current,
+                None => break
+            };
+
+            reached[current] = true;
+
+            for other in 0..candidates.len() {
+                let step = positions[current].distance(positions[other]);
+                if !reached[other] && step <= max_step {
+                    distances[other] = distances[other].min(distances[current] + step);
+                }
+            }
+        }
+
+        candidates.into_iter()
+            .zip(reached)
+            .filter(|&(_, reached)| reached)
+            .map(|(idx, _)| idx)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::geom::surf::{Surface, SurfaceBuilder};
+
+    fn up() -> Vector3<f32> {
+        Vector3::new(0.0, 1.0, 0.0)
+    }
+
+    /// Row of surfels with a gap between the first three and the last two, the last
+    /// surfel belonging to another entity.
+    fn row_with_gap() -> Surface {
+        let mut surface = SurfaceBuilder::new()
+            .add_surface_from_points(vec![0.0, 0.1, 0.2, 0.5, 0.6].into_iter().map(|x| Vector3::new(x, 0.0, 0.0)))
+            .build();
+
+        for surfel in surface.iter_mut() {
+            surfel.normal = up();
+        }
+        surface.samples[4].entity_idx = 1;
+        surface
+    }
+
+    fn point() -> SurfacePoint {
+        SurfacePoint { position: Vector3::new(0.0, 0.0, 0.0), normal: up(), entity_idx: 0 }
+    }
+
+    #[test]
+    fn test_oriented_excludes_other_side() {
+        let mut surface = row_with_gap();
+        surface.samples[1].normal = -up();
+
+        let near = surface.find_near_indexes(&point(), 1.0, Proximity::Oriented { min_normal_dot: 0.0 });
+        assert_eq!(near.len(), 4);
+        assert!(!near.contains(&1));
+    }
+
+    #[test]
+    fn test_same_entity_excludes_other_entities() {
+        let surface = row_with_gap();
+
+        let near = surface.find_near_indexes(&point(), 1.0, Proximity::SameEntity { min_normal_dot: 0.0 });
+        assert_eq!(near.len(), 4);
+        assert!(!near.contains(&4));
+    }
+
+    #[test]
+    fn test_geodesic_excludes_unconnected_and_far() {
+        let surface = row_with_gap();
+        let geodesic = Proximity::Geodesic { min_normal_dot: 0.0, max_step: 0.15 };
+
+        let mut near = surface.find_near_indexes(&point(), 1.0, geodesic);
+        near.sort();
+        assert_eq!(near, vec![0, 1, 2]);
+
+        // Path to the third surfel is longer than the radius
+        let mut near = surface.find_near_indexes(&point(), 0.15, geodesic);
+        near.sort();
+        assert_eq!(near, vec![0, 1]);
+
+        let nearest : Vec<usize> = surface.nearest_n_near_idxs(&point(), 5, geodesic)
+            .into_iter()
+            .map(|(_, idx)| idx)
+            .collect();
+        assert_eq!(nearest, vec![0, 1, 2]);
+    }
+}
@@ -0,0 +1,332 @@
+//! Analysis of the quality of sampled surface models, e.g. for choosing an interaction
+//! radius that fits the spacing of surfels.
+
+use std::f32;
+use std::f32::consts::PI;
+use std::fmt;
+use std::io;
+
+use ::cgmath::prelude::*;
+
+use ::geom::scene::Scene;
+
+use super::Surface;
+
+/// Amount of bins of the radial distribution function.
+const RDF_BINS : usize = 32;
+
+/// The radial distribution function is calculated up to this multiple of the mean
+/// nearest neighbour distance.
+const RDF_RANGE : f32 = 5.0;
+
+/// Triangles are considered uncovered if their center is farther than this multiple of the
+/// mean nearest neighbour distance away from the nearest surfel.
+const GAP_FACTOR : f32 = 2.0;
+
+/// Statistics about how evenly a surface model covers a scene.
+#[derive(Debug, Clone)]
+pub struct SamplingReport {
+    pub surfel_count: usize,
+    /// Total area of all triangles in the scene
+    pub surface_area: f32,
+    /// Distance of each surfel to its nearest neighbour, in the order of the surfels
+    pub nearest_neighbour_distances: Vec<f32>,
+    pub min_nearest_neighbour_distance: f32,
+    pub mean_nearest_neighbour_distance: f32,
+    pub max_nearest_neighbour_distance: f32,
+    /// Triangles farther than this from the nearest surfel count as coverage gaps
+    pub gap_threshold: f32,
+    /// Coverage of each entity in the scene, in the order of the entities
+    pub entities: Vec<EntityCoverage>,
+    /// Material names and the amount of surfels on entities with that material
+    pub surfels_per_material: Vec<(String, usize)>,
+    /// Pairs of radius and value of the radial distribution function, where the value is the
+    /// density of surfels at that distance from a surfel relative to the mean density. Blue noise
+    /// sampling shows a value near zero for small radiuses and a peak at the sample spacing.
+    pub radial_distribution: Vec<(f32, f32)>
+}
+
+/// Coverage statistics of a single entity.
+#[derive(Debug, Clone)]
+pub struct EntityCoverage {
+    pub entity_name: String,
+    pub surfel_count: usize,
+    pub triangle_count: usize,
+    /// Amount of triangles with a center farther from the nearest surfel than the gap threshold
+    pub uncovered_triangle_count: usize,
+    /// Largest distance from a triangle center to the nearest surfel
+    pub max_gap: f32
+}
+
+impl SamplingReport {
+    /// Analyses the given surface that was sampled from the given scene.
+    pub fn analyze(surface: &Surface, scene: &Scene) -> SamplingReport {
+        let surfel_count = surface.samples.len();
+        let surface_area : f32 = scene.triangles().map(|t| t.area()).sum();
+
+        let nearest_neighbour_distances : Vec<f32> = if surfel_count < 2 {
+            Vec::new()
+        } else {
+            surface.samples.iter()
+                .map(|s| surface.nearest_n(s.position, 2)[1].0.sqrt())
+                .collect()
+        };
+
+        let (min, max, sum) = nearest_neighbour_distances.iter()
+            .fold((f32::INFINITY, 0.0_f32, 0.0), |(min, max, sum), &d| (min.min(d), max.max(d), sum + d));
+        let mean = if nearest_neighbour_distances.is_empty() { 0.0 } else { sum / nearest_neighbour_distances.len() as f32 };
+        let min = if nearest_neighbour_distances.is_empty() { 0.0 } else { min };
+
+        let gap_threshold = GAP_FACTOR * mean;
+
+        SamplingReport {
+            surfel_count,
+            surface_area,
+            min_nearest_neighbour_distance: min,
+            mean_nearest_neighbour_distance: mean,
+            max_nearest_neighbour_distance: max,
+            gap_threshold,
+            entities: entity_coverage(surface, scene, gap_threshold),
+            surfels_per_material: surfels_per_material(surface, scene),
+            radial_distribution: radial_distribution(surface, surface_area, RDF_RANGE * mean),
+            nearest_neighbour_distances
+        }
+    }
+
+    /// Calculates the radius of a sphere around a point of the surface that contains the given
+    /// amount of surfels on average, assuming the surface is locally flat. Use this to choose an
+    /// interaction radius that lets tons interact with a few surfels at once.
+    pub fn radius_for_expected_neighbours(&self, neighbour_count: f32) -> f32 {
+        if self.surfel_count == 0 {
+            return 0.0;
+        }
+
+        let density = self.surfel_count as f32 / self.surface_area;
+        (neighbour_count / (PI * density)).sqrt()
+    }
+
+    /// Writes the surfels as an ASCII PLY point cloud, coloured from blue for surfels with close
+    /// neighbours to red for isolated surfels.
+    pub fn write_heatmap_ply<S : io::Write>(&self, surface: &Surface, sink: &mut S) -> io::Result<()> {
+        writeln!(sink, "ply")?;
+        writeln!(sink, "format ascii 1.0")?;
+        writeln!(sink, "comment Nearest neighbour distances, blue is {} and red is {}", self.min_nearest_neighbour_distance, self.max_nearest_neighbour_distance)?;
+        writeln!(sink, "element vertex {}", surface.samples.len())?;
+        writeln!(sink, "property float x")?;
+        writeln!(sink, "property float y")?;
+        writeln!(sink, "property float z")?;
+        writeln!(sink, "property uchar red")?;
+        writeln!(sink, "property uchar green")?;
+        writeln!(sink, "property uchar blue")?;
+        writeln!(sink, "end_header")?;
+
+        let range = self.max_nearest_neighbour_distance - self.min_nearest_neighbour_distance;
+
+        for (idx, surfel) in surface.samples.iter().enumerate() {
+            let heat = match self.nearest_neighbour_distances.get(idx) {
+                Some(&distance) if range > 0.0 => (distance - self.min_nearest_neighbour_distance) / range,
+                _ => 0.0
+            };
+
+            let red = (heat * 255.0) as u8;
+            let blue = ((1.0 - heat) * 255.0) as u8;
+            let p = surfel.position;
+
+            writeln!(sink, "{} {} {} {} 0 {}", p.x, p.y, p.z, red, blue)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for SamplingReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} surfels on {} square units", self.surfel_count, self.surface_area)?;
+        writeln!(
+            f,
+            "nearest neighbour distance: min {}, mean {}, max {}",
+            self.min_nearest_neighbour_distance,
+            self.mean_nearest_neighbour_distance,
+            self.max_nearest_neighbour_distance
+        )?;
+
+        for entity in &self.entities {
+            writeln!(
+                f,
+                "entity {}: {} surfels, {} of {} triangles uncovered, max gap {}",
+                entity.entity_name,
+                entity.surfel_count,
+                entity.uncovered_triangle_count,
+                entity.triangle_count,
+                entity.max_gap
+            )?;
+        }
+
+        for &(ref material, count) in &self.surfels_per_material {
+            writeln!(f, "material {}: {} surfels", material, count)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn entity_coverage(surface: &Surface, scene: &Scene, gap_threshold: f32) -> Vec<EntityCoverage> {
+    let mut coverage : Vec<EntityCoverage> = scene.entities.iter()
+        .map(|e| EntityCoverage {
+            entity_name: e.name.clone(),
+            surfel_count: 0,
+            triangle_count: 0,
+            uncovered_triangle_count: 0,
+            max_gap: 0.0
+        })
+        .collect();
+
+    for surfel in &surface.samples {
+        if let Some(entity) = coverage.get_mut(surfel.entity_idx) {
+            entity.surfel_count += 1;
+        }
+    }
+
+    for triangle in scene.triangles() {
+        let entity = &mut coverage[triangle.vertices[0].entity_idx];
+        entity.triangle_count += 1;
+
+        if surface.samples.is_empty() {
+            entity.uncovered_triangle_count += 1;
+            continue;
+        }
+
+        let center = triangle.center();
+        let gap = surface.nearest(center).position.distance(center);
+
+        entity.max_gap = entity.max_gap.max(gap);
+        if gap > gap_threshold {
+            entity.uncovered_triangle_count += 1;
+        }
+    }
+
+    coverage
+}
+
+fn surfels_per_material(surface: &Surface, scene: &Scene) -> Vec<(String, usize)> {
+    let mut counts = vec![0; scene.materials.len()];
+
+    for surfel in &surface.samples {
+        if let Some(entity) = scene.entities.get(surfel.entity_idx) {
+            counts[entity.original_material_idx] += 1;
+        }
+    }
+
+    scene.materials.iter()
+        .map(|m| m.name.clone())
+        .zip(counts)
+        .collect()
+}
+
+/// Estimates the radial distribution function of the surfels on a surface, normalized with the
+/// mean surfel density per area.
+fn radial_distribution(surface: &Surface, surface_area: f32, max_radius: f32) -> Vec<(f32, f32)> {
+    let surfel_count = surface.samples.len();
+
+    if surfel_count < 2 || max_radius <= 0.0 || surface_area <= 0.0 {
+        return Vec::new();
+    }
+
+    let bin_width = max_radius / RDF_BINS as f32;
+    let mut pair_counts = vec![0usize; RDF_BINS];
+
+    for surfel in &surface.samples {
+        for idx in surface.find_within_sphere_indexes(surfel.position, max_radius) {
+            let distance = surface.samples[idx].position.distance(surfel.position);
+            let bin = (distance / bin_width) as usize;
+
+            if distance > 0.0 && bin < RDF_BINS {
+                pair_counts[bin] += 1;
+            }
+        }
+    }
+
+    let density = surfel_count as f32 / surface_area;
+
+    pair_counts.into_iter()
+        .enumerate()
+        .map(|(bin, count)| {
+            let inner = bin as f32 * bin_width;
+            let outer = inner + bin_width;
+            // Expected count for uniformly distributed surfels on a flat surface
+            let ring_area = PI * (outer * outer - inner * inner);
+            let expected = surfel_count as f32 * density * ring_area;
+
+            (inner + 0.5 * bin_width, count as f32 / expected)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::geom::scene::Mesh;
+    use ::geom::surf::SurfaceBuilder;
+    use ::cgmath::Vector3;
+    use ::tobj;
+
+    fn unit_square_scene() -> Scene {
+        let mut scene = Scene::with_mesh(Mesh {
+            indices: vec![0, 1, 2, 0, 2, 3],
+            positions: vec![0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 1.0, 0.0, 0.0, 1.0, 0.0],
+            normals: vec![0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0],
+            texcoords: Vec::new()
+        });
+
+        let mut material = tobj::Material::empty();
+        material.name = String::from("floor");
+        scene.materials.push(material);
+
+        scene
+    }
+
+    fn grid_surface(per_side: usize) -> Surface {
+        let spacing = 1.0 / per_side as f32;
+        let points = (0..per_side)
+            .flat_map(|x| (0..per_side).map(move |y| Vector3::new((x as f32 + 0.5) * spacing, (y as f32 + 0.5) * spacing, 0.0)));
+
+        SurfaceBuilder::new()
+            .add_surface_from_points(points)
+            .build()
+    }
+
+    #[test]
+    fn test_regular_grid_statistics() {
+        let report = SamplingReport::analyze(&grid_surface(10), &unit_square_scene());
+
+        assert_eq!(report.surfel_count, 100);
+        assert!((report.min_nearest_neighbour_distance - 0.1).abs() < 0.0001);
+        assert!((report.max_nearest_neighbour_distance - 0.1).abs() < 0.0001);
+        assert_eq!(report.surfels_per_material, vec![(String::from("floor"), 100)]);
+        assert_eq!(report.entities[0].triangle_count, 2);
+        assert_eq!(report.entities[0].uncovered_triangle_count, 0);
+        // No pairs closer than the grid spacing
+        assert_eq!(report.radial_distribution[0].1, 0.0);
+    }
+
+    #[test]
+    fn test_heatmap_ply_has_one_line_per_surfel() {
+        let surface = grid_surface(4);
+        let report = SamplingReport::analyze(&surface, &unit_square_scene());
+
+        let mut ply = Vec::new();
+        report.write_heatmap_ply(&surface, &mut ply).unwrap();
+        let ply = String::from_utf8(ply).unwrap();
+
+        assert!(ply.contains("element vertex 16"));
+        assert_eq!(ply.lines().skip_while(|l| *l != "end_header").count(), 17);
+    }
+
+    #[test]
+    fn test_radius_for_expected_neighbours() {
+        let report = SamplingReport::analyze(&grid_surface(10), &unit_square_scene());
+        let radius = report.radius_for_expected_neighbours(PI);
+
+        assert!((radius - 0.1).abs() < 0.0001);
+    }
+}
@@ -0,0 +1,235 @@
+//! Bindings of textures to surfel properties, for initializing surfels from texture maps,
+//! e.g. to seed grime from a dirt or cavity map.
+
+use std::path::Path;
+use std::rc::Rc;
+
+use ::cgmath::Vector2;
+use ::image::{self, DynamicImage, GenericImage, Pixel};
+
+use ::substance::{SubstanceRef, SubstanceRegistry};
+
+use super::Surfel;
+
+/// A property of a surfel that can be initialized from a texture.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SurfelField {
+    /// Initial concentration of the referenced substance
+    Substance(SubstanceRef),
+    /// Deposition rate of the referenced substance
+    DepositionRate(SubstanceRef),
+    DeltaStraight,
+    DeltaParabolic,
+    DeltaFlow
+}
+
+impl SurfelField {
+    /// Refers to the amount of the given substance, by index or by name.
+    pub fn substance<S : Into<SubstanceRef>>(substance: S) -> SurfelField {
+        SurfelField::Substance(substance.into())
+    }
+
+    /// Refers to the deposition rate of the given substance, by index or by name.
+    pub fn deposition_rate<S : Into<SubstanceRef>>(substance: S) -> SurfelField {
+        SurfelField::DepositionRate(substance.into())
+    }
+
+    /// Replaces a reference to a substance by name with its index in the given substances.
+    ///
+    /// # Panics
+    /// If the substance was not declared.
+    pub fn resolve(self, substances: &SubstanceRegistry) -> SurfelField {
+        match self {
+            SurfelField::Substance(substance) => SurfelField::Substance(SubstanceRef::Idx(substances.resolve(&substance))),
+            SurfelField::DepositionRate(substance) => SurfelField::DepositionRate(SubstanceRef::Idx(substances.resolve(&substance))),
+            field => field
+        }
+    }
+
+    /// Sets the field of the given surfel to the given value.
+    ///
+    /// Deposition rates that the surfel does not have yet are added as zero before setting the value.
+    ///
+    /// Panics if the field refers to a substance the surfel does not have, or to a substance
+    /// by a name that was not resolved yet.
+    pub fn set(&self, surfel: &mut Surfel, value: f32) {
+        match *self {
+            SurfelField::Substance(ref substance) => {
+                let idx = resolved_idx(substance);
+                assert!(idx < surfel.substances.len(), "Surfel field refers to substance {}, but surfels only have {} substances", idx, surfel.substances.len());
+                surfel.substances[idx] = value;
+            },
+            SurfelField::DepositionRate(ref substance) => {
+                let idx = resolved_idx(substance);
+                // Missing deposition rates are zero
+                if surfel.deposition_rates.len() <= idx {
+                    surfel.deposition_rates.resize(idx + 1, 0.0);
+                }
+                surfel.deposition_rates[idx] = value;
+            },
+            SurfelField::DeltaStraight => surfel.delta_straight = value,
+            SurfelField::DeltaParabolic => surfel.delta_parabolic = value,
+            SurfelField::DeltaFlow => surfel.delta_flow = value
+        }
+    }
+}
+
+fn resolved_idx(substance: &SubstanceRef) -> usize {
+    match *substance {
+        SubstanceRef::Idx(idx) => idx,
+        SubstanceRef::Name(ref name) => panic!("Surfel field refers to substance {}, which was not resolved", name)
+    }
+}
+
+/// Sets a property of surfels to the luminance of a texture at the texcoords of the surfel,
+/// mapped linearly from 0..1 to the range of the binding.
+///
+/// The image is reference counted, so bindings can be cheaply shared between materials.
+#[derive(Clone)]
+pub struct TextureBinding {
+    field: SurfelField,
+    texture: Rc<DynamicImage>,
+    /// Value for black texels
+    min: f32,
+    /// Value for white texels
+    max: f32
+}
+
+impl TextureBinding {
+    /// Loads the texture at the given path to bind it to the given field, mapping
+    /// black to 0.0 and white to 1.0.
+    ///
+    /// Panics if the texture cannot be loaded.
+    pub fn new<P : AsRef<Path>>(field: SurfelField, texture_path: P) -> TextureBinding {
+        let texture_path = texture_path.as_ref();
+        let texture = image::open(texture_path)
+            .unwrap_or_else(|_| panic!("Texture for binding to surfel field {:?} at {:?} could not be loaded", field, texture_path));
+
+        TextureBinding::from_image(field, texture)
+    }
+
+    /// Binds an already loaded texture to the given field, mapping black to 0.0 and white to 1.0.
+    pub fn from_image(field: SurfelField, texture: DynamicImage) -> TextureBinding {
+        TextureBinding {
+            field,
+            texture: Rc::new(texture),
+            min: 0.0,
+            max: 1.0
+        }
+    }
+
+    /// Maps black texels to `min` and white texels to `max` and everything else in between.
+    ///
+    /// Setting `min` higher than `max` inverts the texture, e.g. to turn an ambient
+    /// occlusion map where crevices are dark into a map where crevices have high values.
+    pub fn range(mut self, min: f32, max: f32) -> TextureBinding {
+        self.min = min;
+        self.max = max;
+        self
+    }
+
+    pub fn field(&self) -> &SurfelField {
+        &self.field
+    }
+
+    /// Replaces a reference to a substance by name in the bound field with its index in the
+    /// given substances.
+    ///
+    /// # Panics
+    /// If the substance was not declared.
+    pub fn resolve(mut self, substances: &SubstanceRegistry) -> TextureBinding {
+        self.field = self.field.resolve(substances);
+        self
+    }
+
+    /// Gets the value of the binding at the given texcoords in the interval 0..1,
+    /// using the nearest texel.
+    pub fn sample(&self, texcoords: Vector2<f32>) -> f32 {
+        let luminance = sample_luminance(&self.texture, texcoords);
+        self.min + luminance * (self.max - self.min)
+    }
+
+    /// Sets the bound field of the given surfel to the value of the texture at the
+    /// texcoords of the surfel.
+    pub fn apply(&self, surfel: &mut Surfel) {
+        let value = self.sample(surfel.texcoords);
+        self.field.set(surfel, value);
+    }
+}
+
+/// Gets the luminance in 0..1 of the texel nearest to the given texcoords in the interval 0..1.
+///
+/// Texcoords have v pointing up like in OBJ files, so v = 1 samples the top row of the image.
+pub fn sample_luminance(texture: &DynamicImage, texcoords: Vector2<f32>) -> f32 {
+    let (width, height) = texture.dimensions();
+
+    let x = ((texcoords.x * width as f32) as u32).min(width - 1);
+    // Pixels are y down, reverse the v coordinate
+    let y = (((1.0 - texcoords.y) * height as f32) as u32).min(height - 1);
+
+    texture.get_pixel(x, y).to_luma().data[0] as f32 / 255.0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::geom::surf::SurfaceBuilder;
+    use ::cgmath::Vector3;
+    use ::image::{ImageBuffer, Luma};
+
+    /// Texture that is black on the left half and white on the right half
+    fn half_white() -> DynamicImage {
+        DynamicImage::ImageLuma8(
+            ImageBuffer::from_fn(4, 4, |x, _| if x < 2 { Luma([0u8]) } else { Luma([255u8]) })
+        )
+    }
+
+    #[test]
+    fn test_sample_maps_to_range() {
+        let binding = TextureBinding::from_image(SurfelField::substance(0), half_white())
+            .range(0.2, 0.6);
+
+        assert!((binding.sample(Vector2::new(0.1, 0.5)) - 0.2).abs() < 0.00001);
+        assert!((binding.sample(Vector2::new(0.9, 0.5)) - 0.6).abs() < 0.00001);
+        // Texcoords of exactly one must not sample outside of the texture
+        assert!((binding.sample(Vector2::new(1.0, 1.0)) - 0.6).abs() < 0.00001);
+    }
+
+    #[test]
+    fn test_sample_flips_v() {
+        // Black in the top half of the image, white in the bottom half
+        let top_black = DynamicImage::ImageLuma8(
+            ImageBuffer::from_fn(4, 4, |_, y| if y < 2 { Luma([0u8]) } else { Luma([255u8]) })
+        );
+        let binding = TextureBinding::from_image(SurfelField::substance(0), top_black);
+
+        assert!(binding.sample(Vector2::new(0.5, 0.9)).abs() < 0.00001);
+        assert!((binding.sample(Vector2::new(0.5, 0.1)) - 1.0).abs() < 0.00001);
+        assert!((binding.sample(Vector2::new(0.5, 0.0)) - 1.0).abs() < 0.00001);
+    }
+
+    #[test]
+    fn test_bind_deposition_rate_without_rates() {
+        let mut surface = SurfaceBuilder::new()
+            .substances(&vec![0.0, 0.0])
+            .add_surface_from_points(vec![Vector3::new(0.0, 0.0, 0.0)])
+            .build();
+        let surfel = &mut surface.samples[0];
+        surfel.texcoords = Vector2::new(0.9, 0.5);
+
+        TextureBinding::from_image(SurfelField::deposition_rate(1), half_white())
+            .range(0.0, 0.6)
+            .apply(surfel);
+
+        assert_eq!(surfel.deposition_rates, vec![0.0, 0.6]);
+    }
+
+    #[test]
+    fn test_inverted_range() {
+        let binding = TextureBinding::from_image(SurfelField::DeltaFlow, half_white())
+            .range(1.0, 0.0);
+
+        assert!((binding.sample(Vector2::new(0.1, 0.5)) - 1.0).abs() < 0.00001);
+        assert!(binding.sample(Vector2::new(0.9, 0.5)).abs() < 0.00001);
+    }
+}
@@ -12,5 +12,12 @@ extern crate nearest_kdtree;
 mod geom;
 mod sim;
 mod sink;
+mod substance;
 
-pub use sim::{Simulation, SimulationBuilder};
+pub use sim::expr;
+pub use sim::{Effect, SurfelRule, ExpressionRule, Modulation, Condition, SunExposure, Light, MaterialTransition};
+pub use sim::{SubstanceMap, SubstanceMapMaterialEffect, MultiMapMaterialEffect, LayeredBlend, Ramp, RampSegment, PbrBlend, PbrMaterial, PbrSource};
+pub use sim::{Simulation, SimulationConfig, SimulationBuilder, SubstanceMapperBuilder, InteractionKernel, MissPolicy, SubstanceBalance};
+pub use substance::{Substance, SubstanceRef, SubstanceRegistry};
+pub use geom::scene::{Scene, Entity};
+pub use geom::surf::{Surface, Surfel, SurfelMaterial, DeltaModifier, SubstanceOrigin, TextureBinding, SurfelField, GeometricFeature, SamplingReport, EntityCoverage, Proximity, SurfelProfile};
@@ -0,0 +1,122 @@
+//! Bookkeeping of the substances that are moved around in an iteration.
+
+use std::fmt;
+
+/// Keeps account of the substances transported by tons during a single iteration,
+/// with one entry per substance in each of the vectors.
+///
+/// When substances are conserved, everything that is emitted or picked up has to
+/// end up somewhere, so `emitted + picked_up = deposited + escaped + clamped + retained`
+/// holds up to floating point error. Use `SubstanceBalance::discrepancy` to check this,
+/// e.g. to detect bugs in custom rules.
+///
+/// Amounts that surfels hold beyond the range of a declared substance after tracing are cut
+/// off and moved from `deposited` to `clamped`, so the equation still holds.
+///
+/// Without conservation, settling tons deposit without losing their load, so the
+/// equation does not hold, but the numbers are still useful for tuning parameters.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubstanceBalance {
+    /// Amount of substances initially carried by all emitted tons
+    pub emitted: Vec<f32>,
+    /// Amount of substances transported from tons to surfels
+    pub deposited: Vec<f32>,
+    /// Amount of substances transported from surfels to tons
+    pub picked_up: Vec<f32>,
+    /// Amount of substances carried by tons that left the scene or did not
+    /// hit any surfels
+    pub escaped: Vec<f32>,
+    /// Amount of substances lost because a surfel or ton was saturated, or because a surfel
+    /// exceeded the range of a declared substance
+    pub clamped: Vec<f32>,
+    /// Amount of substances still carried by tons when they settled
+    pub retained: Vec<f32>
+}
+
+impl SubstanceBalance {
+    pub fn new(substance_count: usize) -> SubstanceBalance {
+        SubstanceBalance {
+            emitted: vec![0.0; substance_count],
+            deposited: vec![0.0; substance_count],
+            picked_up: vec![0.0; substance_count],
+            escaped: vec![0.0; substance_count],
+            clamped: vec![0.0; substance_count],
+            retained: vec![0.0; substance_count]
+        }
+    }
+
+    pub fn substance_count(&self) -> usize {
+        self.emitted.len()
+    }
+
+    /// Books the difference between the amounts of substances on a surfel before and after
+    /// clamping them into their ranges as clamped instead of deposited.
+    ///
+    /// Amounts raised to the minimum of a range count as negative clamping.
+    pub fn book_surfel_clamping(&mut self, unclamped: &[f32], clamped: &[f32]) {
+        for (idx, (before, after)) in unclamped.iter().zip(clamped).enumerate().take(self.substance_count()) {
+            let cut_off = before - after;
+            self.deposited[idx] -= cut_off;
+            self.clamped[idx] += cut_off;
+        }
+    }
+
+    /// Calculates for each substance how much went missing or appeared out of nowhere,
+    /// which should be close to zero when substances are conserved.
+    pub fn discrepancy(&self) -> Vec<f32> {
+        (0..self.substance_count())
+            .map(|idx| {
+                let income = self.emitted[idx] + self.picked_up[idx];
+                let expenses = self.deposited[idx] + self.escaped[idx] + self.clamped[idx] + self.retained[idx];
+                income - expenses
+            })
+            .collect()
+    }
+}
+
+/// Adds the given amounts component-wise to the given account.
+pub fn book<'a, I>(account: &mut [f32], amounts: I)
+    where I : IntoIterator<Item = &'a f32>
+{
+    for (total, amount) in account.iter_mut().zip(amounts) {
+        *total += *amount;
+    }
+}
+
+impl fmt::Display for SubstanceBalance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for idx in 0..self.substance_count() {
+            writeln!(
+                f,
+                "substance {}: emitted {}, deposited {}, picked up {}, escaped {}, clamped {}, retained {}",
+                idx,
+                self.emitted[idx],
+                self.deposited[idx],
+                self.picked_up[idx],
+                self.escaped[idx],
+                self.clamped[idx],
+                self.retained[idx]
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_surfel_clamping_keeps_balance() {
+        let mut balance = SubstanceBalance::new(2);
+        book(&mut balance.emitted, &[1.0, 0.5]);
+        book(&mut balance.deposited, &[1.0, 0.5]);
+
+        balance.book_surfel_clamping(&[1.5, 0.5], &[1.0, 0.5]);
+
+        assert_eq!(balance.clamped, vec![0.5, 0.0]);
+        assert_eq!(balance.deposited, vec![0.5, 0.5]);
+        assert_eq!(balance.discrepancy(), vec![0.0, 0.0]);
+    }
+}
@@ -1,14 +1,13 @@
 use super::substance_map::SubstanceMap;
-use super::substance_map_material::SubstanceMapMaterialEffect;
+use super::substance_map_material::{SubstanceMapMaterialEffect, MultiMapMaterialEffect, material_with_diffuse};
 
 use ::geom::scene::Entity;
+use ::substance::SubstanceRef;
 
 use ::tobj::Material;
 
 use ::image::{self, DynamicImage, GenericImage, ImageBuffer, Pixel};
 
-use std::collections::HashMap;
-
 use std::fs::File;
 
 use std::path::{PathBuf, Path};
@@ -38,6 +37,51 @@ impl Blend {
     }
 }
 
+/// Like `Blend`, but blends several overlay images over the diffuse texture, each one weighted
+/// by the concentration of its own substance, e.g. rust over the clean material and moss on
+/// top of both.
+pub struct LayeredBlend {
+    target_material_names: Vec<String>,
+    texture_base_path: PathBuf,
+    /// Substance and overlay image of each layer, from bottom to top
+    layers: Vec<(SubstanceRef, DynamicImage)>
+}
+
+impl LayeredBlend {
+    pub fn new<P : Into<PathBuf>>(target_material_names: Vec<String>, texture_base_path: P) -> LayeredBlend {
+        LayeredBlend {
+            target_material_names,
+            texture_base_path: texture_base_path.into(),
+            layers: Vec::new()
+        }
+    }
+
+    /// Adds a layer on top of the previous layers that blends towards the given image where
+    /// the given substance is concentrated. The substance can be given by index or by name.
+    pub fn layer<S : Into<SubstanceRef>>(mut self, substance: S, overlay_image_path: &Path) -> LayeredBlend {
+        let overlay_image = image::open(overlay_image_path)
+            .unwrap_or_else(|_| panic!("Blend target image {:?} could not be loaded", overlay_image_path));
+
+        self.layers.push((substance.into(), overlay_image));
+        self
+    }
+}
+
+/// Gets the pixel of the image at the given texture coordinates with y pointing down.
+fn pixel_at(image: &DynamicImage, u: f32, v: f32) -> image::Rgba<u8> {
+    let (width, height) = image.dimensions();
+    let x = ((u * (width as f32)) as u32).min(width - 1);
+    let y = ((v * (height as f32)) as u32).min(height - 1);
+    image.get_pixel(x, y)
+}
+
+fn lerp_pixel(from: image::Rgba<u8>, to: image::Rgba<u8>, weight: f32) -> image::Rgba<u8> {
+    from.map2(
+        &to,
+        |src, target| ((1.0 - weight) * (src as f32) + weight * (target as f32)) as u8
+    )
+}
+
 fn blend_by_substance_map(original: &DynamicImage, overlay: &DynamicImage, concentrations: &SubstanceMap) -> ImageBuffer<image::Rgba<u8>, Vec<u8>> {
     let width = concentrations.width() as u32;
     let height = concentrations.height() as u32;
@@ -48,25 +92,41 @@ fn blend_by_substance_map(original: &DynamicImage, overlay: &DynamicImage, conce
             let u = (x as f32) / (width as f32);
             let v = (y as f32) / (height as f32);
 
-            let (original_w, original_h) = original.dimensions();
-            let original_x = (u * (original_w as f32)) as u32;
-            let original_y = (v * (original_h as f32)) as u32;
-            let original_px = original.get_pixel(original_x, original_y);
-
-            let (overlay_w, overlay_h) = overlay.dimensions();
-            let overlay_x = (u * (overlay_w as f32)) as u32;
-            let overlay_y = (v * (overlay_h as f32)) as u32;
-            let overlay_px = overlay.get_pixel(overlay_x, overlay_y);
-
-            let concentration = {
-                let val = concentrations.sample_for_image_coords(x as usize, y as usize, width as usize, height as usize);
-                if val > 1.0 { 1.0 } else { val }
-            };
-
-            original_px.map2(
-                &overlay_px,
-                |src, target| ((1.0 - concentration) * (src as f32) + concentration * (target as f32)) as u8
-            )
+            let original = pixel_at(original, u, v);
+            let concentration = concentrations.sample_for_image_coords(x as usize, y as usize, width as usize, height as usize);
+
+            // Texels without nearby surfels are NaN, keep the original
+            if concentration.is_nan() {
+                original
+            } else {
+                lerp_pixel(original, pixel_at(overlay, u, v), concentration.min(1.0))
+            }
+        }
+    )
+}
+
+/// Blends the layers over the original image in order, with the concentrations of the map at the
+/// same position. All maps must have the same size.
+fn blend_layers(original: &DynamicImage, layers: &[(SubstanceRef, DynamicImage)], maps: &[&SubstanceMap]) -> ImageBuffer<image::Rgba<u8>, Vec<u8>> {
+    let width = maps[0].width() as u32;
+    let height = maps[0].height() as u32;
+
+    ImageBuffer::from_fn(
+        width, height,
+        |x, y| {
+            let u = (x as f32) / (width as f32);
+            let v = (y as f32) / (height as f32);
+
+            layers.iter().zip(maps).fold(pixel_at(original, u, v), |pixel, ((_, overlay), map)| {
+                let concentration = map.sample_for_image_coords(x as usize, y as usize, width as usize, height as usize);
+
+                // Texels without nearby surfels are NaN, keep the layers below
+                if concentration.is_nan() {
+                    pixel
+                } else {
+                    lerp_pixel(pixel, pixel_at(overlay, u, v), concentration.clamp(0.0, 1.0))
+                }
+            })
         }
     )
 }
@@ -79,48 +139,96 @@ impl SubstanceMapMaterialEffect for Blend {
             return None;
         }
 
-        let original_material_diffuse_tex = {
-            let mut diffuse_texture_path = self.texture_base_path.clone();
-            diffuse_texture_path.push(&original_material.diffuse_texture);
-            image::open(&diffuse_texture_path)
-                .expect("Diffuse texture of material could not be loaded for weathering")
-        };
+        let original_material_diffuse_tex = load_diffuse(&self.texture_base_path, original_material);
 
         let blent = blend_by_substance_map(&original_material_diffuse_tex, &self.overlay_image, concentrations);
 
         let material_name = format!("{}-blent-map-substance-{}", output_file_prefix.file_name().unwrap().to_str().unwrap(), concentrations.substance_idx());
 
-        let mut blent_map_path = PathBuf::from(output_file_prefix);
-        blent_map_path.set_file_name(&material_name);
-        blent_map_path.set_extension("png");
+        Some(write_blent_material(blent, output_file_prefix, &material_name))
+    }
+}
+
+impl MultiMapMaterialEffect for LayeredBlend {
+    fn substances(&self) -> Vec<SubstanceRef> {
+        self.layers.iter().map(|(substance, _)| substance.clone()).collect()
+    }
+
+    fn perform(&self, _entity: &Entity, original_material: &Material, maps: &[&SubstanceMap], output_file_prefix: &Path) -> Option<Material> {
+        if self.layers.is_empty() || !self.target_material_names.iter().any(|n| n == &original_material.name) {
+            return None;
+        }
 
-        let target_filename_relative = String::from(blent_map_path.file_name().unwrap().to_str().unwrap());
+        let original_material_diffuse_tex = load_diffuse(&self.texture_base_path, original_material);
 
-        info!("Writing blent texture {}...", target_filename_relative);
-        let blent_map_file = &mut File::create(blent_map_path).unwrap();
+        let blent = blend_layers(&original_material_diffuse_tex, &self.layers, maps);
 
-        image::ImageRgba8(blent).save(blent_map_file, image::PNG)
-                .expect("Substance map texture could not be written");
+        let substance_names : Vec<String> = maps.iter().map(|m| m.substance_idx().to_string()).collect();
+        let material_name = format!("{}-layered-map-substances-{}", output_file_prefix.file_name().unwrap().to_str().unwrap(), substance_names.join("-"));
 
-        Some(material_with_diffuse(&material_name, &target_filename_relative))
+        Some(write_blent_material(blent, output_file_prefix, &material_name))
     }
 }
 
-fn material_with_diffuse(name: &str, diffuse: &str) -> Material {
-    Material {
-        name: String::from(name),
-        ambient: [1.0; 3],
-        diffuse: [1.0; 3],
-        specular: [0.0; 3],
-        shininess: 0.0,
-        dissolve: 1.0,
-        optical_density: 1.0,
-        ambient_texture: String::new(),
-        diffuse_texture: String::from(diffuse),
-        specular_texture: String::new(),
-        normal_texture: String::new(),
-        dissolve_texture: String::new(),
-        illumination_model: Some(0), // color on and ambient off
-        unknown_param: HashMap::new()
+fn load_diffuse(texture_base_path: &Path, original_material: &Material) -> DynamicImage {
+    let mut diffuse_texture_path = texture_base_path.to_path_buf();
+    diffuse_texture_path.push(&original_material.diffuse_texture);
+    image::open(&diffuse_texture_path)
+        .expect("Diffuse texture of material could not be loaded for weathering")
+}
+
+/// Writes the blent texture next to the output prefix and returns a material using it.
+fn write_blent_material(blent: ImageBuffer<image::Rgba<u8>, Vec<u8>>, output_file_prefix: &Path, material_name: &str) -> Material {
+    let mut blent_map_path = PathBuf::from(output_file_prefix);
+    blent_map_path.set_file_name(material_name);
+    blent_map_path.set_extension("png");
+
+    let target_filename_relative = String::from(blent_map_path.file_name().unwrap().to_str().unwrap());
+
+    info!("Writing blent texture {}...", target_filename_relative);
+    let blent_map_file = &mut File::create(blent_map_path).unwrap();
+
+    image::ImageRgba8(blent).save(blent_map_file, image::PNG)
+            .expect("Substance map texture could not be written");
+
+    material_with_diffuse(material_name, &target_filename_relative)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn solid(red: u8) -> DynamicImage {
+        image::ImageRgba8(ImageBuffer::from_pixel(2, 2, image::Rgba([red, 0, 0, 255])))
+    }
+
+    #[test]
+    fn test_layers_blend_in_order() {
+        let layers = vec![(SubstanceRef::from(0), solid(100)), (SubstanceRef::from(1), solid(200))];
+        let rust = SubstanceMap::new(2, 2, 0, 0, vec![1.0, 0.0, 1.0, 0.0]);
+        let moss = SubstanceMap::new(2, 2, 1, 0, vec![0.5, ::std::f32::NAN, 0.5, ::std::f32::NAN]);
+
+        let blent = blend_layers(&solid(0), &layers, &[&rust, &moss]);
+        let red = |x, y| blent.get_pixel(x, y).data[0];
+
+        // Moss halfway over fully rusted texels, no moss and no rust on the right
+        assert_eq!(red(0, 0), 150);
+        assert_eq!(red(0, 1), 150);
+        assert_eq!(red(1, 0), 0);
+        assert_eq!(red(1, 1), 0);
+    }
+
+    #[test]
+    fn test_blend_keeps_original_without_nearby_surfels() {
+        let rust = SubstanceMap::new(2, 2, 0, 0, vec![2.0, ::std::f32::NAN, 2.0, ::std::f32::NAN]);
+
+        let blent = blend_by_substance_map(&solid(100), &solid(200), &rust);
+        let red = |x, y| blent.get_pixel(x, y).data[0];
+
+        // Fully blended where saturated, original where no surfels are near
+        assert_eq!(red(0, 0), 200);
+        assert_eq!(red(0, 1), 200);
+        assert_eq!(red(1, 0), 100);
+        assert_eq!(red(1, 1), 100);
     }
 }
@@ -0,0 +1,128 @@
+use ::geom::aabb::Aabb;
+use ::geom::scene::Scene;
+use ::geom::surf::Surfel;
+
+use ::cgmath::Vector3;
+use ::cgmath::prelude::*;
+
+use std::f32;
+
+/// Restricts a rule to surfels with a certain orientation, position or entity, or scales the
+/// rate of the rule per surfel, e.g. so that moss only grows on upward surfaces facing north,
+/// or salt crusts only form below a certain height.
+///
+/// Each condition weighs surfels with a factor in 0..1, where 0 excludes the surfel and 1
+/// applies the rule at its full rate. Multiple conditions on a rule multiply their weights.
+#[derive(Debug, Clone)]
+pub enum Condition {
+    /// Only surfels with a normal that has at least the given dot product with the given
+    /// normalized direction.
+    Facing { direction: Vector3<f32>, min_dot: f32 },
+    /// Weighs surfels with the dot product of their normal with the given normalized direction,
+    /// excluding surfels facing away from it.
+    WeightedFacing { direction: Vector3<f32> },
+    /// Only surfels with a Y coordinate in the given range.
+    Height { min: f32, max: f32 },
+    /// Only surfels inside the given box.
+    Inside(Aabb),
+    /// Only surfels on the entity with the given name.
+    Entity(String)
+}
+
+impl Condition {
+    /// Only surfels with normals less than the given angle in radians away from the given direction.
+    pub fn facing(direction: Vector3<f32>, max_angle: f32) -> Condition {
+        Condition::Facing { direction: direction.normalize(), min_dot: max_angle.cos() }
+    }
+
+    /// Weighs surfels by how much they face the given direction.
+    pub fn weighted_facing(direction: Vector3<f32>) -> Condition {
+        Condition::WeightedFacing { direction: direction.normalize() }
+    }
+
+    /// Only surfels at or above the given height.
+    pub fn above(height: f32) -> Condition {
+        Condition::Height { min: height, max: f32::INFINITY }
+    }
+
+    /// Only surfels at or below the given height.
+    pub fn below(height: f32) -> Condition {
+        Condition::Height { min: f32::NEG_INFINITY, max: height }
+    }
+
+    /// Only surfels inside the box spanned by the given corners.
+    pub fn inside(min: Vector3<f32>, max: Vector3<f32>) -> Condition {
+        Condition::Inside(Aabb { min, max })
+    }
+
+    /// Only surfels on the entity with the given name.
+    pub fn entity<S : Into<String>>(name: S) -> Condition {
+        Condition::Entity(name.into())
+    }
+
+    /// Gets the weight of the surfel in the interval 0..1, where 0 means the condition
+    /// does not hold.
+    pub fn weight(&self, surfel: &Surfel, scene: &Scene) -> f32 {
+        let holds = match *self {
+            Condition::Facing { direction, min_dot } => surfel.normal.dot(direction) >= min_dot,
+            Condition::WeightedFacing { direction } => return surfel.normal.dot(direction).clamp(0.0, 1.0),
+            Condition::Height { min, max } => surfel.position.y >= min && surfel.position.y <= max,
+            Condition::Inside(ref aabb) => aabb.is_point_inside(surfel.position),
+            Condition::Entity(ref name) => scene.entities.get(surfel.entity_idx)
+                .map(|e| &e.name == name)
+                .unwrap_or(false)
+        };
+
+        if holds { 1.0 } else { 0.0 }
+    }
+
+    /// Gets the product of the weights of all given conditions, 1 if there are none.
+    pub fn combined_weight(conditions: &[Condition], surfel: &Surfel, scene: &Scene) -> f32 {
+        conditions.iter()
+            .map(|c| c.weight(surfel, scene))
+            .product()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::geom::surf::SurfaceBuilder;
+
+    fn surfel(position: Vector3<f32>, normal: Vector3<f32>) -> Surfel {
+        let mut surface = SurfaceBuilder::new()
+            .add_surface_from_points(vec![position])
+            .build();
+
+        surface.samples[0].normal = normal;
+        surface.samples.remove(0)
+    }
+
+    #[test]
+    fn test_facing_and_height() {
+        let scene = Scene::empty();
+        let up = Vector3::new(0.0, 1.0, 0.0);
+        let north = Vector3::new(0.0, 0.0, -1.0);
+        let roof = surfel(Vector3::new(0.0, 3.0, 0.0), Vector3::new(0.0, 1.0, -1.0).normalize());
+        let floor = surfel(Vector3::new(0.0, 0.5, 0.0), up);
+
+        let upward_north = vec![Condition::facing(up, 1.0), Condition::weighted_facing(north)];
+        assert!((Condition::combined_weight(&upward_north, &roof, &scene) - 0.5f32.sqrt()).abs() < 0.0001);
+        assert_eq!(Condition::combined_weight(&upward_north, &floor, &scene), 0.0);
+
+        let low = vec![Condition::below(1.0)];
+        assert_eq!(Condition::combined_weight(&low, &roof, &scene), 0.0);
+        assert_eq!(Condition::combined_weight(&low, &floor, &scene), 1.0);
+        assert_eq!(Condition::combined_weight(&[], &roof, &scene), 1.0);
+    }
+
+    #[test]
+    fn test_inside_box() {
+        let scene = Scene::empty();
+        let inside = Condition::inside(Vector3::new(-1.0, -1.0, -1.0), Vector3::new(1.0, 1.0, 1.0));
+        let up = Vector3::new(0.0, 1.0, 0.0);
+
+        assert_eq!(inside.weight(&surfel(Vector3::new(0.5, 0.0, 0.0), up), &scene), 1.0);
+        assert_eq!(inside.weight(&surfel(Vector3::new(1.5, 0.0, 0.0), up), &scene), 0.0);
+    }
+}
@@ -0,0 +1,198 @@
+use super::Effect;
+
+use ::geom::scene::Scene;
+use ::geom::surf::{Surface, SurfelGraph};
+
+use std::cell::RefCell;
+use std::f32;
+use std::path::Path;
+
+/// Spreads a substance from surfels to their neighbours on the surface, e.g. for moisture
+/// wicking into porous material, growth fronts of lichen or rust bleeding onto stone.
+///
+/// In each iteration, the amount exchanged between two neighbouring surfels is the rate times
+/// the difference of their concentrations, divided by the larger number of neighbours of the two.
+/// Substances are only moved, never created or destroyed.
+///
+/// With a gravity bias, surfels give more to lower neighbours and less to higher neighbours,
+/// so that substances spread downwards like running water. The bias is the relative increase
+/// in outflow towards a neighbour directly below.
+///
+/// The neighbourhood graph is built on first use and rebuilt when surfels are added, removed
+/// or moved.
+pub struct Diffusion {
+    substance_idx: usize,
+    rate: f32,
+    gravity_bias: f32,
+    neighbour_count: usize,
+    max_distance: f32,
+    min_normal_dot: f32,
+    graph: RefCell<Option<SurfelGraph>>
+}
+
+impl Diffusion {
+    /// Creates a diffusion effect for the substance with the given index, connecting surfels
+    /// with up to 8 nearest neighbours that do not face away from them.
+    ///
+    /// The rate must lie in the interval 0..1, otherwise concentrations would oscillate.
+    pub fn new(substance_idx: usize, rate: f32) -> Diffusion {
+        assert!((0.0..=1.0).contains(&rate), "Diffusion rate must lie in 0..1, got {}", rate);
+
+        Diffusion {
+            substance_idx,
+            rate,
+            gravity_bias: 0.0,
+            neighbour_count: 8,
+            max_distance: f32::INFINITY,
+            min_normal_dot: 0.0,
+            graph: RefCell::new(None)
+        }
+    }
+
+    /// Makes the substance spread faster downwards. The rate multiplied with one plus the bias
+    /// must not exceed one.
+    pub fn gravity_bias(mut self, gravity_bias: f32) -> Diffusion {
+        assert!(
+            gravity_bias >= 0.0 && self.rate * (1.0 + gravity_bias) <= 1.0,
+            "Gravity bias must be positive and rate * (1 + bias) must not exceed 1, got rate {} and bias {}",
+            self.rate, gravity_bias
+        );
+
+        self.gravity_bias = gravity_bias;
+        self
+    }
+
+    /// Sets the parameters for building the neighbourhood graph, see `SurfelGraph::new`.
+    pub fn neighbourhood(mut self, neighbour_count: usize, max_distance: f32, min_normal_dot: f32) -> Diffusion {
+        self.neighbour_count = neighbour_count;
+        self.max_distance = max_distance;
+        self.min_normal_dot = min_normal_dot;
+        self
+    }
+
+    fn diffuse(&self, graph: &SurfelGraph, surf: &mut Surface, time_step: f32) {
+        let substance_idx = self.substance_idx;
+        let rate = self.rate * time_step;
+
+        let concentrations : Vec<f32> = surf.samples.iter()
+            .map(|s| s.substances[substance_idx])
+            .collect();
+
+        let mut changes = vec![0.0; concentrations.len()];
+
+        for (a, b, distance) in graph.edges() {
+            let weight = rate / (graph.neighbours(a).len().max(graph.neighbours(b).len()) as f32);
+
+            // Positive if a is higher than b
+            let descent = if distance > 0.0 {
+                (surf.samples[a].position.y - surf.samples[b].position.y) / distance
+            } else {
+                0.0
+            };
+
+            let outflow_a = (1.0 + self.gravity_bias * descent).max(0.0);
+            let outflow_b = (1.0 - self.gravity_bias * descent).max(0.0);
+
+            let flux = weight * (outflow_a * concentrations[a] - outflow_b * concentrations[b]);
+
+            changes[a] -= flux;
+            changes[b] += flux;
+        }
+
+        for (surfel, change) in surf.samples.iter_mut().zip(changes) {
+            let substance = &mut surfel.substances[substance_idx];
+            *substance = (*substance + change).max(0.0);
+        }
+    }
+}
+
+impl Effect for Diffusion {
+    fn perform(&self, scene: &mut Scene, surf: &mut Surface, output_prefix: &Path) {
+        self.perform_timed(scene, surf, output_prefix, 1.0)
+    }
+
+    /// Diffuses with the rate per unit of time multiplied by the time step. Time steps that are
+    /// so long that concentrations would oscillate are split into shorter steps.
+    fn perform_timed(&self, _: &mut Scene, surf: &mut Surface, _: &Path, time_step: f32) {
+        let mut graph = self.graph.borrow_mut();
+
+        let outdated = match *graph {
+            Some(ref graph) => !graph.is_current(surf),
+            None => true
+        };
+
+        if outdated {
+            info!("Building surfel graph for diffusion of substance {}...", self.substance_idx);
+            *graph = Some(SurfelGraph::new(surf, self.neighbour_count, self.max_distance, self.min_normal_dot));
+        }
+
+        // Keep rate * (1 + bias) at most one in each step
+        let sub_steps = (self.rate * time_step * (1.0 + self.gravity_bias)).ceil().max(1.0) as u32;
+        for _ in 0..sub_steps {
+            self.diffuse(graph.as_ref().unwrap(), surf, time_step / sub_steps as f32);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::geom::surf::SurfaceBuilder;
+    use ::cgmath::Vector3;
+
+    fn vertical_line() -> Surface {
+        let mut surface = SurfaceBuilder::new()
+            .substances(&vec![0.0])
+            .add_surface_from_points((0..5).map(|y| Vector3::new(0.0, y as f32, 0.0)))
+            .build();
+
+        surface.samples[2].substances[0] = 1.0;
+        surface
+    }
+
+    fn total(surface: &Surface) -> f32 {
+        surface.samples.iter().map(|s| s.substances[0]).sum()
+    }
+
+    #[test]
+    fn test_diffusion_conserves_and_spreads() {
+        let mut surface = vertical_line();
+        let diffusion = Diffusion::new(0, 0.5).neighbourhood(2, 1.5, -1.0);
+
+        diffusion.perform(&mut Scene::empty(), &mut surface, Path::new(""));
+
+        assert!((total(&surface) - 1.0).abs() < 0.00001);
+        assert!(surface.samples[2].substances[0] < 1.0);
+        assert!(surface.samples[1].substances[0] > 0.0);
+        assert!((surface.samples[1].substances[0] - surface.samples[3].substances[0]).abs() < 0.00001);
+    }
+
+    #[test]
+    fn test_gravity_bias_spreads_downwards() {
+        let mut surface = vertical_line();
+        let diffusion = Diffusion::new(0, 0.4)
+            .gravity_bias(1.0)
+            .neighbourhood(2, 1.5, -1.0);
+
+        diffusion.perform(&mut Scene::empty(), &mut surface, Path::new(""));
+
+        assert!((total(&surface) - 1.0).abs() < 0.00001);
+        assert!(surface.samples[1].substances[0] > surface.samples[3].substances[0]);
+    }
+
+    #[test]
+    fn test_long_time_steps_stay_stable() {
+        let mut surface = vertical_line();
+        let diffusion = Diffusion::new(0, 0.5)
+            .gravity_bias(0.5)
+            .neighbourhood(2, 1.5, -1.0);
+
+        diffusion.perform_timed(&mut Scene::empty(), &mut surface, Path::new(""), 4.0);
+
+        assert!((total(&surface) - 1.0).abs() < 0.00001);
+        // Without sub-steps, the source surfel would overshoot far below zero and get clamped
+        assert!(surface.samples.iter().all(|s| s.substances[0] > 0.0));
+        assert!(surface.samples[2].substances[0] > surface.samples[3].substances[0]);
+        assert!(surface.samples[3].substances[0] > surface.samples[4].substances[0]);
+    }
+}
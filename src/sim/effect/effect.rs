@@ -1,5 +1,6 @@
 
-use ::geom::scene::Scene;
+use ::geom::octree::Octree;
+use ::geom::scene::{Scene, Triangle};
 use ::geom::surf::Surface;
 
 use std::path::Path;
@@ -10,4 +11,22 @@ pub trait Effect {
     /// Applies an iterative weathering effect by mutating the referenced scene.
     /// Changed geometry will effect future iterations.
     fn perform(&self, scene: &mut Scene, surf: &mut Surface, output_prefix: &Path);
+
+    /// Like `perform`, but for simulations where each iteration stands for the given amount of
+    /// simulated time, e.g. a year.
+    ///
+    /// Effects with rates per unit of time scale them with the time step, `perform` then advances
+    /// by one unit of time. Other effects ignore the time step, which is the default.
+    fn perform_timed(&self, scene: &mut Scene, surf: &mut Surface, output_prefix: &Path, time_step: f32) {
+        let _ = time_step;
+        self.perform(scene, surf, output_prefix)
+    }
+
+    /// Like `perform_timed`, but with the octree of scene triangles that the simulation built for
+    /// tracing tons in the same iteration, so that effects casting rays need not build another one.
+    /// Other effects ignore the octree, which is the default.
+    fn perform_with_octree(&self, scene: &mut Scene, surf: &mut Surface, octree: &Octree<Triangle>, output_prefix: &Path, time_step: f32) {
+        let _ = octree;
+        self.perform_timed(scene, surf, output_prefix, time_step)
+    }
 }
@@ -0,0 +1,769 @@
+//! A small expression language for surfel rules that combine several substances, e.g.
+//!
+//! ```text
+//! rust += 0.2 * water * oxygen;
+//! moss += 0.1 * water * (1 - sunlight) if water > 0.3;
+//! rust = min(rust, 1)
+//! ```
+//!
+//! A program is a list of assignments separated by semicolons, each changing a substance of a
+//! surfel with `=`, `+=`, `-=` or `*=`, optionally only if a condition holds. Substances are
+//! referred to by name, or by index with `substance[0]`.
+//!
+//! Expressions support numbers, `+`, `-`, `*`, `/`, parentheses, comparisons `<`, `<=`, `>`
+//! and `>=`, `and`, `or` and `not`, and the functions `min`, `max`, `clamp` and `abs`.
+//! Comparisons and logical operators evaluate to one if true and to zero otherwise, and any
+//! value other than zero counts as true.
+
+use std::error;
+use std::fmt;
+use std::result;
+
+use ::substance::{SubstanceRef, SubstanceRegistry};
+
+pub type Result<T> = result::Result<T, ParseError>;
+
+/// An expression evaluated on the substances of a single surfel.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Const(f32),
+    Substance(SubstanceRef),
+    Neg(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    Call(Function, Vec<Expr>)
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    And,
+    Or
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Function {
+    /// Smallest of two or more arguments
+    Min,
+    /// Largest of two or more arguments
+    Max,
+    /// First argument limited to the range from the second to the third argument
+    Clamp,
+    /// Absolute value of the single argument
+    Abs
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum AssignOp {
+    Set,
+    Add,
+    Sub,
+    Mul
+}
+
+/// Changes a single substance of a surfel, optionally only if a condition holds.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Assignment {
+    pub target: SubstanceRef,
+    pub op: AssignOp,
+    pub value: Expr,
+    pub condition: Option<Expr>
+}
+
+/// Syntax error in an expression, with the byte offset in the source where it was found.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub offset: usize,
+    pub message: String
+}
+
+impl Expr {
+    /// Replaces references to substances by name with references by index.
+    ///
+    /// # Panics
+    /// If a referenced substance was not declared.
+    pub fn resolve(&self, substances: &SubstanceRegistry) -> Expr {
+        match *self {
+            Expr::Const(value) => Expr::Const(value),
+            Expr::Substance(ref substance) => Expr::Substance(SubstanceRef::Idx(substances.resolve(substance))),
+            Expr::Neg(ref operand) => Expr::Neg(Box::new(operand.resolve(substances))),
+            Expr::Not(ref operand) => Expr::Not(Box::new(operand.resolve(substances))),
+            Expr::Binary(op, ref lhs, ref rhs) => Expr::Binary(
+                op,
+                Box::new(lhs.resolve(substances)),
+                Box::new(rhs.resolve(substances))
+            ),
+            Expr::Call(function, ref args) => Expr::Call(
+                function,
+                args.iter().map(|a| a.resolve(substances)).collect()
+            )
+        }
+    }
+
+    /// Evaluates the expression as a condition, which holds for all values except zero.
+    pub fn holds(&self, substances: &[f32]) -> bool {
+        is_true(self.eval(substances))
+    }
+
+    /// Checks if evaluating the expression reads the substance with the given index.
+    pub fn refers_to(&self, substance_idx: usize) -> bool {
+        match *self {
+            Expr::Const(_) => false,
+            Expr::Substance(SubstanceRef::Idx(idx)) => idx == substance_idx,
+            Expr::Substance(SubstanceRef::Name(_)) => false,
+            Expr::Neg(ref operand) | Expr::Not(ref operand) => operand.refers_to(substance_idx),
+            Expr::Binary(_, ref lhs, ref rhs) => lhs.refers_to(substance_idx) || rhs.refers_to(substance_idx),
+            Expr::Call(_, ref args) => args.iter().any(|a| a.refers_to(substance_idx))
+        }
+    }
+
+    /// Gets the factor `k` if the expression is provably `k * target` for the substance with the
+    /// given index, with `k` evaluated on the given substance amounts, e.g. `0.5` for
+    /// `0.5 * water` or `-oxygen / 2` for `-water * oxygen / 2`. Gets `None` for all other
+    /// expressions, e.g. `1 - water`.
+    pub fn rate_of(&self, target: usize, substances: &[f32]) -> Option<f32> {
+        match *self {
+            Expr::Substance(SubstanceRef::Idx(idx)) if idx == target => Some(1.0),
+            Expr::Neg(ref operand) => operand.rate_of(target, substances).map(|rate| -rate),
+            Expr::Binary(BinaryOp::Mul, ref lhs, ref rhs) if !lhs.refers_to(target) =>
+                rhs.rate_of(target, substances).map(|rate| lhs.eval(substances) * rate),
+            Expr::Binary(BinaryOp::Mul, ref lhs, ref rhs) if !rhs.refers_to(target) =>
+                lhs.rate_of(target, substances).map(|rate| rate * rhs.eval(substances)),
+            Expr::Binary(BinaryOp::Div, ref lhs, ref rhs) if !rhs.refers_to(target) =>
+                lhs.rate_of(target, substances).map(|rate| rate / rhs.eval(substances)),
+            _ => None
+        }
+    }
+
+    /// Evaluates the expression on the given substance amounts of a surfel.
+    ///
+    /// # Panics
+    /// If the expression refers to a substance by name, use `resolve` first.
+    pub fn eval(&self, substances: &[f32]) -> f32 {
+        match *self {
+            Expr::Const(value) => value,
+            Expr::Substance(SubstanceRef::Idx(idx)) => substances[idx],
+            Expr::Substance(SubstanceRef::Name(ref name)) => panic!("Substance {} must be resolved before evaluating", name),
+            Expr::Neg(ref operand) => -operand.eval(substances),
+            Expr::Not(ref operand) => truth(!is_true(operand.eval(substances))),
+            Expr::Binary(op, ref lhs, ref rhs) => {
+                let lhs = lhs.eval(substances);
+                let rhs = rhs.eval(substances);
+
+                match op {
+                    BinaryOp::Add => lhs + rhs,
+                    BinaryOp::Sub => lhs - rhs,
+                    BinaryOp::Mul => lhs * rhs,
+                    BinaryOp::Div => lhs / rhs,
+                    BinaryOp::Less => truth(lhs < rhs),
+                    BinaryOp::LessEqual => truth(lhs <= rhs),
+                    BinaryOp::Greater => truth(lhs > rhs),
+                    BinaryOp::GreaterEqual => truth(lhs >= rhs),
+                    BinaryOp::And => truth(is_true(lhs) && is_true(rhs)),
+                    BinaryOp::Or => truth(is_true(lhs) || is_true(rhs))
+                }
+            },
+            Expr::Call(function, ref args) => {
+                let args : Vec<f32> = args.iter().map(|a| a.eval(substances)).collect();
+
+                match function {
+                    Function::Min => args.iter().cloned().fold(f32::INFINITY, f32::min),
+                    Function::Max => args.iter().cloned().fold(f32::NEG_INFINITY, f32::max),
+                    Function::Clamp => args[0].max(args[1]).min(args[2]),
+                    Function::Abs => args[0].abs()
+                }
+            }
+        }
+    }
+}
+
+impl Assignment {
+    /// Replaces references to substances by name with references by index.
+    ///
+    /// # Panics
+    /// If a referenced substance was not declared.
+    pub fn resolve(&self, substances: &SubstanceRegistry) -> Assignment {
+        Assignment {
+            target: SubstanceRef::Idx(substances.resolve(&self.target)),
+            op: self.op,
+            value: self.value.resolve(substances),
+            condition: self.condition.as_ref().map(|c| c.resolve(substances))
+        }
+    }
+
+    /// Changes the target substance in the given substance amounts, if the condition holds.
+    /// Like with `SurfelRule`, the amount cannot become negative.
+    ///
+    /// # Panics
+    /// If the assignment refers to a substance by name, use `resolve` first.
+    pub fn apply(&self, substances: &mut [f32]) {
+        self.apply_timed(substances, 1.0)
+    }
+
+    /// Like `apply`, but treats the values of `+=` and `-=` as rates per unit of time and
+    /// scales them with the given time step, and the factor of `*=` as the factor per unit of
+    /// time, raised to the power of the time step. Plain assignments are not affected.
+    ///
+    /// If the value of `+=` or `-=` is proportional to the target, e.g. `water -= 0.5 * water`,
+    /// the change compounds over the time step like `SurfelRule` does when it reads and writes
+    /// the same substance, so that long time steps give the same result as several short ones
+    /// instead of overshooting below zero. Values that read the target in other ways, e.g.
+    /// `moss += 0.1 * (1 - moss)`, are applied in steps of at most one unit of time instead.
+    pub fn apply_timed(&self, substances: &mut [f32], time_step: f32) {
+        if let Some(ref condition) = self.condition {
+            if !is_true(condition.eval(substances)) {
+                return;
+            }
+        }
+
+        let target = match self.target {
+            SubstanceRef::Idx(idx) => idx,
+            SubstanceRef::Name(ref name) => panic!("Substance {} must be resolved before evaluating", name)
+        };
+
+        let current = substances[target];
+
+        substances[target] = match self.op {
+            AssignOp::Set => self.value.eval(substances),
+            AssignOp::Add => self.add_timed(substances, target, 1.0, time_step),
+            AssignOp::Sub => self.add_timed(substances, target, -1.0, time_step),
+            // Compounds to the same factor over a unit of time, regardless of the step
+            AssignOp::Mul => current * self.value.eval(substances).powf(time_step)
+        }.max(0.0);
+    }
+
+    /// Gets the target amount after adding the value per unit of time with the given sign over
+    /// the time step. Changes proportional to the target compound, which is exact, other changes
+    /// that read the target are evaluated again after each unit of time.
+    fn add_timed(&self, substances: &mut [f32], target: usize, sign: f32, time_step: f32) -> f32 {
+        let current = substances[target];
+
+        if !self.value.refers_to(target) {
+            return current + sign * time_step * self.value.eval(substances);
+        }
+
+        if let Some(rate) = self.value.rate_of(target, substances) {
+            return current * (1.0 + sign * rate).max(0.0).powf(time_step);
+        }
+
+        let steps = time_step.ceil().max(1.0);
+        let step = time_step / steps;
+        for _ in 0..(steps as usize) {
+            let change = sign * step * self.value.eval(substances);
+            substances[target] = (substances[target] + change).max(0.0);
+        }
+
+        substances[target]
+    }
+}
+
+impl error::Error for ParseError {}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at offset {}", self.message, self.offset)
+    }
+}
+
+fn truth(condition: bool) -> f32 {
+    if condition { 1.0 } else { 0.0 }
+}
+
+fn is_true(value: f32) -> bool {
+    value != 0.0
+}
+
+/// Parses a program of assignments separated by semicolons.
+pub fn parse_program(source: &str) -> Result<Vec<Assignment>> {
+    let mut parser = Parser::new(source)?;
+    let mut assignments = Vec::new();
+
+    while !parser.at_end() {
+        assignments.push(parser.assignment()?);
+
+        if !parser.at_end() {
+            parser.expect(&Token::Semicolon, "Expected ; between assignments")?;
+        }
+    }
+
+    if assignments.is_empty() {
+        return Err(ParseError { offset: 0, message: String::from("Expected at least one assignment") });
+    }
+
+    Ok(assignments)
+}
+
+/// Parses a single expression, e.g. for conditions.
+pub fn parse_expr(source: &str) -> Result<Expr> {
+    let mut parser = Parser::new(source)?;
+    let expr = parser.expr()?;
+
+    if !parser.at_end() {
+        return Err(parser.error("Unexpected input after expression"));
+    }
+
+    Ok(expr)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f32),
+    Ident(String),
+    LeftParen,
+    RightParen,
+    LeftBracket,
+    RightBracket,
+    Comma,
+    Semicolon,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Assign(AssignOp)
+}
+
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>> {
+    let chars : Vec<(usize, char)> = source.char_indices().collect();
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+
+    let next_is = |pos: usize, expected: char| chars.get(pos + 1).map(|&(_, c)| c == expected).unwrap_or(false);
+
+    while pos < chars.len() {
+        let (offset, c) = chars[pos];
+
+        if c.is_whitespace() {
+            pos += 1;
+            continue;
+        }
+
+        if c.is_ascii_digit() || c == '.' {
+            while pos < chars.len() && (chars[pos].1.is_ascii_digit() || chars[pos].1 == '.') {
+                pos += 1;
+            }
+
+            let end = chars.get(pos).map(|&(o, _)| o).unwrap_or(source.len());
+            let number = source[offset..end].parse::<f32>()
+                .map_err(|_| ParseError { offset, message: format!("Invalid number {}", &source[offset..end]) })?;
+
+            tokens.push((offset, Token::Number(number)));
+            continue;
+        }
+
+        if c.is_alphabetic() || c == '_' {
+            while pos < chars.len() && (chars[pos].1.is_alphanumeric() || chars[pos].1 == '_') {
+                pos += 1;
+            }
+
+            let end = chars.get(pos).map(|&(o, _)| o).unwrap_or(source.len());
+            tokens.push((offset, Token::Ident(String::from(&source[offset..end]))));
+            continue;
+        }
+
+        let (token, len) = match c {
+            '(' => (Token::LeftParen, 1),
+            ')' => (Token::RightParen, 1),
+            '[' => (Token::LeftBracket, 1),
+            ']' => (Token::RightBracket, 1),
+            ',' => (Token::Comma, 1),
+            ';' => (Token::Semicolon, 1),
+            '+' if next_is(pos, '=') => (Token::Assign(AssignOp::Add), 2),
+            '-' if next_is(pos, '=') => (Token::Assign(AssignOp::Sub), 2),
+            '*' if next_is(pos, '=') => (Token::Assign(AssignOp::Mul), 2),
+            '<' if next_is(pos, '=') => (Token::LessEqual, 2),
+            '>' if next_is(pos, '=') => (Token::GreaterEqual, 2),
+            '+' => (Token::Plus, 1),
+            '-' => (Token::Minus, 1),
+            '*' => (Token::Star, 1),
+            '/' => (Token::Slash, 1),
+            '<' => (Token::Less, 1),
+            '>' => (Token::Greater, 1),
+            '=' => (Token::Assign(AssignOp::Set), 1),
+            _ => return Err(ParseError { offset, message: format!("Unexpected character {}", c) })
+        };
+
+        tokens.push((offset, token));
+        pos += len;
+    }
+
+    Ok(tokens)
+}
+
+/// Recursive descent parser, from lowest to highest precedence: `or`, `and`, comparisons,
+/// sums, products, unary operators and primary expressions.
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+    source_len: usize
+}
+
+impl Parser {
+    fn new(source: &str) -> Result<Parser> {
+        Ok(Parser {
+            tokens: tokenize(source)?,
+            pos: 0,
+            source_len: source.len()
+        })
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos >= self.tokens.len()
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(_, t)| t)
+    }
+
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(Token::Ident(ident)) => ident == keyword,
+            _ => false
+        }
+    }
+
+    fn advance(&mut self) -> Option<Token> {
+        let token = self.peek().cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn error(&self, message: &str) -> ParseError {
+        let offset = self.tokens.get(self.pos)
+            .map(|&(offset, _)| offset)
+            .unwrap_or(self.source_len);
+
+        ParseError { offset, message: String::from(message) }
+    }
+
+    fn expect(&mut self, expected: &Token, message: &str) -> Result<()> {
+        if self.peek() == Some(expected) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.error(message))
+        }
+    }
+
+    fn assignment(&mut self) -> Result<Assignment> {
+        let target = match self.advance() {
+            Some(Token::Ident(name)) => self.substance(name)?,
+            _ => {
+                self.pos -= 1;
+                return Err(self.error("Expected substance to assign to"));
+            }
+        };
+
+        let op = match self.advance() {
+            Some(Token::Assign(op)) => op,
+            _ => {
+                self.pos -= 1;
+                return Err(self.error("Expected =, +=, -= or *="));
+            }
+        };
+
+        let value = self.expr()?;
+
+        let condition = if self.peek_keyword("if") {
+            self.pos += 1;
+            Some(self.expr()?)
+        } else {
+            None
+        };
+
+        Ok(Assignment { target, op, value, condition })
+    }
+
+    /// Parses the rest of a substance reference after its identifier.
+    fn substance(&mut self, name: String) -> Result<SubstanceRef> {
+        if name != "substance" || self.peek() != Some(&Token::LeftBracket) {
+            return Ok(SubstanceRef::Name(name));
+        }
+
+        self.pos += 1;
+        let idx = match self.advance() {
+            Some(Token::Number(idx)) if idx >= 0.0 && idx.fract() == 0.0 => idx as usize,
+            _ => {
+                self.pos -= 1;
+                return Err(self.error("Expected substance index"));
+            }
+        };
+        self.expect(&Token::RightBracket, "Expected ] after substance index")?;
+
+        Ok(SubstanceRef::Idx(idx))
+    }
+
+    fn expr(&mut self) -> Result<Expr> {
+        let mut lhs = self.and()?;
+
+        while self.peek_keyword("or") {
+            self.pos += 1;
+            let rhs = self.and()?;
+            lhs = Expr::Binary(BinaryOp::Or, Box::new(lhs), Box::new(rhs));
+        }
+
+        Ok(lhs)
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        let mut lhs = self.comparison()?;
+
+        while self.peek_keyword("and") {
+            self.pos += 1;
+            let rhs = self.comparison()?;
+            lhs = Expr::Binary(BinaryOp::And, Box::new(lhs), Box::new(rhs));
+        }
+
+        Ok(lhs)
+    }
+
+    fn comparison(&mut self) -> Result<Expr> {
+        let lhs = self.sum()?;
+
+        let op = match self.peek() {
+            Some(&Token::Less) => BinaryOp::Less,
+            Some(&Token::LessEqual) => BinaryOp::LessEqual,
+            Some(&Token::Greater) => BinaryOp::Greater,
+            Some(&Token::GreaterEqual) => BinaryOp::GreaterEqual,
+            _ => return Ok(lhs)
+        };
+
+        self.pos += 1;
+        let rhs = self.sum()?;
+
+        Ok(Expr::Binary(op, Box::new(lhs), Box::new(rhs)))
+    }
+
+    fn sum(&mut self) -> Result<Expr> {
+        let mut lhs = self.product()?;
+
+        loop {
+            let op = match self.peek() {
+                Some(&Token::Plus) => BinaryOp::Add,
+                Some(&Token::Minus) => BinaryOp::Sub,
+                _ => return Ok(lhs)
+            };
+
+            self.pos += 1;
+            let rhs = self.product()?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    fn product(&mut self) -> Result<Expr> {
+        let mut lhs = self.unary()?;
+
+        loop {
+            let op = match self.peek() {
+                Some(&Token::Star) => BinaryOp::Mul,
+                Some(&Token::Slash) => BinaryOp::Div,
+                _ => return Ok(lhs)
+            };
+
+            self.pos += 1;
+            let rhs = self.unary()?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        if self.peek() == Some(&Token::Minus) {
+            self.pos += 1;
+            return Ok(Expr::Neg(Box::new(self.unary()?)));
+        }
+
+        if self.peek_keyword("not") {
+            self.pos += 1;
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr> {
+        match self.advance() {
+            Some(Token::Number(value)) => Ok(Expr::Const(value)),
+            Some(Token::LeftParen) => {
+                let expr = self.expr()?;
+                self.expect(&Token::RightParen, "Expected )")?;
+                Ok(expr)
+            },
+            Some(Token::Ident(name)) => {
+                if self.peek() == Some(&Token::LeftParen) {
+                    self.call(&name)
+                } else {
+                    Ok(Expr::Substance(self.substance(name)?))
+                }
+            },
+            _ => {
+                self.pos -= 1;
+                Err(self.error("Expected number, substance, function call or ("))
+            }
+        }
+    }
+
+    fn call(&mut self, name: &str) -> Result<Expr> {
+        let (function, min_args, max_args) = match name {
+            "min" => (Function::Min, 2, usize::MAX),
+            "max" => (Function::Max, 2, usize::MAX),
+            "clamp" => (Function::Clamp, 3, 3),
+            "abs" => (Function::Abs, 1, 1),
+            _ => {
+                self.pos -= 1;
+                return Err(self.error(&format!("Unknown function {}, try min, max, clamp or abs", name)));
+            }
+        };
+
+        // Skip the opening parenthesis
+        self.pos += 1;
+
+        let mut args = vec![self.expr()?];
+        while self.peek() == Some(&Token::Comma) {
+            self.pos += 1;
+            args.push(self.expr()?);
+        }
+        self.expect(&Token::RightParen, "Expected , or ) in function call")?;
+
+        if args.len() < min_args || args.len() > max_args {
+            return Err(self.error(&format!("Wrong number of arguments for {}: {}", name, args.len())));
+        }
+
+        Ok(Expr::Call(function, args))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::substance::Substance;
+
+    fn registry() -> SubstanceRegistry {
+        let mut registry = SubstanceRegistry::new();
+        for name in &["water", "oxygen", "rust", "sunlight", "moss"] {
+            registry.add(Substance::new(*name));
+        }
+        registry
+    }
+
+    fn run(source: &str, substances: &mut [f32]) {
+        let registry = registry();
+        for assignment in parse_program(source).unwrap() {
+            assignment.resolve(&registry).apply(substances);
+        }
+    }
+
+    #[test]
+    fn test_product_of_several_substances() {
+        let mut substances = [0.5, 0.4, 0.1, 0.0, 0.0];
+        run("rust += 0.2 * water * oxygen", &mut substances);
+        assert!((substances[2] - 0.14).abs() < 0.00001);
+    }
+
+    #[test]
+    fn test_condition_guards_assignment() {
+        let source = "moss += 0.1 * water * (1 - sunlight) if water > 0.3";
+
+        let mut dry = [0.2, 0.0, 0.0, 0.5, 0.0];
+        run(source, &mut dry);
+        assert_eq!(dry[4], 0.0);
+
+        let mut wet = [0.5, 0.0, 0.0, 0.5, 0.0];
+        run(source, &mut wet);
+        assert!((wet[4] - 0.025).abs() < 0.00001);
+    }
+
+    #[test]
+    fn test_functions_and_indexes() {
+        let mut substances = [0.9, 0.0, 0.95, 0.0, 0.0];
+        run("rust += water; substance[2] = min(rust, 1); oxygen = clamp(-2, 0.1, 0.3) + abs(-0.1)", &mut substances);
+        assert_eq!(substances[2], 1.0);
+        assert!((substances[1] - 0.2).abs() < 0.00001);
+    }
+
+    #[test]
+    fn test_multiplication_compounds_over_time_steps() {
+        let decay = parse_program("water *= 0.9").unwrap().remove(0).resolve(&registry());
+
+        let mut once = [1.0, 0.0, 0.0, 0.0, 0.0];
+        decay.apply_timed(&mut once, 1.0);
+
+        let mut quarters = [1.0, 0.0, 0.0, 0.0, 0.0];
+        for _ in 0..4 {
+            decay.apply_timed(&mut quarters, 0.25);
+        }
+
+        assert!((once[0] - 0.9).abs() < 0.00001);
+        assert!((quarters[0] - once[0]).abs() < 0.00001);
+    }
+
+    #[test]
+    fn test_self_referencing_decay_over_time_steps() {
+        let decay = parse_program("water -= 0.5 * water").unwrap().remove(0).resolve(&registry());
+
+        let mut coarse = [1.0, 0.0, 0.0, 0.0, 0.0];
+        decay.apply_timed(&mut coarse, 4.0);
+
+        let mut fine = [1.0, 0.0, 0.0, 0.0, 0.0];
+        for _ in 0..16 {
+            decay.apply_timed(&mut fine, 0.25);
+        }
+
+        // Same as SurfelRule::new(0, 0, -0.5) over four units of time
+        assert!((coarse[0] - 0.0625).abs() < 0.00001, "Expected 0.0625, got {}", coarse[0]);
+        assert!((fine[0] - coarse[0]).abs() < 0.00001);
+
+        // Other substances still change linearly with time
+        let growth = parse_program("rust += 0.1 * water").unwrap().remove(0).resolve(&registry());
+        let mut substances = [1.0, 0.0, 0.0, 0.0, 0.0];
+        growth.apply_timed(&mut substances, 4.0);
+        assert!((substances[2] - 0.4).abs() < 0.00001);
+    }
+
+    #[test]
+    fn test_saturating_growth_over_long_time_steps() {
+        let growth = parse_program("moss += 0.1 * (1 - moss)").unwrap().remove(0).resolve(&registry());
+
+        let mut coarse = [0.0, 0.0, 0.0, 0.0, 0.5];
+        growth.apply_timed(&mut coarse, 10.0);
+
+        let mut units = [0.0, 0.0, 0.0, 0.0, 0.5];
+        for _ in 0..10 {
+            growth.apply_timed(&mut units, 1.0);
+        }
+
+        // Approaches the fixed point at 1 without overshooting it
+        let expected = 1.0 - 0.5 * 0.9_f32.powi(10);
+        assert!((coarse[4] - expected).abs() < 0.00001, "Expected {}, got {}", expected, coarse[4]);
+        assert!((units[4] - coarse[4]).abs() < 0.00001);
+
+        // Growth from zero does not jump to a different branch
+        let mut empty = [0.0; 5];
+        growth.apply_timed(&mut empty, 10.0);
+        assert!((empty[4] - (1.0 - 0.9_f32.powi(10))).abs() < 0.00001);
+    }
+
+    #[test]
+    fn test_precedence() {
+        let expr = parse_expr("1 + 2 * 3 > 6 and not 0").unwrap();
+        assert_eq!(expr.eval(&[]), 1.0);
+        assert_eq!(parse_expr("-2 * -(1 - 3)").unwrap().eval(&[]), -4.0);
+    }
+
+    #[test]
+    fn test_syntax_errors_have_offsets() {
+        assert_eq!(parse_program("rust += 0.2 *").unwrap_err().offset, 13);
+        assert_eq!(parse_program("rust += foo(1)").unwrap_err().offset, 8);
+        assert_eq!(parse_program("rust + 1").unwrap_err().offset, 5);
+        assert!(parse_program("").is_err());
+    }
+}
@@ -0,0 +1,128 @@
+use super::Effect;
+
+use ::geom::octree::Octree;
+use ::geom::scene::{Scene, Triangle, geometry_fingerprint};
+use ::geom::surf::{Surface, GeometricFeature};
+
+use std::cell::RefCell;
+use std::path::Path;
+
+/// Changes a substance on every surfel in proportion to a geometric feature of the surface,
+/// e.g. to let dirt accumulate in cavities or to wear paint off edges:
+///
+/// substance = max(0.0, substance + rate * feature)
+///
+/// The feature values are computed on first use and only computed again when surfels are
+/// added, removed or moved, or when triangles of the scene are.
+pub struct FeatureRule {
+    substance_idx: usize,
+    rate: f32,
+    feature: GeometricFeature,
+    /// Revision of the surface, fingerprint of the scene geometry and the feature value of each
+    /// surfel for both
+    feature_values: RefCell<Option<(u64, u64, Vec<f32>)>>
+}
+
+impl FeatureRule {
+    /// Creates a rule that changes the substance with the given index by the given rate
+    /// times the feature of each surfel.
+    pub fn new(substance_idx: usize, rate: f32, feature: GeometricFeature) -> FeatureRule {
+        FeatureRule { substance_idx, rate, feature, feature_values: RefCell::new(None) }
+    }
+}
+
+impl Effect for FeatureRule {
+    fn perform(&self, scene: &mut Scene, surf: &mut Surface, output_prefix: &Path) {
+        self.perform_timed(scene, surf, output_prefix, 1.0)
+    }
+
+    fn perform_timed(&self, scene: &mut Scene, surf: &mut Surface, output_prefix: &Path, time_step: f32) {
+        let octree : Octree<Triangle> = scene.triangles().collect();
+        self.perform_with_octree(scene, surf, &octree, output_prefix, time_step)
+    }
+
+    fn perform_with_octree(&self, _: &mut Scene, surf: &mut Surface, octree: &Octree<Triangle>, _: &Path, time_step: f32) {
+        let mut feature_values = self.feature_values.borrow_mut();
+        let fingerprint = geometry_fingerprint(octree);
+
+        let outdated = match *feature_values {
+            Some((revision, geometry, ref values)) => revision != surf.revision() || geometry != fingerprint || values.len() != surf.samples.len(),
+            None => true
+        };
+
+        if outdated {
+            info!("Computing {:?} for {} surfels...", self.feature, surf.samples.len());
+            *feature_values = Some((surf.revision(), fingerprint, self.feature.compute(surf, octree)));
+        }
+
+        let (_, _, values) = feature_values.as_ref().unwrap();
+
+        for (surfel, feature_value) in surf.samples.iter_mut().zip(values) {
+            let substance = &mut surfel.substances[self.substance_idx];
+            *substance = (*substance + time_step * self.rate * feature_value).max(0.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::geom::scene::{Mesh, horizontal_triangle};
+    use ::geom::surf::SurfaceBuilder;
+    use ::cgmath::Vector3;
+
+    fn ground() -> Scene {
+        Scene::with_mesh(Mesh {
+            indices: vec![0, 1, 2],
+            positions: vec![-1.0, 0.0, -1.0, 1.0, 0.0, -1.0, 0.0, 0.0, 1.0],
+            normals: vec![0.0, 1.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 0.0],
+            texcoords: vec![0.0, 0.0, 1.0, 0.0, 0.0, 1.0]
+        })
+    }
+
+    #[test]
+    fn test_feature_values_follow_inserted_and_removed_surfels() {
+        let mut scene = ground();
+        let mut surface = SurfaceBuilder::new()
+            .substances(&vec![0.0])
+            .add_surface_from_points(vec![Vector3::new(0.0, 0.0, 0.0), Vector3::new(0.0, 1.0, 0.0)])
+            .build();
+        let rule = FeatureRule::new(0, 1.0, GeometricFeature::Height);
+
+        rule.perform(&mut scene, &mut surface, Path::new(""));
+        assert_eq!(surface.samples[1].substances[0], 1.0);
+
+        // Highest surfel moves into the place of the lowest one, which is inserted again in the middle
+        let mut moved = surface.remove(0);
+        moved.position = Vector3::new(0.0, 0.5, 0.0);
+        surface.insert(moved);
+
+        rule.perform(&mut scene, &mut surface, Path::new(""));
+        assert_eq!(surface.samples.len(), 2);
+        assert_eq!(surface.samples[0].substances[0], 2.0);
+        assert_eq!(surface.samples[1].substances[0], 0.0);
+    }
+
+    #[test]
+    fn test_feature_values_follow_scene_changes() {
+        let mut scene = Scene::empty();
+        let mut surface = SurfaceBuilder::new()
+            .substances(&vec![0.0])
+            .add_surface_from_points(vec![Vector3::new(0.0, 0.0, 0.0)])
+            .build();
+        surface.samples[0].normal = Vector3::new(0.0, 1.0, 0.0);
+        let rule = FeatureRule::new(0, 1.0, GeometricFeature::SkyExposure { rays: 64 });
+
+        let roof = |center_x| -> Octree<Triangle> {
+            vec![horizontal_triangle(Vector3::new(center_x, 1.0, 0.0), 1.0, -1.0)].into_iter().collect()
+        };
+
+        rule.perform_with_octree(&mut scene, &mut surface, &roof(0.0), Path::new(""), 1.0);
+        let sheltered = surface.samples[0].substances[0];
+        assert!(sheltered < 1.0);
+
+        // Roof moved away, so the surfel gets the full sky
+        rule.perform_with_octree(&mut scene, &mut surface, &roof(100.0), Path::new(""), 1.0);
+        assert_eq!(surface.samples[0].substances[0], sheltered + 1.0);
+    }
+}
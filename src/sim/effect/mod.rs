@@ -1,20 +1,36 @@
 mod blend;
+mod condition;
+mod diffusion;
 mod effect;
+pub mod expr;
+mod feature;
+mod modulation;
+mod pbr;
 mod ramp;
 mod substance_color;
 mod substance_map_material;
 mod substance_map;
 mod substance_mapper;
+mod sun;
 mod surfel;
+mod transition;
 
-pub use self::blend::Blend;
+pub use self::blend::{Blend, LayeredBlend};
+pub use self::condition::Condition;
+pub use self::diffusion::Diffusion;
 pub use self::effect::Effect;
+pub use self::feature::FeatureRule;
+pub use self::modulation::Modulation;
+pub use self::pbr::{PbrBlend, PbrMaterial, PbrSource};
 pub use self::ramp::{Ramp, RampSegment};
 pub use self::substance_color::SubstanceColorEffect;
 pub use self::substance_mapper::SubstanceMapper;
 pub use self::substance_mapper::Sampling;
-pub use self::substance_map_material::SubstanceMapMaterialEffect;
-pub use self::surfel::SurfelRule;
+pub use self::substance_map::SubstanceMap;
+pub use self::substance_map_material::{SubstanceMapMaterialEffect, MultiMapMaterialEffect};
+pub use self::sun::{SunExposure, Light};
+pub use self::surfel::{SurfelRule, ExpressionRule, SurfelEffect};
+pub use self::transition::MaterialTransition;
 
 /*use std::fs::File;
 use std::path::{Path, PathBuf};
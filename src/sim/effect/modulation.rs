@@ -0,0 +1,86 @@
+use ::geom::noise;
+use ::geom::surf::{Surfel, sample_luminance};
+
+use ::image::{self, DynamicImage};
+
+use std::path::Path;
+use std::rc::Rc;
+
+/// Varies the rate of a rule from surfel to surfel, so that large surfaces do not age in
+/// perfect lockstep, e.g. to let rust nucleate in spots on a uniformly wet iron surface.
+///
+/// Each variant maps its own values from 0..1 onto a range of factors for the rate. Setting
+/// `min` higher than `max` inverts the mapping, e.g. to make Worley noise high at the centers
+/// of spots instead of low.
+///
+/// The factor of a surfel only depends on its position or texcoords, so it stays the same in
+/// every iteration and spots grow instead of flickering.
+#[derive(Clone)]
+pub enum Modulation {
+    /// The same rate for all surfels.
+    Uniform,
+    /// Random factor for each surfel, uncorrelated between neighbouring surfels.
+    Random { seed: u32, min: f32, max: f32 },
+    /// Smooth Perlin noise at the position of surfels, with features about `1 / frequency` in size.
+    Perlin { seed: u32, frequency: f32, min: f32, max: f32 },
+    /// Worley noise at the position of surfels, which is low near randomly placed points about
+    /// `1 / frequency` apart and high in between.
+    Worley { seed: u32, frequency: f32, min: f32, max: f32 },
+    /// Luminance of a texture at the texcoords of surfels.
+    Texture { texture: Rc<DynamicImage>, min: f32, max: f32 }
+}
+
+impl Modulation {
+    /// Loads the texture at the given path for modulation, mapping black to `min` and white to `max`.
+    ///
+    /// Panics if the texture cannot be loaded.
+    pub fn texture<P : AsRef<Path>>(texture_path: P, min: f32, max: f32) -> Modulation {
+        let texture_path = texture_path.as_ref();
+        let texture = image::open(texture_path)
+            .unwrap_or_else(|_| panic!("Texture for modulation at {:?} could not be loaded", texture_path));
+
+        Modulation::Texture { texture: Rc::new(texture), min, max }
+    }
+
+    /// Gets the factor for the rate of the given surfel.
+    pub fn factor(&self, surfel: &Surfel) -> f32 {
+        let (value, min, max) = match *self {
+            Modulation::Uniform => return 1.0,
+            Modulation::Random { seed, min, max } => (noise::white(seed, surfel.position), min, max),
+            Modulation::Perlin { seed, frequency, min, max } => (noise::perlin(seed, frequency * surfel.position), min, max),
+            Modulation::Worley { seed, frequency, min, max } => (noise::worley(seed, frequency * surfel.position), min, max),
+            Modulation::Texture { ref texture, min, max } => (sample_luminance(texture, surfel.texcoords), min, max)
+        };
+
+        min + value * (max - min)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::geom::surf::SurfaceBuilder;
+    use ::cgmath::Vector3;
+
+    #[test]
+    fn test_factors_within_range_and_varying() {
+        let surface = SurfaceBuilder::new()
+            .add_surface_from_points((0..100).map(|i| Vector3::new(i as f32 * 0.37, 0.0, i as f32 * 0.11)))
+            .build();
+
+        let modulations = vec![
+            Modulation::Random { seed: 1, min: 0.5, max: 2.0 },
+            Modulation::Perlin { seed: 1, frequency: 1.0, min: 0.5, max: 2.0 },
+            Modulation::Worley { seed: 1, frequency: 1.0, min: 2.0, max: 0.5 }
+        ];
+
+        for modulation in modulations {
+            let factors : Vec<f32> = surface.samples.iter().map(|s| modulation.factor(s)).collect();
+
+            assert!(factors.iter().all(|&f| f >= 0.5 && f <= 2.0));
+            assert!(factors.iter().any(|&f| (f - factors[0]).abs() > 0.1), "Factors should vary between surfels");
+        }
+
+        assert!(surface.samples.iter().all(|s| Modulation::Uniform.factor(s) == 1.0));
+    }
+}
@@ -0,0 +1,438 @@
+use super::substance_map::SubstanceMap;
+use super::substance_map_material::{SubstanceMapMaterialEffect, material_with_diffuse};
+
+use ::geom::scene::Entity;
+
+use ::sink::{ROUGHNESS_MAP_PARAM, METALNESS_MAP_PARAM, ROUGHNESS_PARAM, METALNESS_PARAM, ORM_MAP_PARAM, NORMAL_MAP_PARAM, normal_map};
+
+use ::tobj::Material;
+
+use ::cgmath::Vector3;
+use ::cgmath::prelude::*;
+
+use ::image::{self, DynamicImage, GenericImage, ImageBuffer, Luma, Pixel, Rgb, Rgba};
+
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// Where a channel of a PBR material gets its values from.
+#[derive(Clone)]
+pub enum PbrSource {
+    /// The same color everywhere, scalar channels use the red component
+    Constant(Rgba<u8>),
+    /// A texture, sampled at the texture coordinates of the texel being synthesized
+    Texture(Rc<DynamicImage>)
+}
+
+/// Textures or constants for albedo, roughness, metalness, normals and ambient occlusion,
+/// either the clean or the weathered look of a material.
+///
+/// Roughness, metalness and occlusion are read from the luminance of textures, normals
+/// from tangent space normal maps.
+#[derive(Clone)]
+pub struct PbrMaterial {
+    albedo: PbrSource,
+    roughness: PbrSource,
+    metalness: PbrSource,
+    normal: PbrSource,
+    occlusion: PbrSource
+}
+
+/// Values of all channels of a PBR material at a single texel.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct PbrTexel {
+    albedo: Rgba<u8>,
+    roughness: f32,
+    metalness: f32,
+    /// Tangent space normal of unit length
+    normal: Vector3<f32>,
+    occlusion: f32
+}
+
+/// Synthesizes a full set of PBR textures for the target materials, each channel blended from
+/// the clean towards the weathered material by the concentration of the mapped substance.
+///
+/// For rust on metal, the weathered material would have a low metalness and a high roughness,
+/// so that rusty areas stop reflecting like metal instead of only changing color.
+///
+/// If the clean look is read from the original material, its ambient texture `map_Ka` is
+/// assumed to hold ambient occlusion, as exported by most PBR tools, rather than an ambient
+/// color. Set the clean look explicitly for materials where this does not hold.
+pub struct PbrBlend {
+    target_material_names: Vec<String>,
+    texture_base_path: PathBuf,
+    /// If none, the clean look is taken from the original material
+    clean: Option<PbrMaterial>,
+    weathered: PbrMaterial
+}
+
+impl PbrSource {
+    /// Loads a texture to use as a source.
+    ///
+    /// # Panics
+    /// If the texture could not be loaded.
+    pub fn texture<P : AsRef<Path>>(path: P) -> PbrSource {
+        let path = path.as_ref();
+        let texture = image::open(path)
+            .unwrap_or_else(|_| panic!("PBR texture {:?} could not be loaded", path));
+
+        PbrSource::Texture(Rc::new(texture))
+    }
+
+    /// Makes a constant source for a scalar channel from a value in 0..1.
+    pub fn scalar(value: f32) -> PbrSource {
+        let value = to_byte(value);
+        PbrSource::Constant(Rgba([value, value, value, 255]))
+    }
+
+    /// Gets the color of the source at the given texture coordinates with y pointing down.
+    fn sample(&self, u: f32, v: f32) -> Rgba<u8> {
+        match *self {
+            PbrSource::Constant(color) => color,
+            PbrSource::Texture(ref texture) => {
+                let (width, height) = texture.dimensions();
+                let x = ((u * (width as f32)) as u32).min(width - 1);
+                let y = ((v * (height as f32)) as u32).min(height - 1);
+                texture.get_pixel(x, y)
+            }
+        }
+    }
+
+    fn sample_scalar(&self, u: f32, v: f32) -> f32 {
+        match *self {
+            PbrSource::Constant(color) => from_byte(color.data[0]),
+            PbrSource::Texture(_) => from_byte(self.sample(u, v).to_luma().data[0])
+        }
+    }
+}
+
+impl PbrMaterial {
+    /// Creates a white dielectric material with medium roughness, flat normals and
+    /// no ambient occlusion.
+    pub fn new() -> PbrMaterial {
+        PbrMaterial {
+            albedo: PbrSource::Constant(Rgba([255, 255, 255, 255])),
+            roughness: PbrSource::scalar(0.5),
+            metalness: PbrSource::scalar(0.0),
+            normal: PbrSource::Constant(Rgba([128, 128, 255, 255])),
+            occlusion: PbrSource::scalar(1.0)
+        }
+    }
+
+    /// Reads the PBR channels of a material loaded from an MTL file, with textures relative
+    /// to the given base path.
+    ///
+    /// Albedo is taken from `map_Kd` or `Kd`, roughness and metalness from `map_Pr`/`Pr` and
+    /// `map_Pm`/`Pm`, normals from `norm` and occlusion from `map_Ka`. Without roughness, it is
+    /// estimated from the shininess, without metalness, the material is dielectric.
+    ///
+    /// The loader stores `map_Ns` in `normal_texture`, so it is not used for normals here, and
+    /// neither are `map_Bump` or `bump`, which usually hold height maps.
+    pub fn from_material(material: &Material, texture_base_path: &Path) -> PbrMaterial {
+        let texture = |name: &str| PbrSource::texture(texture_base_path.join(name));
+        let param = |key: &str| material.unknown_param.get(key).filter(|v| !v.is_empty());
+        let scalar_param = |key: &str| param(key).and_then(|v| v.trim().parse::<f32>().ok());
+
+        let albedo = if material.diffuse_texture.is_empty() {
+            PbrSource::Constant(Rgba([
+                to_byte(material.diffuse[0]),
+                to_byte(material.diffuse[1]),
+                to_byte(material.diffuse[2]),
+                255
+            ]))
+        } else {
+            texture(&material.diffuse_texture)
+        };
+
+        let roughness = match (param(ROUGHNESS_MAP_PARAM), scalar_param(ROUGHNESS_PARAM)) {
+            (Some(map), _) => texture(map),
+            (None, Some(roughness)) => PbrSource::scalar(roughness),
+            // Approximation of Blinn-Phong exponents by GGX roughness
+            (None, None) => PbrSource::scalar((2.0 / (material.shininess.max(0.0) + 2.0)).sqrt())
+        };
+
+        let metalness = match (param(METALNESS_MAP_PARAM), scalar_param(METALNESS_PARAM)) {
+            (Some(map), _) => texture(map),
+            (None, Some(metalness)) => PbrSource::scalar(metalness),
+            (None, None) => PbrSource::scalar(0.0)
+        };
+
+        let mut pbr = PbrMaterial { albedo, roughness, metalness, ..PbrMaterial::new() };
+
+        if let Some(normal_map) = normal_map(material) {
+            pbr.normal = texture(normal_map);
+        }
+
+        if !material.ambient_texture.is_empty() {
+            pbr.occlusion = texture(&material.ambient_texture);
+        }
+
+        pbr
+    }
+
+    /// Sets a constant albedo with components in 0..1.
+    pub fn albedo(mut self, red: f32, green: f32, blue: f32) -> PbrMaterial {
+        self.albedo = PbrSource::Constant(Rgba([to_byte(red), to_byte(green), to_byte(blue), 255]));
+        self
+    }
+
+    pub fn albedo_texture<P : AsRef<Path>>(mut self, path: P) -> PbrMaterial {
+        self.albedo = PbrSource::texture(path);
+        self
+    }
+
+    pub fn roughness(mut self, roughness: f32) -> PbrMaterial {
+        self.roughness = PbrSource::scalar(roughness);
+        self
+    }
+
+    pub fn roughness_texture<P : AsRef<Path>>(mut self, path: P) -> PbrMaterial {
+        self.roughness = PbrSource::texture(path);
+        self
+    }
+
+    pub fn metalness(mut self, metalness: f32) -> PbrMaterial {
+        self.metalness = PbrSource::scalar(metalness);
+        self
+    }
+
+    pub fn metalness_texture<P : AsRef<Path>>(mut self, path: P) -> PbrMaterial {
+        self.metalness = PbrSource::texture(path);
+        self
+    }
+
+    pub fn normal_texture<P : AsRef<Path>>(mut self, path: P) -> PbrMaterial {
+        self.normal = PbrSource::texture(path);
+        self
+    }
+
+    pub fn occlusion_texture<P : AsRef<Path>>(mut self, path: P) -> PbrMaterial {
+        self.occlusion = PbrSource::texture(path);
+        self
+    }
+
+    fn texel(&self, u: f32, v: f32) -> PbrTexel {
+        PbrTexel {
+            albedo: self.albedo.sample(u, v),
+            roughness: self.roughness.sample_scalar(u, v),
+            metalness: self.metalness.sample_scalar(u, v),
+            normal: decode_normal(self.normal.sample(u, v)),
+            occlusion: self.occlusion.sample_scalar(u, v)
+        }
+    }
+}
+
+impl Default for PbrMaterial {
+    fn default() -> PbrMaterial {
+        PbrMaterial::new()
+    }
+}
+
+impl PbrTexel {
+    /// Interpolates all channels towards the other texel, renormalizing the normal.
+    fn lerp(&self, other: &PbrTexel, weight: f32) -> PbrTexel {
+        let lerp = |from: f32, to: f32| (1.0 - weight) * from + weight * to;
+
+        let normal = self.normal.lerp(other.normal, weight);
+        let normal = if normal.magnitude2() > 0.0 { normal.normalize() } else { other.normal };
+
+        PbrTexel {
+            albedo: self.albedo.map2(&other.albedo, |from, to| lerp(from as f32, to as f32).round() as u8),
+            roughness: lerp(self.roughness, other.roughness),
+            metalness: lerp(self.metalness, other.metalness),
+            normal,
+            occlusion: lerp(self.occlusion, other.occlusion)
+        }
+    }
+}
+
+impl PbrBlend {
+    /// Creates an effect blending the target materials towards the weathered material, with
+    /// the textures of the original materials relative to the given base path.
+    pub fn new<P : Into<PathBuf>>(target_material_names: Vec<String>, texture_base_path: P, weathered: PbrMaterial) -> PbrBlend {
+        PbrBlend {
+            target_material_names,
+            texture_base_path: texture_base_path.into(),
+            clean: None,
+            weathered
+        }
+    }
+
+    /// Sets the look without the substance, instead of reading it from the original material.
+    pub fn clean(mut self, clean: PbrMaterial) -> PbrBlend {
+        self.clean = Some(clean);
+        self
+    }
+
+    fn should_process_material(&self, original_material: &Material) -> bool {
+        self.target_material_names.iter().any(|n| n == &original_material.name)
+    }
+}
+
+impl SubstanceMapMaterialEffect for PbrBlend {
+    fn perform(&self, _entity: &Entity, original_material: &Material, concentrations: &SubstanceMap, output_file_prefix: &Path) -> Option<Material> {
+        if !self.should_process_material(original_material) {
+            return None;
+        }
+
+        let clean = self.clean.clone()
+            .unwrap_or_else(|| PbrMaterial::from_material(original_material, &self.texture_base_path));
+
+        let width = concentrations.width() as u32;
+        let height = concentrations.height() as u32;
+
+        let mut albedo = ImageBuffer::new(width, height);
+        let mut roughness = ImageBuffer::new(width, height);
+        let mut metalness = ImageBuffer::new(width, height);
+        let mut normal = ImageBuffer::new(width, height);
+        let mut occlusion = ImageBuffer::new(width, height);
+        let mut orm = ImageBuffer::new(width, height);
+
+        for y in 0..height {
+            for x in 0..width {
+                let u = (x as f32) / (width as f32);
+                let v = (y as f32) / (height as f32);
+
+                let concentration = concentrations.sample_for_image_coords(x as usize, y as usize, width as usize, height as usize);
+                // Texels without nearby surfels are NaN, keep them clean
+                let weight = if concentration.is_nan() { 0.0 } else { concentration.clamp(0.0, 1.0) };
+
+                let texel = clean.texel(u, v).lerp(&self.weathered.texel(u, v), weight);
+                let (r, m, o) = (to_byte(texel.roughness), to_byte(texel.metalness), to_byte(texel.occlusion));
+
+                albedo.put_pixel(x, y, texel.albedo);
+                roughness.put_pixel(x, y, Luma([r]));
+                metalness.put_pixel(x, y, Luma([m]));
+                normal.put_pixel(x, y, encode_normal(texel.normal));
+                occlusion.put_pixel(x, y, Luma([o]));
+                orm.put_pixel(x, y, Rgb([o, r, m]));
+            }
+        }
+
+        let material_name = format!("{}-pbr-map-substance-{}", output_file_prefix.file_name().unwrap().to_str().unwrap(), concentrations.substance_idx());
+        let write = |image: DynamicImage, channel: &str| write_texture(image, output_file_prefix, &format!("{}-{}", material_name, channel));
+
+        let mut material = material_with_diffuse(&material_name, &write(image::ImageRgba8(albedo), "albedo"));
+        material.unknown_param.insert(String::from(NORMAL_MAP_PARAM), write(image::ImageRgb8(normal), "normal"));
+        material.ambient_texture = write(image::ImageLuma8(occlusion), "occlusion");
+        material.unknown_param.insert(String::from(ROUGHNESS_MAP_PARAM), write(image::ImageLuma8(roughness), "roughness"));
+        material.unknown_param.insert(String::from(METALNESS_MAP_PARAM), write(image::ImageLuma8(metalness), "metalness"));
+        material.unknown_param.insert(String::from(ORM_MAP_PARAM), write(image::ImageRgb8(orm), "orm"));
+
+        Some(material)
+    }
+}
+
+fn to_byte(value: f32) -> u8 {
+    (value.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+fn from_byte(value: u8) -> f32 {
+    (value as f32) / 255.0
+}
+
+fn decode_normal(color: Rgba<u8>) -> Vector3<f32> {
+    let decode = |c: u8| from_byte(c) * 2.0 - 1.0;
+    let normal = Vector3::new(decode(color.data[0]), decode(color.data[1]), decode(color.data[2]));
+
+    if normal.magnitude2() > 0.0 { normal.normalize() } else { Vector3::new(0.0, 0.0, 1.0) }
+}
+
+fn encode_normal(normal: Vector3<f32>) -> Rgb<u8> {
+    let encode = |c: f32| to_byte(c * 0.5 + 0.5);
+    Rgb([encode(normal.x), encode(normal.y), encode(normal.z)])
+}
+
+/// Writes the image next to the output prefix with the given name and returns the file name.
+fn write_texture(image: DynamicImage, output_file_prefix: &Path, name: &str) -> String {
+    let mut path = PathBuf::from(output_file_prefix);
+    path.set_file_name(name);
+    path.set_extension("png");
+
+    let filename_relative = String::from(path.file_name().unwrap().to_str().unwrap());
+
+    info!("Writing PBR texture {}...", filename_relative);
+    let file = &mut File::create(path).unwrap();
+
+    image.save(file, image::PNG)
+        .expect("PBR texture could not be written");
+
+    filename_relative
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+    use std::fs;
+
+    fn steel() -> PbrMaterial {
+        PbrMaterial::new().albedo(0.8, 0.8, 0.8).roughness(0.2).metalness(1.0)
+    }
+
+    fn rust() -> PbrMaterial {
+        PbrMaterial::new().albedo(0.4, 0.2, 0.0).roughness(0.9).metalness(0.0)
+    }
+
+    #[test]
+    fn test_rust_drops_metalness_and_raises_roughness() {
+        let clean = steel().texel(0.5, 0.5);
+        let weathered = rust().texel(0.5, 0.5);
+
+        assert_eq!(clean.lerp(&weathered, 0.0), clean);
+        assert_eq!(clean.lerp(&weathered, 1.0), weathered);
+
+        let half = clean.lerp(&weathered, 0.5);
+        assert!((half.metalness - 0.5).abs() < 0.01);
+        assert!((half.roughness - 0.55).abs() < 0.01);
+        assert_eq!(half.albedo.data[2], 102);
+    }
+
+    #[test]
+    fn test_lerped_normals_stay_normalized() {
+        let flat = PbrMaterial::new().texel(0.0, 0.0);
+        let bumpy = PbrTexel { normal: Vector3::new(1.0, 0.0, 0.0), ..flat };
+
+        let normal = flat.lerp(&bumpy, 0.5).normal;
+        assert!((normal.magnitude() - 1.0).abs() < 0.0001);
+        assert!((normal.x - normal.z).abs() < 0.01);
+        assert_eq!(encode_normal(flat.normal), Rgb([128, 128, 255]));
+    }
+
+    #[test]
+    fn test_from_material_reads_pbr_params() {
+        let mut material = material_with_diffuse("iron", "");
+        material.diffuse = [0.5, 0.5, 0.5];
+        material.shininess = 0.0;
+        material.unknown_param = HashMap::new();
+        material.unknown_param.insert(String::from(METALNESS_PARAM), String::from("1.0"));
+
+        let texel = PbrMaterial::from_material(&material, Path::new(".")).texel(0.0, 0.0);
+        assert_eq!(texel.albedo, Rgba([128, 128, 128, 255]));
+        assert_eq!(texel.metalness, 1.0);
+        // Without Pr, shininess of 0 is fully rough
+        assert_eq!(texel.roughness, 1.0);
+    }
+
+    #[test]
+    fn test_from_material_reads_normals_only_from_norm() {
+        let dir = Path::new("test-output/pbr-normals");
+        fs::create_dir_all(dir).unwrap();
+        let tilted = DynamicImage::ImageRgb8(ImageBuffer::from_pixel(1, 1, Rgb([255, 128, 128])));
+        tilted.save(&mut File::create(dir.join("tilted.png")).unwrap(), image::PNG).unwrap();
+
+        let mut material = material_with_diffuse("stone", "");
+        material.unknown_param = HashMap::new();
+        // The loader puts map_Ns here, which is a shininess map rather than normals
+        material.normal_texture = String::from("missing-shininess.png");
+        material.unknown_param.insert(String::from("map_Bump"), String::from("-bm 1.0 missing-height.png"));
+
+        let flat = PbrMaterial::from_material(&material, dir).texel(0.0, 0.0);
+        assert!(flat.normal.z > 0.99);
+
+        material.unknown_param.insert(String::from(NORMAL_MAP_PARAM), String::from("tilted.png"));
+        let tilted = PbrMaterial::from_material(&material, dir).texel(0.0, 0.0);
+        assert!(tilted.normal.x > 0.99);
+    }
+}
@@ -1,5 +1,5 @@
 use super::substance_map::SubstanceMap;
-use super::substance_map_material::SubstanceMapMaterialEffect;
+use super::substance_map_material::{SubstanceMapMaterialEffect, material_with_diffuse};
 
 use ::geom::scene::Entity;
 
@@ -7,8 +7,6 @@ use ::tobj::Material;
 
 use ::image::{self, DynamicImage, GenericImage, ImageBuffer, Pixel, Rgba};
 
-use std::collections::HashMap;
-
 use std::fs::File;
 
 use std::path::{PathBuf, Path};
@@ -22,7 +20,6 @@ pub struct Ramp {
 
 enum TextureKind {
     Diffuse, // kd in MTL
-    #[allow(unused)]
     Specular // ks in MTL, metallicity
 }
 
@@ -41,6 +38,13 @@ impl Ramp {
         Ramp { target_material_names, texture_base_path, target_texture: TextureKind::Diffuse, segments }
     }
 
+    /// Applies the ramp to the specular texture instead of the diffuse texture, keeping the
+    /// other textures of the original material.
+    pub fn specular(mut self) -> Ramp {
+        self.target_texture = TextureKind::Specular;
+        self
+    }
+
     fn should_process_material(&self, original_material: &Material) -> bool {
         self.target_material_names.iter().any(|n| n == &original_material.name)
     }
@@ -101,7 +105,15 @@ impl SubstanceMapMaterialEffect for Ramp {
         image::ImageRgba8(synthesized_texture).save(ramp_map_file, image::PNG)
                 .expect("Substance map texture could not be written");
 
-        Some(material_with_diffuse(&material_name, &target_filename_relative))
+        match self.target_texture {
+            TextureKind::Diffuse => Some(material_with_diffuse(&material_name, &target_filename_relative)),
+            TextureKind::Specular => {
+                let mut material = original_material.clone();
+                material.name = material_name;
+                material.specular_texture = target_filename_relative;
+                Some(material)
+            }
+        }
     }
 }
 
@@ -162,22 +174,3 @@ fn original_texture(texture_base_path: &PathBuf, material: &Material, kind: &Tex
     image::open(&texture_path)
                 .expect("Texture of material could not be loaded for weathering")
 }
-
-fn material_with_diffuse(name: &str, diffuse: &str) -> Material {
-    Material {
-        name: String::from(name),
-        ambient: [1.0; 3],
-        diffuse: [1.0; 3],
-        specular: [0.0; 3],
-        shininess: 0.0,
-        dissolve: 1.0,
-        optical_density: 1.0,
-        ambient_texture: String::new(),
-        diffuse_texture: String::from(diffuse),
-        specular_texture: String::new(),
-        normal_texture: String::new(),
-        dissolve_texture: String::new(),
-        illumination_model: Some(0), // color on and ambient off
-        unknown_param: HashMap::new()
-    }
-}
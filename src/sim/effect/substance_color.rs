@@ -1,6 +1,6 @@
 
 use super::substance_map::SubstanceMap;
-use super::substance_map_material::SubstanceMapMaterialEffect;
+use super::substance_map_material::{SubstanceMapMaterialEffect, material_with_diffuse};
 
 use ::geom::scene::Entity;
 
@@ -11,7 +11,6 @@ use ::tobj::Material;
 
 use ::image;
 
-use std::collections::HashMap;
 use std::path::{PathBuf, Path};
 use std::fs::File;
 
@@ -85,22 +84,3 @@ impl SubstanceMapMaterialEffect for SubstanceColorEffect {
         Some(material_with_diffuse(&material_name, &target_filename_relative))
     }
 }
-
-fn material_with_diffuse(name: &str, diffuse: &str) -> Material {
-    Material {
-        name: String::from(name),
-        ambient: [1.0; 3],
-        diffuse: [1.0; 3],
-        specular: [0.0; 3],
-        shininess: 0.0,
-        dissolve: 1.0,
-        optical_density: 1.0,
-        ambient_texture: String::new(),
-        diffuse_texture: String::from(diffuse),
-        specular_texture: String::new(),
-        normal_texture: String::new(),
-        dissolve_texture: String::new(),
-        illumination_model: Some(0), // color on and ambient off
-        unknown_param: HashMap::new()
-    }
-}
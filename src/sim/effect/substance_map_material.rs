@@ -1,9 +1,11 @@
 use super::substance_map::SubstanceMap;
 
 use ::geom::scene::Entity;
+use ::substance::SubstanceRef;
 
 use ::tobj::Material;
 
+use std::collections::HashMap;
 use std::path::Path;
 
 /// Changes an entity using the information in the given associated substance map
@@ -13,3 +15,35 @@ pub trait SubstanceMapMaterialEffect {
     /// based on name and index of the entity, and index of the iteration
     fn perform(&self, entity: &Entity, original_material: &Material, concentrations: &SubstanceMap, prefix: &Path) -> Option<Material>;
 }
+
+/// Changes an entity using the substance maps of several substances at once, e.g. to mix
+/// a clean, a rusty and a mossy texture by the concentrations of rust and moss.
+pub trait MultiMapMaterialEffect {
+    /// Gets the substances that this effect needs maps of, by index or by name, in the order
+    /// the maps are passed to `perform`. Names are resolved when the simulation is built.
+    fn substances(&self) -> Vec<SubstanceRef>;
+
+    /// Like `SubstanceMapMaterialEffect::perform`, but with one map for each of the requested
+    /// substances, in the same order.
+    fn perform(&self, entity: &Entity, original_material: &Material, maps: &[&SubstanceMap], prefix: &Path) -> Option<Material>;
+}
+
+/// Creates a material that only shows the given diffuse texture, for effects to return.
+pub fn material_with_diffuse(name: &str, diffuse: &str) -> Material {
+    Material {
+        name: String::from(name),
+        ambient: [1.0; 3],
+        diffuse: [1.0; 3],
+        specular: [0.0; 3],
+        shininess: 0.0,
+        dissolve: 1.0,
+        optical_density: 1.0,
+        ambient_texture: String::new(),
+        diffuse_texture: String::from(diffuse),
+        specular_texture: String::new(),
+        normal_texture: String::new(),
+        dissolve_texture: String::new(),
+        illumination_model: Some(0), // color on and ambient off
+        unknown_param: HashMap::new()
+    }
+}
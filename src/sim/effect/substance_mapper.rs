@@ -1,11 +1,11 @@
 use super::Effect;
 use super::substance_map::SubstanceMap;
-use super::substance_map_material::SubstanceMapMaterialEffect;
+use super::substance_map_material::{SubstanceMapMaterialEffect, MultiMapMaterialEffect};
 use super::substance_color::SubstanceColorEffect;
 use super::ramp::{Ramp, RampSegment};
 
-use ::geom::scene::{Scene, Entity};
-use ::geom::surf::Surface;
+use ::geom::scene::{Scene, Entity, Triangle as SceneTriangle};
+use ::geom::surf::{Surface, Surfel, SurfacePoint, Proximity};
 use ::geom::tri::Triangle;
 use ::geom::vtx::{Position, Texcoords};
 use ::geom::raster::Rasterize;
@@ -13,6 +13,8 @@ use ::geom::raster::Rasterize;
 use ::cgmath::{Vector2, Vector3, Vector4};
 use ::cgmath::prelude::*;
 
+use ::tobj::Material;
+
 use ::nearest_kdtree::KdTree;
 use ::nearest_kdtree::distance::squared_euclidean;
 
@@ -26,12 +28,20 @@ use ::image;
 pub struct SubstanceMapper {
     substance_idx: usize,
     sampling: Sampling,
+    proximity: Proximity,
     texture_width: usize,
     texture_height: usize,
-    after_effects: Vec<Box<SubstanceMapMaterialEffect>>
+    /// Effects on the map of a single substance, along with the index of that substance
+    after_effects: Vec<(usize, Box<SubstanceMapMaterialEffect>)>,
+    /// Effects that need the maps of several substances at once, along with the indexes of
+    /// those substances
+    combined_effects: Vec<(Vec<usize>, Box<MultiMapMaterialEffect>)>,
+    /// Part of the file names of after effect output between entity and effect index
+    output_name: String
 }
 
 /// Sets the strategy for surfel lookup for a given texel
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum Sampling {
     /// Calculates a texture pixel by looking up all surfels within the
     /// given radius in UV space and taking the average
@@ -63,19 +73,35 @@ impl Effect for SubstanceMapper {
         let start = Instant::now();
 
         for entity_idx in 0..scene.entities.len() {
-            info!("Gathering {}x{} substance {} map for entity {}...", self.texture_width, self.texture_height, self.substance_idx, scene.entities[entity_idx].name);
-            let substance_tex = self.gather(scene, surf, entity_idx);
+            let maps = self.gather_required(scene, surf, entity_idx);
             info!("Ok, took {}s", start.elapsed().as_secs());
 
-            for (effect_idx, effect) in self.after_effects.iter().enumerate() {
-                let prefix = format!("{}-{}-{}-effect-{}", base_filename, entity_idx, scene.entities[entity_idx].name, effect_idx);
+            // Every effect starts from the original material, so only the last replacement sticks
+            let mut replaced_by = None;
+
+            // Effects on a single map first, then effects combining multiple maps, numbered in that order
+            for (effect_idx, &(substance_idx, ref effect)) in self.after_effects.iter().enumerate() {
+                let substance_tex = maps.iter().find(|m| m.substance_idx() == substance_idx);
+                let prefix = format!("{}-{}-{}-{}-{}", base_filename, entity_idx, scene.entities[entity_idx].name, self.output_name, effect_idx);
                 base_output_prefix.push(prefix);
 
-                if let Some(new_material) = effect.perform(&scene.entities[entity_idx], &scene.materials[scene.entities[entity_idx].original_material_idx], &substance_tex, &base_output_prefix) {
-                    let new_material_idx = scene.materials.len();
-                    scene.materials.push(new_material);
-                    scene.entities[entity_idx].material_idx = new_material_idx;
-                }
+                let new_material = effect.perform(&scene.entities[entity_idx], &scene.materials[scene.entities[entity_idx].original_material_idx], substance_tex.unwrap(), &base_output_prefix);
+                Self::assign_material(scene, entity_idx, effect_idx, new_material, &mut replaced_by);
+
+                base_output_prefix.pop();
+            }
+
+            for (combined_idx, (substance_idxs, effect)) in self.combined_effects.iter().enumerate() {
+                let effect_idx = self.after_effects.len() + combined_idx;
+                let prefix = format!("{}-{}-{}-{}-{}", base_filename, entity_idx, scene.entities[entity_idx].name, self.output_name, effect_idx);
+                base_output_prefix.push(prefix);
+
+                let requested_maps : Vec<&SubstanceMap> = substance_idxs.iter()
+                    .map(|&idx| maps.iter().find(|m| m.substance_idx() == idx).unwrap())
+                    .collect();
+
+                let new_material = effect.perform(&scene.entities[entity_idx], &scene.materials[scene.entities[entity_idx].original_material_idx], &requested_maps, &base_output_prefix);
+                Self::assign_material(scene, entity_idx, effect_idx, new_material, &mut replaced_by);
 
                 base_output_prefix.pop();
             }
@@ -88,23 +114,121 @@ impl SubstanceMapper {
         SubstanceMapper {
             substance_idx,
             sampling,
+            proximity: Proximity::Euclidean,
             //sampling: Sampling::UvRadius(3.0 / (texture_width as f32)), // within three pixels distance in UV space
             texture_width,
             texture_height,
-            after_effects
+            after_effects: after_effects.into_iter()
+                .map(|effect| (substance_idx, effect))
+                .collect(),
+            combined_effects: Vec::new(),
+            output_name: String::from("effect")
+        }
+    }
+
+    /// Adds an effect that combines the maps of several substances, e.g. to mix rust and moss into
+    /// the same texture. The maps are gathered with the size and sampling of this mapper, each one
+    /// only once per entity, and shared with the single-substance after effects.
+    ///
+    /// The substances the effect requests must already be resolved to the given indexes.
+    pub fn add_combined_effect(mut self, substance_idxs: Vec<usize>, effect: Box<MultiMapMaterialEffect>) -> SubstanceMapper {
+        self.combined_effects.push((substance_idxs, effect));
+        self
+    }
+
+    /// Sets the part of output file names of after effects between the entity and the index of
+    /// the effect, so that the outputs of multiple mappers do not overwrite each other.
+    /// Defaults to `effect`.
+    pub fn output_name<S : Into<String>>(mut self, output_name: S) -> SubstanceMapper {
+        self.output_name = output_name.into();
+        self
+    }
+
+    /// Checks if the other mapper gathers substance maps with the same size, sampling and proximity
+    /// as this one, so that both can share the maps of the substances they have in common.
+    pub fn gathers_maps_alike(&self, other: &SubstanceMapper) -> bool {
+        self.sampling == other.sampling &&
+            self.proximity == other.proximity &&
+            self.texture_width == other.texture_width &&
+            self.texture_height == other.texture_height
+    }
+
+    /// Performs the effects of the other mapper on the substance maps of this one, after the
+    /// own effects, so that the map of each substance needed by either mapper is gathered only once.
+    ///
+    /// Each effect starts over from the original material of an entity. If effects of both mappers
+    /// replace the material of the same entity, only the last one is kept and a warning is logged.
+    /// Use a single layered blend to combine e.g. rust and moss on the same material instead.
+    pub fn merge(&mut self, other: SubstanceMapper) {
+        assert!(self.gathers_maps_alike(&other), "Only mappers that gather maps alike can be merged");
+        self.after_effects.extend(other.after_effects);
+        self.combined_effects.extend(other.combined_effects);
+    }
+
+    /// Gets the indexes of the substances that effects need maps of, each one once. If there are
+    /// no effects at all, only the mapped substance is required.
+    pub fn required_substances(&self) -> Vec<usize> {
+        let mut substance_idxs = Vec::new();
+        if self.after_effects.is_empty() && self.combined_effects.is_empty() {
+            substance_idxs.push(self.substance_idx);
+        }
+
+        let needed = self.after_effects.iter()
+            .map(|&(idx, _)| idx)
+            .chain(self.combined_effects.iter().flat_map(|(idxs, _)| idxs.iter().cloned()));
+
+        for idx in needed {
+            if !substance_idxs.contains(&idx) {
+                substance_idxs.push(idx);
+            }
+        }
+
+        substance_idxs
+    }
+
+    /// Gathers the maps of all required substances.
+    fn gather_required(&self, scene: &Scene, surf: &Surface, entity_idx: usize) -> Vec<SubstanceMap> {
+        self.required_substances().into_iter()
+            .map(|substance_idx| {
+                info!("Gathering {}x{} substance {} map for entity {}...", self.texture_width, self.texture_height, substance_idx, scene.entities[entity_idx].name);
+                self.gather(scene, surf, entity_idx, substance_idx)
+            })
+            .collect()
+    }
+
+    fn assign_material(scene: &mut Scene, entity_idx: usize, effect_idx: usize, new_material: Option<Material>, replaced_by: &mut Option<usize>) {
+        if let Some(new_material) = new_material {
+            if let Some(previous_effect_idx) = replaced_by.replace(effect_idx) {
+                warn!(
+                    "Effect {} replaces the material of entity {} that effect {} already replaced, only the last one is kept. Use add_effect_layered_blend to combine them.",
+                    effect_idx, scene.entities[entity_idx].name, previous_effect_idx
+                );
+            }
+
+            let new_material_idx = scene.materials.len();
+            scene.materials.push(new_material);
+            scene.entities[entity_idx].material_idx = new_material_idx;
         }
     }
 
-    fn gather(&self, scene: &Scene, surf: &Surface, entity_idx: usize) -> SubstanceMap {
+    /// Sets which surfels around a texel contribute to its concentration when gathering in world
+    /// space, e.g. to keep substances on one side of a thin wall from showing on the other side.
+    /// Surfels on other entities are only excluded with a proximity other than `Proximity::Euclidean`.
+    pub fn proximity(mut self, proximity: Proximity) -> SubstanceMapper {
+        self.proximity = proximity;
+        self
+    }
+
+    fn gather(&self, scene: &Scene, surf: &Surface, entity_idx: usize, substance_idx: usize) -> SubstanceMap {
         SubstanceMap::new(
             self.texture_width,
             self.texture_height,
-            self.substance_idx,
+            substance_idx,
             entity_idx,
             match self.sampling {
-                Sampling::UvRadius(radius) => self.gather_uv_radius(surf, entity_idx, radius, self.texture_width, self.texture_height),
-                Sampling::NearestTriangle => self.gather_space_radius(&scene.entities[entity_idx], surf, self.texture_width, self.texture_height),
-                Sampling::Rasterization(padding) => self.gather_rasterize(&scene.entities[entity_idx], surf, self.texture_width, self.texture_height, padding)
+                Sampling::UvRadius(radius) => self.gather_uv_radius(surf, entity_idx, substance_idx, radius, self.texture_width, self.texture_height),
+                Sampling::NearestTriangle => self.gather_space_radius(&scene.entities[entity_idx], surf, substance_idx, self.texture_width, self.texture_height),
+                Sampling::Rasterization(padding) => self.gather_rasterize(&scene.entities[entity_idx], surf, substance_idx, self.texture_width, self.texture_height, padding)
             }
         )
     }
@@ -112,7 +236,7 @@ impl SubstanceMapper {
     /// Synthesizes the substance concentration texture by building a new set of triangles in UV space.
     /// When rendering these triangles, the UV coordinate of a pixel is used for interpolation of a world position,
     /// for this world position the nearest surfels are looked up and drawn using a filtering similar to photon mapping.
-    fn gather_rasterize(&self, ent: &Entity, surf: &Surface, tex_width: usize, tex_height: usize, padding: f32) -> Vec<f32> {
+    fn gather_rasterize(&self, ent: &Entity, surf: &Surface, substance_idx: usize, tex_width: usize, tex_height: usize, padding: f32) -> Vec<f32> {
         info!("Rendering concentrations...");
 
         let mut concentrations = vec![NAN; tex_width * tex_height];
@@ -121,10 +245,10 @@ impl SubstanceMapper {
             // Ignore zero-area triangles
             .filter(|t| t.area() > EPSILON)
             // Transform triangles into uv space scaled for target texture dimensions
-            .map(|t| Self::to_padded_uv_space(&t, tex_width, tex_height, padding))
-            .for_each(|t| t.rasterize(tex_width, tex_height, |x, y| {
+            .map(|t| (Self::to_padded_uv_space(&t, tex_width, tex_height, padding), Self::vertex_normal_average(&t)))
+            .for_each(|(t, normal)| t.rasterize(tex_width, tex_height, |x, y| {
                let world_position = t.interpolate_at(Vector3::new(x as f32, y as f32, 0.0), |v| v.0);
-               let surfels = surf.nearest_n(world_position, 4);
+               let surfels = self.nearest_surfels(surf, &SurfacePoint { position: world_position, normal, entity_idx: ent.entity_idx });
 
                let sample_radius = surfels.iter()
                         .map(|&(dist, _)| dist)
@@ -135,7 +259,7 @@ impl SubstanceMapper {
                 let k = 2.7;
 
                 let concentration = surfels.iter()
-                    .map(|&(dist, surfel)| (1.0 - (dist / (k * sample_radius))) * surfel.substances[self.substance_idx])
+                    .map(|&(dist, surfel)| (1.0 - (dist / (k * sample_radius))) * surfel.substances[substance_idx])
                     .sum::<f32>() / (/*PI * sample_radius * sample_radius*/ surfels.len() as f32);
 
                 concentrations[y * tex_width + x] = concentration;
@@ -248,10 +372,10 @@ impl SubstanceMapper {
         )
     }
 
-    fn gather_uv_radius(&self, surf: &Surface, entity_idx: usize, radius: f32, tex_width: usize, tex_height: usize) -> Vec<f32> {
+    fn gather_uv_radius(&self, surf: &Surface, entity_idx: usize, substance_idx: usize, radius: f32, tex_width: usize, tex_height: usize) -> Vec<f32> {
         let mut concentrations = Vec::with_capacity(tex_width * tex_height);
 
-        let concentration_tree = self.build_substance_uv_tree(surf, entity_idx);
+        let concentration_tree = self.build_substance_uv_tree(surf, entity_idx, substance_idx);
 
         // width and height of a pixel in UV space
         let pixel_width = 1.0 / (tex_width as f32);
@@ -273,13 +397,13 @@ impl SubstanceMapper {
     }
 
     /// Builds a kdtree of substance values indexed by their position in UV space
-    fn build_substance_uv_tree(&self, surf: &Surface, entity_idx: usize) -> KdTree<f32, [f64; 2]> {
+    fn build_substance_uv_tree(&self, surf: &Surface, entity_idx: usize, substance_idx: usize) -> KdTree<f32, [f64; 2]> {
         let mut tree = KdTree::new(2); //KdTree::new_with_capacity(2, surf.samples.len());
 
         for sample in &surf.samples {
             if sample.entity_idx == entity_idx {
                 let pos = [sample.texcoords.x as f64, sample.texcoords.y as f64];
-                let concentration = sample.substances[self.substance_idx];
+                let concentration = sample.substances[substance_idx];
 
                 tree.add(
                     pos,
@@ -293,7 +417,7 @@ impl SubstanceMapper {
 
     /// Builds a kdtree of substance values indexed by their position in UV space
     #[allow(unused_variables)]
-    fn gather_space_radius(&self, ent: &Entity, surf: &Surface, tex_width: usize, tex_height: usize) -> Vec<f32> {
+    fn gather_space_radius(&self, ent: &Entity, surf: &Surface, substance_idx: usize, tex_width: usize, tex_height: usize) -> Vec<f32> {
         info!("Gathering concentrations using old method");
 
         let mut concentrations = Vec::with_capacity(tex_width * tex_height);
@@ -331,10 +455,10 @@ impl SubstanceMapper {
                     &squared_euclidean
                 ).unwrap()
                     .iter()
-                    // Select the triangle reference from the tuple
+                    // Select the triangle and normal reference from the tuple
                     .map(|t| t.1)
                     // Calculate barys in UV space
-                    .map(|t| (t, t.barycentric_at(Vector3::new(u as f32, v as f32, 0.0))) )
+                    .map(|&(ref t, normal)| ((t, normal), t.barycentric_at(Vector3::new(u as f32, v as f32, 0.0))) )
                     // select the first triangle where the barycentric coordinates are inside
                     /*.find(|&(_, bary)|
                         if bary[1] + bary[2] > 1.0 {
@@ -360,10 +484,10 @@ impl SubstanceMapper {
                         }
                         (error * 1_000_000_000.0) as u64
                     })
-                    .map(|(tri, bary)| tri.interpolate_bary(bary, |v| v.0));
+                    .map(|((tri, normal), bary)| (tri.interpolate_bary(bary, |v| v.0), normal));
 
-                let mut concentration = if let Some(position) = interpolated_position {
-                    let surfels = surf.nearest_n(position, 4);
+                let mut concentration = if let Some((position, normal)) = interpolated_position {
+                    let surfels = self.nearest_surfels(surf, &SurfacePoint { position, normal, entity_idx: ent.entity_idx });
 
                     let sample_radius = surfels.iter()
                         .map(|&(dist, _)| dist)
@@ -375,7 +499,7 @@ impl SubstanceMapper {
                     let k = 2.7;
 
                     let concentration = surfels.iter()
-                        .map(|&(dist, surfel)| (1.0 - (dist / (k * sample_radius))) * surfel.substances[self.substance_idx])
+                        .map(|&(dist, surfel)| (1.0 - (dist / (k * sample_radius))) * surfel.substances[substance_idx])
                         .sum::<f32>() / (/*PI * sample_radius * sample_radius*/ surfels.len() as f32);
 
                     Some(concentration)
@@ -387,11 +511,11 @@ impl SubstanceMapper {
                         None
                     } else {
                         /*let val = surfels.iter()
-                            .map(|s| s.substances[self.substance_idx])
+                            .map(|s| s.substances[substance_idx])
                             .sum::<f32>() / (surfels.len() as f32);*/
 
                         let val = surfels.iter()
-                            .map(|s| s.substances[self.substance_idx])
+                            .map(|s| s.substances[substance_idx])
                             .sum::<f32>() / (surfels.len() as f32);
 
                         Some(val)
@@ -411,7 +535,9 @@ impl SubstanceMapper {
         concentrations
     }
 
-    fn build_triangle_uv_tree(&self, entity: &Entity) -> KdTree<Triangle<(Vector3<f32>, Vector2<f32>)>, [f64; 2]> {
+    /// Builds a kdtree of triangles in UV space along with the average of their vertex normals,
+    /// indexed by their centers in UV space
+    fn build_triangle_uv_tree(&self, entity: &Entity) -> KdTree<(Triangle<(Vector3<f32>, Vector2<f32>)>, Vector3<f32>), [f64; 2]> {
         let mut tree = KdTree::new(2); //KdTree::new_with_capacity(2, surf.samples.len());
 
         for tri in entity.triangles() {
@@ -419,19 +545,40 @@ impl SubstanceMapper {
                 let world_center = tri.center();
                 let tex_center = tri.interpolate_at(world_center, |v| v.texcoords);
                 let tex_center = [ tex_center.x as f64, tex_center.y as f64 ];
+
+                let normal = Self::vertex_normal_average(&tri);
                 let tri = Triangle::new(
                     (tri.vertices[0].position, tri.vertices[0].texcoords),
                     (tri.vertices[1].position, tri.vertices[1].texcoords),
                     (tri.vertices[2].position, tri.vertices[2].texcoords)
                 );
 
-                tree.add(tex_center, tri).unwrap();
+                tree.add(tex_center, (tri, normal)).unwrap();
             }
         }
 
         tree
     }
 
+    /// Finds the four surfels nearest to the given point that are near to it by the configured
+    /// proximity, or the four nearest surfels overall if none of them are, along with their
+    /// squared distances.
+    fn nearest_surfels<'a>(&self, surf: &'a Surface, point: &SurfacePoint) -> Vec<(f32, &'a Surfel)> {
+        let near = surf.nearest_n_near_idxs(point, 4, self.proximity);
+
+        if near.is_empty() {
+            surf.nearest_n(point.position, 4)
+        } else {
+            near.into_iter()
+                .map(|(dist, idx)| (dist as f32, &surf.samples[idx]))
+                .collect()
+        }
+    }
+
+    fn vertex_normal_average(triangle: &SceneTriangle) -> Vector3<f32> {
+        (triangle.vertices[0].normal + triangle.vertices[1].normal + triangle.vertices[2].normal).normalize()
+    }
+
     /// Looks up the surfels within the given radius at the given point in UV space
     /// and calculates a combined substance concentration.
     fn gather_concentration_at(&self, concentrations: &KdTree<f32, [f64; 2]>, u: f32, v: f32, radius: f32) -> f32 {
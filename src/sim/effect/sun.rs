@@ -0,0 +1,262 @@
+use super::Effect;
+
+use ::geom::octree::Octree;
+use ::geom::scene::{Scene, Triangle, geometry_fingerprint};
+use ::geom::surf::{Surface, Surfel, GeometricFeature};
+
+use ::cgmath::Vector3;
+use ::cgmath::prelude::*;
+
+use std::cell::{Cell, RefCell};
+use std::f32::consts::PI;
+use std::path::Path;
+
+/// Offset along the normal for shadow rays so they do not hit the triangle they start on.
+const SHADOW_RAY_OFFSET : f32 = 0.00001;
+
+/// Where light for `SunExposure` comes from. Y points up, X east and Z south.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Light {
+    /// Parallel light from the given direction towards the sun.
+    Sun { direction: Vector3<f32> },
+    /// Parallel light from a sun that moves to the next of the given directions in each
+    /// iteration, starting over after the last one.
+    SunPath { directions: Vec<Vector3<f32>> },
+    /// Diffuse light from an evenly bright sky, estimated with the given amount of rays per
+    /// surfel. Open ground facing up gets the full intensity.
+    Sky { rays: u32 }
+}
+
+/// Accumulates the light that reaches each surfel into a substance, so that rules for fading,
+/// bleaching or the suppression of moss can read it.
+///
+/// Surfels get the intensity of the light times the cosine of the angle between their normal
+/// and the light, unless something in the scene casts a shadow on them, multiplied with the time
+/// step of the simulation. Shadow rays are cast against the octree that the simulation builds
+/// for tracing tons in each iteration, so shadows follow changes of the scene.
+///
+/// Light from the sky does not change between iterations, so the exposure to the sky is only
+/// estimated again when surfels are added, removed or moved, or when triangles of the scene are.
+pub struct SunExposure {
+    substance_idx: usize,
+    light: Light,
+    intensity: f32,
+    iteration: Cell<usize>,
+    /// Revision of the surface, fingerprint of the scene geometry and the sky exposure of each
+    /// surfel for both
+    sky_exposures: RefCell<Option<(u64, u64, Vec<f32>)>>
+}
+
+impl Light {
+    /// Light from the given direction towards the sun.
+    pub fn sun(direction: Vector3<f32>) -> Light {
+        Light::Sun { direction: direction.normalize() }
+    }
+
+    /// Approximates the daily path of the sun with the given amount of directions, rising in
+    /// the east, reaching the given elevation in radians in the south and setting in the west.
+    pub fn sun_arc(steps: usize, max_elevation: f32) -> Light {
+        assert!(steps > 0, "Sun path needs at least one direction");
+
+        let directions = (0..steps)
+            .map(|step| {
+                // Angle from east over south to west, leaving out sunrise and sunset
+                let azimuth = PI * (step as f32 + 0.5) / steps as f32;
+                let elevation = max_elevation * azimuth.sin();
+
+                Vector3::new(
+                    azimuth.cos() * elevation.cos(),
+                    elevation.sin(),
+                    azimuth.sin() * elevation.cos()
+                ).normalize()
+            })
+            .collect();
+
+        Light::SunPath { directions }
+    }
+}
+
+impl SunExposure {
+    /// Creates an effect that adds the light per unit of time reaching each surfel to the substance
+    /// with the given index, where the given intensity reaches surfels facing the light directly.
+    pub fn new(substance_idx: usize, light: Light, intensity: f32) -> SunExposure {
+        if let Light::SunPath { ref directions } = light {
+            assert!(!directions.is_empty(), "Sun path needs at least one direction");
+        }
+
+        SunExposure { substance_idx, light, intensity, iteration: Cell::new(0), sky_exposures: RefCell::new(None) }
+    }
+
+    /// Gets the direction towards the sun in the current iteration, if the light comes from the sun.
+    fn sun_direction(&self) -> Option<Vector3<f32>> {
+        match self.light {
+            Light::Sun { direction } => Some(direction),
+            Light::SunPath { ref directions } => Some(directions[self.iteration.get() % directions.len()]),
+            Light::Sky { .. } => None
+        }
+    }
+
+    fn expose(&self, surf: &mut Surface, octree: &Octree<Triangle>, time_step: f32) {
+        let exposures : Vec<f32> = match self.sun_direction() {
+            Some(direction) => surf.samples.iter()
+                .map(|s| sunlight(s, octree, direction))
+                .collect(),
+            None => match self.light {
+                Light::Sky { rays } => self.sky_exposures(surf, octree, rays),
+                _ => unreachable!()
+            }
+        };
+
+        let amount = self.intensity * time_step;
+        for (surfel, exposure) in surf.samples.iter_mut().zip(exposures) {
+            surfel.substances[self.substance_idx] += amount * exposure;
+        }
+
+        self.iteration.set(self.iteration.get() + 1);
+    }
+
+    /// Gets the fraction of the sky visible from each surfel, estimating it only if the surface
+    /// or the scene geometry changed since the last estimate.
+    fn sky_exposures(&self, surf: &Surface, octree: &Octree<Triangle>, rays: u32) -> Vec<f32> {
+        let mut sky_exposures = self.sky_exposures.borrow_mut();
+        let fingerprint = geometry_fingerprint(octree);
+
+        let outdated = match *sky_exposures {
+            Some((revision, geometry, ref exposures)) => revision != surf.revision() || geometry != fingerprint || exposures.len() != surf.samples.len(),
+            None => true
+        };
+
+        if outdated {
+            *sky_exposures = Some((surf.revision(), fingerprint, GeometricFeature::SkyExposure { rays }.compute(surf, octree)));
+        }
+
+        let (_, _, exposures) = sky_exposures.as_ref().unwrap();
+        exposures.clone()
+    }
+}
+
+/// Cosine of the angle between the normal and the sun, or zero if the surfel faces away from
+/// the sun or lies in shadow.
+fn sunlight(surfel: &Surfel, octree: &Octree<Triangle>, direction: Vector3<f32>) -> f32 {
+    let cosine = surfel.normal.dot(direction);
+    if cosine <= 0.0 {
+        return 0.0;
+    }
+
+    let origin = surfel.position + SHADOW_RAY_OFFSET * surfel.normal;
+    match octree.ray_intersection_target_and_parameter(origin, direction) {
+        Some(_) => 0.0,
+        None => cosine
+    }
+}
+
+impl Effect for SunExposure {
+    fn perform(&self, scene: &mut Scene, surf: &mut Surface, output_prefix: &Path) {
+        self.perform_timed(scene, surf, output_prefix, 1.0)
+    }
+
+    fn perform_timed(&self, scene: &mut Scene, surf: &mut Surface, output_prefix: &Path, time_step: f32) {
+        let octree : Octree<Triangle> = scene.triangles().collect();
+        self.perform_with_octree(scene, surf, &octree, output_prefix, time_step)
+    }
+
+    fn perform_with_octree(&self, _: &mut Scene, surf: &mut Surface, octree: &Octree<Triangle>, _: &Path, time_step: f32) {
+        info!("Tracing shadow rays for sun exposure of substance {}...", self.substance_idx);
+        self.expose(surf, octree, time_step);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::geom::surf::SurfaceBuilder;
+    use ::geom::scene::horizontal_triangle;
+
+    /// Octree with a roof over the origin at height 1
+    fn roof() -> Octree<Triangle> {
+        roof_at(0.0)
+    }
+
+    /// Octree with a roof at height 1 centered over the given x coordinate
+    fn roof_at(center_x: f32) -> Octree<Triangle> {
+        vec![horizontal_triangle(Vector3::new(center_x, 1.0, 0.0), 1.0, -1.0)].into_iter().collect()
+    }
+
+    /// Surfels facing up under the roof and next to it
+    fn ground() -> Surface {
+        let mut surface = SurfaceBuilder::new()
+            .substances(&vec![0.0])
+            .add_surface_from_points(vec![Vector3::new(0.0, 0.0, 0.0), Vector3::new(5.0, 0.0, 0.0)])
+            .build();
+
+        for surfel in surface.samples.iter_mut() {
+            surfel.normal = Vector3::new(0.0, 1.0, 0.0);
+        }
+
+        surface
+    }
+
+    #[test]
+    fn test_shadowed_surfels_get_no_sun() {
+        let mut surface = ground();
+        let exposure = SunExposure::new(0, Light::sun(Vector3::new(0.0, 2.0, 1.0)), 2.0);
+
+        exposure.expose(&mut surface, &roof(), 0.5);
+
+        assert_eq!(surface.samples[0].substances[0], 0.0);
+        assert!((surface.samples[1].substances[0] - 2.0 / 5.0f32.sqrt()).abs() < 0.0001);
+    }
+
+    #[test]
+    fn test_sun_path_moves() {
+        let mut surface = ground();
+        let path = Light::SunPath { directions: vec![Vector3::new(0.0, 1.0, 0.0), Vector3::new(0.0, -1.0, 0.0)] };
+        let exposure = SunExposure::new(0, path, 1.0);
+
+        exposure.expose(&mut surface, &roof(), 1.0);
+        exposure.expose(&mut surface, &roof(), 1.0);
+        exposure.expose(&mut surface, &roof(), 1.0);
+
+        // Sun from below in the second iteration does not reach surfels facing up
+        assert_eq!(surface.samples[1].substances[0], 2.0);
+    }
+
+    #[test]
+    fn test_sky_exposure_is_estimated_again_when_surface_or_scene_changes() {
+        let mut surface = ground();
+        let exposure = SunExposure::new(0, Light::Sky { rays: 64 }, 1.0);
+
+        exposure.expose(&mut surface, &roof(), 1.0);
+        let sheltered = surface.samples[0].substances[0];
+        assert!(sheltered < 1.0);
+        assert_eq!(surface.samples[1].substances[0], 1.0);
+
+        // Same surface and geometry in a newly built octree
+        exposure.expose(&mut surface, &roof(), 1.0);
+        assert_eq!(surface.samples[0].substances[0], 2.0 * sheltered);
+
+        // Surfel under the roof is removed and inserted again at the end
+        let surfel = surface.remove(0);
+        surface.insert(surfel);
+        exposure.expose(&mut surface, &roof(), 1.0);
+        assert_eq!(surface.samples[0].substances[0], 3.0);
+        let sheltered_total = surface.samples[1].substances[0];
+        assert!(sheltered_total < 2.0 * sheltered + 1.0);
+
+        // Roof moved away, so the surfel that was under it gets the full sky
+        exposure.expose(&mut surface, &roof_at(100.0), 1.0);
+        assert_eq!(surface.samples[1].substances[0], sheltered_total + 1.0);
+    }
+
+    #[test]
+    fn test_sun_arc_stays_above_horizon() {
+        match Light::sun_arc(5, PI / 3.0) {
+            Light::SunPath { directions } => {
+                assert_eq!(directions.len(), 5);
+                assert!(directions.iter().all(|d| d.y > 0.0 && (d.magnitude() - 1.0).abs() < 0.0001));
+                assert!(directions[2].z > 0.99 * (PI / 3.0).cos());
+            },
+            _ => panic!("Expected sun path")
+        }
+    }
+}
@@ -1,4 +1,7 @@
 use super::Effect;
+use super::condition::Condition;
+use super::expr::Assignment;
+use super::modulation::Modulation;
 
 use ::geom::scene::Scene;
 use ::geom::surf::{Surface, Surfel};
@@ -20,10 +23,17 @@ use std::iter::IntoIterator;
 /// as `SurfelRule::new(0, 1, 0.2)`.
 ///
 /// Similarly, the evaporation of water over time can be described as: `SurfelRule::new(0, 0, -0.5)`.
+/// Rules that read and write the same substance grow or decay exponentially over time, so that
+/// half of the water evaporates per unit of time, no matter how long the time steps are.
+///
+/// The rate can vary between surfels with a `Modulation`, and be limited to or scaled by the
+/// orientation, position or entity of surfels with conditions.
 pub struct SurfelRule {
     write_substance_idx: usize,
     read_substance_idx: usize,
     rate: f32,
+    modulation: Modulation,
+    conditions: Vec<Condition>,
     applicable_materials: Vec<String>
 }
 
@@ -37,6 +47,7 @@ impl SurfelRule {
     ///
     /// ```
     /// use std::iter::empty;
+    /// use aitios::SurfelRule;
     ///
     /// // Material 0 should drop by 10% for all materials
     /// let drop_substance_zero = SurfelRule::new(0, 0, -0.1, empty::<String>());
@@ -48,58 +59,239 @@ impl SurfelRule {
         where M : IntoIterator<Item = S>, S : Into<String>
     {
         let applicable_materials = applicable_materials.into_iter().map(|m| m.into()).collect();
-        SurfelRule { write_substance_idx, read_substance_idx, rate, applicable_materials }
+        SurfelRule {
+            write_substance_idx,
+            read_substance_idx,
+            rate,
+            modulation: Modulation::Uniform,
+            conditions: Vec::new(),
+            applicable_materials
+        }
     }
 
-    fn age_surfel(&self, surfel: &mut Surfel) {
-        let &SurfelRule { write_substance_idx: write, read_substance_idx: read, rate, .. } = self;
+    /// Varies the rate between surfels, e.g. with noise. Defaults to `Modulation::Uniform`.
+    pub fn modulation(mut self, modulation: Modulation) -> SurfelRule {
+        self.modulation = modulation;
+        self
+    }
 
-        surfel.substances[write] = (surfel.substances[write] + rate * surfel.substances[read]).max(0.0);
+    /// Adds a condition that surfels must satisfy, in addition to the applicable materials.
+    /// The rate is scaled with the weight of the surfel for the condition.
+    pub fn condition(mut self, condition: Condition) -> SurfelRule {
+        self.conditions.push(condition);
+        self
     }
 
-    fn applicable_material_idxs(&self, scene: &Scene) -> Vec<usize> {
-        // Empty vector indicates for all materials
-        if self.applicable_materials.is_empty() {
-            return Vec::new()
-        }
+    /// Changes the written substance over the given time step, with the rate scaled by the weight.
+    fn age_surfel(&self, surfel: &mut Surfel, weight: f32, time_step: f32) {
+        let &SurfelRule { write_substance_idx: write, read_substance_idx: read, rate, .. } = self;
+        let rate = weight * rate * self.modulation.factor(surfel);
+
+        surfel.substances[write] = if write == read {
+            // Exact growth or decay, so that long time steps do not overshoot below zero
+            surfel.substances[write] * (1.0 + rate).max(0.0).powf(time_step)
+        } else {
+            (surfel.substances[write] + time_step * rate * surfel.substances[read]).max(0.0)
+        };
+    }
+}
 
-        // Non-empty vector can be translated into material indexes
-        let applicable_idxs : Vec<usize> = scene.materials.iter()
-            .enumerate()
-            .filter(|&(_, scene_mat)| self.applicable_materials.iter()
-                                          .any(|applicable_mat| applicable_mat == &scene_mat.name))
-            .map(|(idx, _)| idx)
-            .collect();
+impl Effect for SurfelRule {
+    fn perform(&self, scene: &mut Scene, surf: &mut Surface, output_prefix: &Path) {
+        self.perform_timed(scene, surf, output_prefix, 1.0)
+    }
 
-        assert!(
-            applicable_idxs.len() > 0,
-            "When non-empty target material names provided, at least one should actually exist. Target material names: {:?}, Scene materials: {:?}",
-            self.applicable_materials,
-            scene.materials
-        );
+    /// Applies the rule with the rate per unit of time multiplied by the time step.
+    fn perform_timed(&self, scene: &mut Scene, surf: &mut Surface, _: &Path, time_step: f32) {
+        let applicable_material_idxs = applicable_material_idxs(&self.applicable_materials, scene);
 
-        applicable_idxs
+        surf.samples.iter_mut()
+            .filter(|s| {
+                let orig_mat_idx = scene.entities[s.entity_idx].original_material_idx;
+                is_applicable(orig_mat_idx, &applicable_material_idxs)
+            })
+            .for_each(|s| {
+                let weight = Condition::combined_weight(&self.conditions, s, scene);
+                if weight > 0.0 {
+                    self.age_surfel(s, weight, time_step);
+                }
+            })
     }
+}
 
-    fn is_applicable(&self, mat_idx: usize, applicable_material_idxs: &Vec<usize>) -> bool {
-        if applicable_material_idxs.is_empty() {
-            true
-        } else {
-            applicable_material_idxs.iter()
-                .any(|&idx| idx == mat_idx)
-        }
+/// Like `SurfelRule`, but changes substances with a program in the expression language
+/// described in `expr`, which allows for several inputs, products, thresholds and limits, e.g.
+///
+/// ```text
+/// rust += 0.2 * water * oxygen;
+/// moss += 0.1 * water * (1 - sunlight) if water > 0.3;
+/// rust = min(rust, 1)
+/// ```
+///
+/// The assignments are applied to each surfel in order, so later assignments see the results
+/// of earlier ones.
+pub struct ExpressionRule {
+    assignments: Vec<Assignment>,
+    applicable_materials: Vec<String>
+}
+
+impl ExpressionRule {
+    /// Creates a rule from assignments that refer to substances by index, e.g. parsed with
+    /// `parse_program` and resolved against the declared substances.
+    ///
+    /// Applicable materials work like in `SurfelRule::new`.
+    pub fn new<M, S>(assignments: Vec<Assignment>, applicable_materials: M) -> ExpressionRule
+        where M : IntoIterator<Item = S>, S : Into<String>
+    {
+        let applicable_materials = applicable_materials.into_iter().map(|m| m.into()).collect();
+        ExpressionRule { assignments, applicable_materials }
     }
 }
 
-impl Effect for SurfelRule {
-    fn perform(&self, scene: &mut Scene, surf: &mut Surface, _: &Path) {
-        let applicable_material_idxs = self.applicable_material_idxs(scene);
+impl Effect for ExpressionRule {
+    fn perform(&self, scene: &mut Scene, surf: &mut Surface, output_prefix: &Path) {
+        self.perform_timed(scene, surf, output_prefix, 1.0)
+    }
+
+    /// Applies the assignments, scaling increments and decrements with the time step.
+    fn perform_timed(&self, scene: &mut Scene, surf: &mut Surface, _: &Path, time_step: f32) {
+        let applicable_material_idxs = applicable_material_idxs(&self.applicable_materials, scene);
 
         surf.samples.iter_mut()
             .filter(|s| {
                 let orig_mat_idx = scene.entities[s.entity_idx].original_material_idx;
-                self.is_applicable(orig_mat_idx, &applicable_material_idxs)
+                is_applicable(orig_mat_idx, &applicable_material_idxs)
+            })
+            .for_each(|s| for assignment in &self.assignments {
+                assignment.apply_timed(&mut s.substances, time_step);
             })
-            .for_each(|s| self.age_surfel(s))
+    }
+}
+
+/// Applies a custom function to every surfel after each iteration, for ageing logic that
+/// cannot be expressed with rules.
+pub struct SurfelEffect<F> {
+    effect: F
+}
+
+impl<F> SurfelEffect<F>
+    where F : Fn(&mut Surfel, &Scene)
+{
+    /// Creates an effect that calls the given function with each surfel and the scene.
+    pub fn new(effect: F) -> SurfelEffect<F> {
+        SurfelEffect { effect }
+    }
+}
+
+impl<F> Effect for SurfelEffect<F>
+    where F : Fn(&mut Surfel, &Scene)
+{
+    fn perform(&self, scene: &mut Scene, surf: &mut Surface, _: &Path) {
+        for surfel in surf.samples.iter_mut() {
+            (self.effect)(surfel, scene);
+        }
+    }
+}
+
+pub fn applicable_material_idxs(applicable_materials: &[String], scene: &Scene) -> Vec<usize> {
+    // Empty vector indicates for all materials
+    if applicable_materials.is_empty() {
+        return Vec::new()
+    }
+
+    // Non-empty vector can be translated into material indexes
+    let applicable_idxs : Vec<usize> = scene.materials.iter()
+        .enumerate()
+        .filter(|&(_, scene_mat)| applicable_materials.iter()
+                                      .any(|applicable_mat| applicable_mat == &scene_mat.name))
+        .map(|(idx, _)| idx)
+        .collect();
+
+    assert!(
+        !applicable_idxs.is_empty(),
+        "When non-empty target material names provided, at least one should actually exist. Target material names: {:?}, Scene materials: {:?}",
+        applicable_materials,
+        scene.materials
+    );
+
+    applicable_idxs
+}
+
+pub fn is_applicable(mat_idx: usize, applicable_material_idxs: &[usize]) -> bool {
+    if applicable_material_idxs.is_empty() {
+        true
+    } else {
+        applicable_material_idxs.iter()
+            .any(|&idx| idx == mat_idx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::geom::surf::SurfaceBuilder;
+    use ::cgmath::Vector3;
+    use std::iter;
+
+    #[test]
+    fn test_surfel_effect_visits_all_surfels() {
+        let mut surface = SurfaceBuilder::new()
+            .substances(&vec![0.5])
+            .add_surface_from_points((0..3).map(|x| Vector3::new(x as f32, 0.0, 0.0)))
+            .build();
+
+        let effect = SurfelEffect::new(|surfel: &mut Surfel, _: &Scene| surfel.substances[0] += surfel.position.x);
+        effect.perform(&mut Scene::empty(), &mut surface, Path::new(""));
+
+        let substances : Vec<f32> = surface.samples.iter().map(|s| s.substances[0]).collect();
+        assert_eq!(substances, vec![0.5, 1.5, 2.5]);
+    }
+
+    #[test]
+    fn test_modulated_rule_varies_between_surfels() {
+        let mut surface = SurfaceBuilder::new()
+            .substances(&vec![1.0, 0.0])
+            .add_surface_from_points((0..20).map(|x| Vector3::new(x as f32 * 0.3, 0.0, 0.0)))
+            .build();
+
+        let rule = SurfelRule::new(1, 0, 0.5, iter::empty::<String>())
+            .modulation(Modulation::Random { seed: 3, min: 0.0, max: 2.0 });
+        surface.samples.iter_mut().for_each(|s| rule.age_surfel(s, 1.0, 1.0));
+
+        let rust : Vec<f32> = surface.samples.iter().map(|s| s.substances[1]).collect();
+        assert!(rust.iter().all(|&r| r >= 0.0 && r <= 1.0));
+        assert!(rust.iter().any(|&r| (r - rust[0]).abs() > 0.05));
+    }
+
+    #[test]
+    fn test_rule_rate_is_per_unit_of_time() {
+        let mut surface = SurfaceBuilder::new()
+            .substances(&vec![1.0, 0.0])
+            .add_surface_from_points(vec![Vector3::new(0.0, 0.0, 0.0)])
+            .build();
+
+        let rule = SurfelRule::new(1, 0, 0.2, iter::empty::<String>());
+        surface.samples.iter_mut().for_each(|s| rule.age_surfel(s, 1.0, 0.5));
+        surface.samples.iter_mut().for_each(|s| rule.age_surfel(s, 1.0, 0.5));
+
+        assert!((surface.samples[0].substances[1] - 0.2).abs() < 0.00001);
+    }
+
+    #[test]
+    fn test_decay_is_independent_of_time_step() {
+        let mut surface = SurfaceBuilder::new()
+            .substances(&vec![1.0])
+            .add_surface_from_points(vec![Vector3::new(0.0, 0.0, 0.0), Vector3::new(1.0, 0.0, 0.0)])
+            .build();
+
+        let evaporation = SurfelRule::new(0, 0, -0.5, iter::empty::<String>());
+        evaporation.age_surfel(&mut surface.samples[0], 1.0, 2.0);
+        for _ in 0..4 {
+            evaporation.age_surfel(&mut surface.samples[1], 1.0, 0.5);
+        }
+
+        // Half of the water evaporates per unit of time, a single long step must not dry it up
+        assert!((surface.samples[0].substances[0] - 0.25).abs() < 0.00001);
+        assert!((surface.samples[1].substances[0] - 0.25).abs() < 0.00001);
     }
 }
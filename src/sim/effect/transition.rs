@@ -0,0 +1,57 @@
+use super::Effect;
+use super::expr::Expr;
+use super::surfel::{applicable_material_idxs, is_applicable};
+
+use ::geom::scene::Scene;
+use ::geom::surf::{Surface, SurfelProfile};
+
+use std::path::Path;
+
+/// Changes how surfels interact with tons once a condition on their substances holds, e.g. when
+/// rust or moss exceeds a threshold, by applying the parameters of another material.
+///
+/// The transition is not reversed when the condition stops holding, the surfel keeps behaving
+/// like the other material, just like rust does not turn back into iron.
+pub struct MaterialTransition {
+    condition: Expr,
+    profile: SurfelProfile,
+    applicable_materials: Vec<String>
+}
+
+impl MaterialTransition {
+    /// Creates a transition to the given profile for surfels where the given condition, with
+    /// substances referred to by index, holds.
+    ///
+    /// Applicable materials work like in `SurfelRule::new` and refer to the material that the
+    /// surfels were sampled from, not the last profile they transitioned to.
+    pub fn new<M, S>(condition: Expr, profile: SurfelProfile, applicable_materials: M) -> MaterialTransition
+        where M : IntoIterator<Item = S>, S : Into<String>
+    {
+        let applicable_materials = applicable_materials.into_iter().map(|m| m.into()).collect();
+        MaterialTransition { condition, profile, applicable_materials }
+    }
+}
+
+impl Effect for MaterialTransition {
+    fn perform(&self, scene: &mut Scene, surf: &mut Surface, _: &Path) {
+        let applicable_material_idxs = applicable_material_idxs(&self.applicable_materials, scene);
+        let profile_material_idx = self.profile.material_idx(surf);
+        let mut transitions = 0;
+
+        for surfel in surf.samples.iter_mut() {
+            let orig_mat_idx = scene.entities[surfel.entity_idx].original_material_idx;
+
+            if is_applicable(orig_mat_idx, &applicable_material_idxs) &&
+                !self.profile.is_applied(surfel, profile_material_idx) &&
+                self.condition.holds(&surfel.substances)
+            {
+                self.profile.apply(surfel, profile_material_idx);
+                transitions += 1;
+            }
+        }
+
+        if transitions > 0 {
+            info!("{} surfels changed their material", transitions);
+        }
+    }
+}
@@ -0,0 +1,100 @@
+//! Kernels for weighting the exchange of substances between a ton and the
+//! surfels within its interaction radius.
+
+use std::f32::consts::PI;
+
+/// Determines how much of the substance exchange of an interaction a surfel
+/// receives depending on its distance to the point of impact.
+///
+/// The weights of all interacting surfels are normalized to sum up to one,
+/// so the kernel only affects the distribution of substances, not the total
+/// amount that is exchanged.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum InteractionKernel {
+    /// All surfels within the interaction radius get an equal share, regardless of
+    /// their distance. This was the only available behaviour before kernels were
+    /// introduced and produces hard-edged splotches at low surfel densities.
+    Box,
+    /// Weight falls off linearly from one at the point of impact to zero at the
+    /// interaction radius.
+    Linear,
+    /// Gaussian falloff with a standard deviation of a third of the interaction
+    /// radius, so that the weight is almost zero at the border of the radius.
+    Gaussian,
+    /// Parabolic falloff `1 - (d/r)^2`, the kernel with the least mean squared
+    /// error for density estimation.
+    Epanechnikov
+}
+
+impl InteractionKernel {
+    /// Calculates the unnormalized weight of a surfel at the given distance from
+    /// the point of impact.
+    pub fn weight(&self, distance: f32, radius: f32) -> f32 {
+        if radius <= 0.0 {
+            return 1.0;
+        }
+
+        let relative_distance = (distance / radius).min(1.0);
+
+        match *self {
+            InteractionKernel::Box => 1.0,
+            InteractionKernel::Linear => 1.0 - relative_distance,
+            InteractionKernel::Gaussian => {
+                let sigma = 1.0 / 3.0;
+                (-0.5 * (relative_distance / sigma).powi(2)).exp() / (sigma * (2.0 * PI).sqrt())
+            },
+            InteractionKernel::Epanechnikov => 1.0 - relative_distance * relative_distance
+        }
+    }
+
+    /// Calculates weights for the given distances that sum up to one.
+    ///
+    /// If all weights are zero, e.g. because all surfels lie exactly on the border
+    /// of the interaction radius, falls back to equal weights.
+    pub fn normalized_weights<I>(&self, distances: I, radius: f32) -> Vec<f32>
+        where I : IntoIterator<Item = f32>
+    {
+        let mut weights : Vec<f32> = distances.into_iter()
+            .map(|d| self.weight(d, radius))
+            .collect();
+
+        let total : f32 = weights.iter().sum();
+
+        if total > 0.0 {
+            weights.iter_mut().for_each(|w| *w /= total);
+        } else {
+            let equal_weight = 1.0 / (weights.len() as f32);
+            weights.iter_mut().for_each(|w| *w = equal_weight);
+        }
+
+        weights
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_box_weights_are_equal() {
+        let weights = InteractionKernel::Box.normalized_weights(vec![0.0, 0.5, 1.0, 0.2], 1.0);
+        assert!(weights.iter().all(|&w| (w - 0.25).abs() < 0.00001));
+    }
+
+    #[test]
+    fn test_falloff_kernels_prefer_near_surfels() {
+        for kernel in &[InteractionKernel::Linear, InteractionKernel::Gaussian, InteractionKernel::Epanechnikov] {
+            let weights = kernel.normalized_weights(vec![0.1, 0.5, 0.9], 1.0);
+            let total : f32 = weights.iter().sum();
+
+            assert!((total - 1.0).abs() < 0.00001, "Weights of {:?} should sum to one, got {}", kernel, total);
+            assert!(weights[0] > weights[1] && weights[1] > weights[2], "Weights of {:?} should fall off, got {:?}", kernel, weights);
+        }
+    }
+
+    #[test]
+    fn test_all_zero_weights_fall_back_to_equal_weights() {
+        let weights = InteractionKernel::Linear.normalized_weights(vec![1.0, 1.0], 1.0);
+        assert_eq!(weights, vec![0.5, 0.5]);
+    }
+}
@@ -0,0 +1,216 @@
+use std::path::PathBuf;
+
+use ::geom::surf::Proximity;
+
+use ::cgmath::Vector4;
+
+use ::image;
+
+use ::substance::{Substance, SubstanceRef, SubstanceRegistry};
+use super::effect::{SubstanceMapper, Sampling, SubstanceColorEffect, SubstanceMapMaterialEffect, MultiMapMaterialEffect, Blend, LayeredBlend, PbrBlend, PbrMaterial, Ramp, RampSegment};
+
+/// Makes an effect on substance maps, given the mapped substance if substances were declared.
+type SubstanceMapEffectFactory = Box<FnOnce(Option<&Substance>) -> Box<SubstanceMapMaterialEffect>>;
+
+
+/// Configures a substance map, gathered from the surfels for each entity after each iteration,
+/// and the effects that turn it into textures and materials.
+///
+/// Used with `SimulationBuilder::add_substance_mapper`, e.g. to drive a rust blend from
+/// one substance and a moss blend from another in the same simulation.
+pub struct SubstanceMapperBuilder {
+    substance: SubstanceRef,
+    width: usize,
+    height: usize,
+    sampling: Sampling,
+    proximity: Proximity,
+    effects: Vec<SubstanceMapEffectFactory>,
+    combined_effects: Vec<Box<MultiMapMaterialEffect>>
+}
+
+impl SubstanceMapperBuilder {
+    /// Creates a builder for 4096x4096 maps of the given substance, gathered with
+    /// `Sampling::NearestTriangle` and without effects.
+    pub fn new<S : Into<SubstanceRef>>(substance: S) -> SubstanceMapperBuilder {
+        SubstanceMapperBuilder {
+            substance: substance.into(),
+            width: 4096,
+            height: 4096,
+            sampling: Sampling::NearestTriangle,
+            proximity: Proximity::Euclidean,
+            effects: Vec::new(),
+            combined_effects: Vec::new()
+        }
+    }
+
+    pub fn substance<S : Into<SubstanceRef>>(mut self, substance: S) -> SubstanceMapperBuilder {
+        self.substance = substance.into();
+        self
+    }
+
+    pub fn size(mut self, width: usize, height: usize) -> SubstanceMapperBuilder {
+        self.width = width;
+        self.height = height;
+        self
+    }
+
+    pub fn rasterize(mut self, padding: f32) -> SubstanceMapperBuilder {
+        self.sampling = Sampling::Rasterization(padding);
+        self
+    }
+
+    /// Sets which surfels contribute to a texel of the substance map, e.g. to keep substances
+    /// from showing through thin walls. Defaults to `Proximity::Euclidean`.
+    pub fn proximity(mut self, proximity: Proximity) -> SubstanceMapperBuilder {
+        self.proximity = proximity;
+        self
+    }
+
+    /// Adds a custom effect on the gathered substance maps.
+    pub fn add_effect(mut self, effect: Box<SubstanceMapMaterialEffect>) -> SubstanceMapperBuilder {
+        self.effects.push(Box::new(move |_: Option<&Substance>| effect));
+        self
+    }
+
+    /// Adds a map that fades from white to the color of the mapped substance, or to black
+    /// if substances were not declared.
+    pub fn add_effect_density_map(mut self) -> SubstanceMapperBuilder {
+        self.effects.push(Box::new(|substance: Option<&Substance>| Box::new(
+            SubstanceColorEffect::new(
+                Vector4::new(1.0, 1.0, 1.0, 1.0), // substance = 0
+                substance.map(Substance::debug_color).unwrap_or_else(|| Vector4::new(0.0, 0.0, 0.0, 1.0)), // substance = 1
+                Vector4::new(0.0, 0.0, 1.0, 1.0),  // substance = NaN
+            )
+        )));
+
+        self
+    }
+
+    pub fn add_effect_ramp(self) -> SubstanceMapperBuilder {
+        let material_names = vec![String::from("bronze"), String::from("stone"), String::from("iron")];
+        let segments = vec![
+            RampSegment::new(0.0, 0.1, None, None),
+            RampSegment::new(0.1, 0.25, None, Some(image::open("test-scenes/buddha-scene-iron-concrete/RustPlain018_COL_VAR1_1K.jpg").unwrap())),
+            RampSegment::new(0.25, 1.0, Some(image::open("test-scenes/buddha-scene-iron-concrete/RustPlain018_COL_VAR1_1K.jpg").unwrap()), Some(image::open("test-scenes/buddha-scene-iron-concrete/RustPlain018_COL_VAR1_1K.jpg").unwrap()))
+        ];
+
+        self.add_effect(Box::new(Ramp::new(material_names, PathBuf::from("test-scenes/buddha-scene-iron-concrete/"), segments)))
+    }
+
+    /// Blends the given image over the diffuse textures of the target materials, weighted by
+    /// concentration. Blends of multiple substances on the same material do not stack, use
+    /// `add_effect_layered_blend` for that.
+    pub fn add_effect_blend<P>(self, target_material_names: Vec<String>, texture_base_path: P, blend_target_image: P) -> SubstanceMapperBuilder
+        where P : Into<PathBuf>
+    {
+        let blend = Blend::new(target_material_names, texture_base_path, &blend_target_image.into());
+        self.add_effect(Box::new(blend))
+    }
+
+    /// Synthesizes a full set of PBR textures for the target materials, blending from the original
+    /// materials towards the weathered material by concentration.
+    pub fn add_effect_pbr_blend<P>(self, target_material_names: Vec<String>, texture_base_path: P, weathered: PbrMaterial) -> SubstanceMapperBuilder
+        where P : Into<PathBuf>
+    {
+        self.add_effect(Box::new(PbrBlend::new(target_material_names, texture_base_path, weathered)))
+    }
+
+    /// Adds a custom effect on the maps of several substances, which are gathered with the size
+    /// and sampling of this mapper. The substances the effect needs are resolved when building.
+    pub fn add_combined_effect(mut self, effect: Box<MultiMapMaterialEffect>) -> SubstanceMapperBuilder {
+        self.combined_effects.push(effect);
+        self
+    }
+
+    /// Blends the given images over the diffuse textures of the target materials, from first to
+    /// last, each one weighted by the concentration of its substance, e.g.
+    /// `vec![("rust", "rust.png"), ("moss", "moss.png")]` for moss growing over rust.
+    pub fn add_effect_layered_blend<P, S>(self, target_material_names: Vec<String>, texture_base_path: P, layers: Vec<(S, P)>) -> SubstanceMapperBuilder
+        where P : Into<PathBuf>,
+            S : Into<SubstanceRef>
+    {
+        let blend = layers.into_iter().fold(
+            LayeredBlend::new(target_material_names, texture_base_path),
+            |blend, (substance, image)| blend.layer(substance, &image.into())
+        );
+
+        self.add_combined_effect(Box::new(blend))
+    }
+
+    pub fn has_effects(&self) -> bool {
+        !self.effects.is_empty() || !self.combined_effects.is_empty()
+    }
+
+    /// Builds the mapper, resolving the mapped substance and the substances of combined effects.
+    ///
+    /// # Panics
+    /// If a substance was not declared.
+    pub fn build(self, substances: &SubstanceRegistry) -> SubstanceMapper {
+        let substance_idx = substances.resolve(&self.substance);
+        let substance = substances.get(substance_idx);
+        let effects = self.effects.into_iter()
+            .map(|make| make(substance))
+            .collect();
+
+        self.combined_effects.into_iter().fold(
+            SubstanceMapper::new(substance_idx, self.sampling, self.width, self.height, effects)
+                .proximity(self.proximity),
+            |mapper, effect| {
+                let substance_idxs = effect.substances().iter()
+                    .map(|substance| substances.resolve(substance))
+                    .collect();
+                mapper.add_combined_effect(substance_idxs, effect)
+            }
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn substances() -> SubstanceRegistry {
+        let mut substances = SubstanceRegistry::new();
+        substances.add(Substance::new("rust"));
+        substances.add(Substance::new("moss"));
+        substances
+    }
+
+    #[test]
+    fn test_mappers_share_maps_only_with_same_settings() {
+        let substances = substances();
+        let rust = SubstanceMapperBuilder::new("rust").size(512, 512).build(&substances);
+
+        assert!(rust.gathers_maps_alike(&SubstanceMapperBuilder::new(0).size(512, 512).add_effect_density_map().build(&substances)));
+        assert!(rust.gathers_maps_alike(&SubstanceMapperBuilder::new("moss").size(512, 512).build(&substances)));
+        assert!(!rust.gathers_maps_alike(&SubstanceMapperBuilder::new("rust").size(512, 512).rasterize(0.1).build(&substances)));
+        assert!(!rust.gathers_maps_alike(&SubstanceMapperBuilder::new("rust").build(&substances)));
+    }
+
+    #[test]
+    fn test_merged_mappers_require_each_substance_once() {
+        let substances = substances();
+        let mapper = |substance| SubstanceMapperBuilder::new(substance)
+            .size(512, 512)
+            .add_effect_density_map()
+            .build(&substances);
+
+        let mut merged = mapper("moss");
+        merged.merge(mapper("rust"));
+        merged.merge(mapper("moss"));
+
+        assert_eq!(merged.required_substances(), vec![1, 0]);
+    }
+
+    #[test]
+    fn test_combined_effects_resolve_names_when_building() {
+        let mapper = SubstanceMapperBuilder::new("rust")
+            .add_combined_effect(Box::new(
+                LayeredBlend::new(vec![], "")
+                    .layer("moss", &PathBuf::from("test-scenes/buddha-scene/moss.png"))
+            ))
+            .build(&substances());
+
+        assert_eq!(mapper.required_substances(), vec![1]);
+    }
+}
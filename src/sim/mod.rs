@@ -1,8 +1,19 @@
 
+mod balance;
 mod effect;
+mod kernel;
+mod mapbuilder;
 mod sim;
 mod simbuilder;
 mod ton;
+mod tracer;
 
-pub use self::sim::Simulation;
+pub use self::balance::SubstanceBalance;
+pub use self::effect::expr;
+pub use self::effect::{Effect, SurfelRule, ExpressionRule, Modulation, Condition, SunExposure, Light, MaterialTransition};
+pub use self::effect::{SubstanceMap, SubstanceMapMaterialEffect, MultiMapMaterialEffect, LayeredBlend, Ramp, RampSegment, PbrBlend, PbrMaterial, PbrSource};
+pub use self::kernel::InteractionKernel;
+pub use self::mapbuilder::SubstanceMapperBuilder;
+pub use self::sim::{Simulation, SimulationConfig};
 pub use self::simbuilder::SimulationBuilder;
+pub use self::ton::MissPolicy;
@@ -5,24 +5,18 @@
 use std::fs;
 use std::time::Instant;
 use std::path::PathBuf;
-use std::f32::{INFINITY};
 
-use ::geom::surf::{Surface, Surfel, SurfaceBuilder};
+use ::geom::surf::{Surface, SurfaceBuilder, SamplingReport};
 use ::geom::scene::{Scene, Triangle};
 use ::geom::octree::Octree;
-use ::geom::vtx::Position;
-use ::geom::spatial::Spatial;
-
-use ::cgmath::Vector3;
-use ::cgmath::prelude::*;
 
 use ::sink::SceneSink;
 
-use super::ton::{Ton, TonSource};
+use super::ton::TonSource;
+use super::tracer::Tracer;
+use super::balance::SubstanceBalance;
 use super::effect::Effect;
-
-use ::rand;
-use ::rand::Rng;
+use ::substance::SubstanceRegistry;
 
 /// Maintains a simulation on a scene with an associated surface
 /// model.
@@ -31,9 +25,14 @@ pub struct Simulation {
     scene: Scene,
     /// The surface model, describing surface properties at point samples of the scene
     surface: Surface,
+    /// Declared substances, or empty if substances are only referred to by index
+    substances: SubstanceRegistry,
     /// Amount of iterations to perform, each involving the tracing of newly emitted particles and
     /// the performing of effects.
     iterations: u32,
+    /// Amount of simulated time that passes in each iteration, in the unit of time that rates of
+    /// effects and sources are given in, 1 if the simulation is not concerned with time.
+    time_step: f32,
     /// Ton sources that will emit particles at the start of each iteration
     sources: Vec<TonSource>,
     /// Effects that will be invoked at the end of each iteration
@@ -45,10 +44,34 @@ pub struct Simulation {
     output_path: PathBuf,
     /// If set, holds the path where to write an obj with the subset of the surfels that were hit
     hit_map_path: Option<PathBuf>,
+    /// If set, saturated surfels and tons keep excess substances where they came from instead of
+    /// clamping them away, and settling tons lose what they deposit.
+    conserve_substances: bool,
+    /// Most of each substance that a ton can carry, 1.0 for substances without an entry
+    max_ton_loads: Vec<f32>,
+    /// Accounting of transported substances for each completed iteration
+    substance_balances: Vec<SubstanceBalance>
+}
+
+/// Options that control how a simulation runs, as opposed to what it simulates.
+pub struct SimulationConfig {
+    /// Amount of iterations to perform
+    pub iterations: u32,
+    /// Amount of simulated time that passes in each iteration
+    pub time_step: f32,
+    /// Base path for synthesized output files
+    pub output_path: PathBuf,
+    /// If set, holds the path where to write an obj with the subset of the surfels that were hit
+    pub hit_map_path: Option<PathBuf>,
+    /// Keep excess substances where they came from instead of clamping them away
+    pub conserve_substances: bool,
+    /// Most of each substance that a ton can carry, 1.0 for substances without an entry
+    pub max_ton_loads: Vec<f32>
 }
 
 impl Simulation {
-    /// Creates a new simulation.
+    /// Creates a new simulation without declared substances, where each iteration is a unit of
+    /// time and substances are clamped rather than conserved.
     /// Using the builder is recommended.
     pub fn new(
         scene: Scene,
@@ -59,16 +82,44 @@ impl Simulation {
         scene_sinks: Vec<Box<SceneSink>>,
         output_path: PathBuf,
         hit_map_path: Option<PathBuf>) -> Simulation
+    {
+        let config = SimulationConfig {
+            iterations,
+            time_step: 1.0,
+            output_path,
+            hit_map_path,
+            conserve_substances: false,
+            max_ton_loads: Vec::new()
+        };
+
+        Simulation::with_config(scene, surface, SubstanceRegistry::new(), sources, effects, scene_sinks, config)
+    }
+
+    /// Creates a new simulation with declared substances and the given options.
+    /// Using the builder is recommended.
+    pub fn with_config(
+        scene: Scene,
+        surface: Surface,
+        substances: SubstanceRegistry,
+        sources: Vec<TonSource>,
+        effects: Vec<Box<Effect>>,
+        scene_sinks: Vec<Box<SceneSink>>,
+        config: SimulationConfig) -> Simulation
     {
         Simulation {
             scene,
             surface,
-            iterations,
+            substances,
+            iterations: config.iterations,
+            time_step: config.time_step,
             sources,
             effects,
             scene_sinks,
-            output_path,
-            hit_map_path
+            output_path: config.output_path,
+            hit_map_path: config.hit_map_path,
+            conserve_substances: config.conserve_substances,
+            max_ton_loads: config.max_ton_loads,
+            substance_balances: Vec::new()
         }
     }
 
@@ -82,9 +133,10 @@ impl Simulation {
     /// modified scene and materials.
     pub fn run(&mut self) {
         info!(
-            "Running simulation with {} iterations of {} particles each... ",
+            "Running simulation with {} iterations of {} particles and {} units of time each... ",
             self.iterations,
-            self.sources.iter().map(|s| s.emission_count()).sum::<u32>()
+            self.sources.iter().map(|s| s.emission_count()).sum::<u32>(),
+            self.time_step
         );
 
         for iteration_idx in 0..self.iterations {
@@ -92,8 +144,10 @@ impl Simulation {
             self.output_path.push(format!("iteration-{}", (1+iteration_idx)));
             fs::create_dir_all(&self.output_path).expect(&format!("Could not create iteration output directory {:?}", self.output_path));
 
-            self.trace_particles();
-            self.perform_iteration_effects();
+            let octree = self.build_octree();
+            self.trace_particles(&octree);
+            self.clamp_substances();
+            self.perform_iteration_effects(&octree);
             self.serialize_scene_to_sinks();
 
             self.output_path.pop();
@@ -102,198 +156,96 @@ impl Simulation {
         self.dump_hit_map();
     }
 
+    /// Gets the amount of simulated time that passes in each iteration.
+    pub fn time_step(&self) -> f32 {
+        self.time_step
+    }
+
     pub fn surface(&self) -> &Surface {
         &self.surface
     }
 
-    fn interact(surface: &mut Surface, octree: &Octree<Triangle>, ton: &mut Ton, hit_tri: &Triangle, intersection_point: Vector3<f32>, incoming_direction: Vector3<f32>) {
-        let interacting_surfel_idxs = surface.find_within_sphere_indexes(intersection_point, ton.interaction_radius);
-
-        if interacting_surfel_idxs.is_empty() {
-            warn!("Ton intersected geometry but did not interact with any surfels, terminating early");
-            return;
-        }
-
-        let mut rng = rand::thread_rng();
-        let random : f32 = rng.gen();
-
-        let &mut Ton { p_straight, p_parabolic, p_flow, .. } = ton;
-
-        if random < (p_straight + p_parabolic + p_flow) {
-            // If not settled yet, pick up some material
+    /// Analyses how evenly the surface model covers the scene, e.g. to choose an interaction
+    /// radius that fits the spacing of surfels.
+    pub fn sampling_report(&self) -> SamplingReport {
+        SamplingReport::analyze(&self.surface, &self.scene)
+    }
 
-            // REVIEW, should each interacting surfel deteriorate motion probabilities? Currently just one does
-            Self::deteriorate_motion_probabilities(ton, &surface.samples[interacting_surfel_idxs[0]]);
-            Self::transport_material_to_ton(surface, &interacting_surfel_idxs, ton);
-        }
+    /// Gets the declared substances, which is empty if substances were not declared.
+    pub fn substances(&self) -> &SubstanceRegistry {
+        &self.substances
+    }
 
-        if random < p_straight {
-            let normal = surface.samples[interacting_surfel_idxs[0]].normal;
-            let outgoing_direction = hit_tri.sample_diffuse();
-
-            // TODO instead of taking the normal, sample on upper hemisphere, but I need tangents for this
-            //let reflection_direction = normal;
-            Self::trace_straight(surface, octree, ton, intersection_point + 0.000001 * normal, outgoing_direction);
-        } else if random < (p_straight + p_parabolic) {
-            Self::trace_parabolic(surface, octree, ton, hit_tri, intersection_point);
-        } else if random < (p_straight + p_parabolic + p_flow) {
-            Self::trace_flow(surface, octree, ton, hit_tri, intersection_point, incoming_direction);
-        } else {
-            Self::transport_material_to_surf(ton, surface, &interacting_surfel_idxs);
-            return;
-        }
+    /// Gets the accounting of substances transported by tons, one entry per completed iteration.
+    pub fn substance_balances(&self) -> &[SubstanceBalance] {
+        &self.substance_balances
     }
 
-    fn trace_particles(&mut self) {
+    /// Builds an octree of the triangles of the scene, shared by tracing and effects in an iteration.
+    fn build_octree(&self) -> Octree<Triangle> {
         info!("Building octree...  ");
         let before = Instant::now();
         let octree : Octree<_> = self.scene.triangles().collect();
         info!("Done building octree after {}s", before.elapsed().as_secs());
+        octree
+    }
 
+    fn trace_particles(&mut self, octree: &Octree<Triangle>) {
         info!("Tracing particles and transporting substances...  ");
         let before = Instant::now();
-        let surf = &mut self.surface;
 
-        // First motion state is always trace straight
-        self.sources.iter()
-            .flat_map(|src| src.emit())
-            .for_each(move |(mut ton, ray_origin, ray_direction)| Self::trace_straight(surf, &octree, &mut ton, ray_origin, ray_direction));
-        info!("Ok, took {}s", before.elapsed().as_secs());
-    }
+        let substance_count = self.surface.samples.first()
+            .map(|s| s.substances.len())
+            .unwrap_or(0);
 
-    fn trace_straight(surface: &mut Surface, octree: &Octree<Triangle>, ton: &mut Ton, origin: Vector3<f32>, direction: Vector3<f32>) {
-        if let Some((hit_tri, param)) = octree.ray_intersection_target_and_parameter(origin, direction) {
-            let intersection_point = origin + direction * param;
-            Self::interact(surface, octree, ton, hit_tri, intersection_point, direction);
+        for source in &mut self.sources {
+            source.advance();
         }
-    }
 
-    fn trace_flow(surface: &mut Surface, octree: &Octree<Triangle>, ton: &mut Ton,  hit_tri: &Triangle, intersection_point: Vector3<f32>, incoming_direction: Vector3<f32>) {
-        let normal = hit_tri.normal();
+        let mut max_loads = self.max_ton_loads.clone();
+        max_loads.resize(substance_count, 1.0);
 
-        let origin_offset_mag = ton.flow_upward_offset; // both affect the distance of a flow event
-        let downward_pull_mag = ton.flow_downward_pull;
+        let balance = {
+            let mut tracer = Tracer::new(&mut self.surface, octree, self.conserve_substances, substance_count)
+                .max_loads(max_loads);
 
-        let new_origin = intersection_point + origin_offset_mag * normal;
-        let flow_direction = {
-            let dir = hit_tri.project_onto_tangential_plane(incoming_direction);
-            if dir.is_zero() {
-                warn!("Incoming direction for flow is orthogonal, using A edge as flow direction");
-                (hit_tri.vertices[2].position() - hit_tri.vertices[1].position()).normalize()
-            } else {
-                dir
-            }
-        };
-        let new_direction = (flow_direction - downward_pull_mag * normal).normalize();
-
-        // TODO somehow handle misses, we dont wanna go 10 in X direction
-        // maybe if misses, rescue by making it parabolic
+            self.sources.iter()
+                .flat_map(|src| src.emit())
+                .for_each(|(mut ton, ray_origin, ray_direction)| tracer.trace_emitted(&mut ton, ray_origin, ray_direction));
 
-        Self::trace_straight(surface, octree, ton, new_origin, new_direction);
-    }
-
-    fn trace_parabolic(surface: &mut Surface, octree: &Octree<Triangle>, ton: &mut Ton,  hit_tri: &Triangle, intersection_point: Vector3<f32>) {
-        // Maximum height of a bounce assuming it is straight up and gravity pointing straight down
-        let upward_parabola_height = ton.parabola_height;
-        let gravity_mag = 9.81_f32;
-        let timestep = 1.0 / 30.0; // 0.0333333 seconds, more is more exact but slower
-
-        let gravity_acceleration = Vector3::new(0.0, -gravity_mag, 0.0);
-        let takeoff_velocity_mag = (2.0 * gravity_mag * upward_parabola_height).sqrt();
-
-        // REVIEW regarding surface as diffuse, could also reflect on the normal
-        let normal = hit_tri.interpolate_at(intersection_point, |v| v.normal);
-        let mut velocity = takeoff_velocity_mag * hit_tri.sample_diffuse();
-        let mut position = intersection_point + normal * 0.0000001;
-        let mut scene_bounds = octree.bounds();
-        scene_bounds.max.y = INFINITY; // ignore if out of bounds in positive y direction since gravity will eventually pull it downward
-
-        while scene_bounds.is_point_inside(position) {
-            velocity += gravity_acceleration * timestep;
-
-            let spatial_delta = velocity * timestep;
-            let dist = spatial_delta.magnitude();
-            let direction = spatial_delta / dist;
-
-            if let Some((hit_tri, t)) = octree.line_segment_intersection_target_and_parameter(position, direction, dist) {
-                let intersection_point = position + t * direction;
-                Self::interact(surface, octree, ton, hit_tri, intersection_point, direction);
-                break;
-            } else {
-                // No intersection, safe to move particle without penetrating objects
-                position += spatial_delta;
+            if tracer.retries() > 0 {
+                info!("Retried {} motions of tons that missed", tracer.retries());
             }
-        }
-    }
 
-    fn transport_material_to_surf(ton: &Ton, surface: &mut Surface, interacting_surfel_idxs: &Vec<usize>) {
-        for surfel_idx in interacting_surfel_idxs {
-            let interacting_surfel = &mut surface.samples[*surfel_idx];
-            assert_eq!(interacting_surfel.substances.len(), ton.substances.len());
-
-            let material_transports = interacting_surfel.deposition_rates.iter()
-                .zip(
-                    interacting_surfel.substances
-                        .iter_mut()
-                        .zip(
-                            ton.substances.iter()
-                        )
-                );
-
-            for (ref deposition_rate, (ref mut surfel_material, &ton_material)) in material_transports {
-                // Deposition rate gets equally divided between interacting surfels
-                let deposition_rate = *deposition_rate / (interacting_surfel_idxs.len() as f32);
-                **surfel_material = (**surfel_material + deposition_rate * ton_material).min(1.0);
-            }
-        }
-    }
+            tracer.into_balance()
+        };
+        info!("Ok, took {}s", before.elapsed().as_secs());
 
-    fn transport_material_to_ton(surface: &mut Surface, interacting_surfel_idxs: &Vec<usize>, ton: &mut Ton) {
-        for surfel_idx in interacting_surfel_idxs {
-            let interacting_surfel = &mut surface.samples[*surfel_idx];
-
-            assert_eq!(interacting_surfel.substances.len(), ton.substances.len());
-            let material_transports = ton.pickup_rates.iter()
-                .zip(
-                    ton.substances
-                        .iter_mut()
-                        .zip(
-                            interacting_surfel.substances.iter_mut()
-                        )
-                );
-
-            for (ref pickup_rate, (ref mut ton_material, ref mut surfel_material)) in material_transports {
-                // pickup rate gets equally distributed between all interacting surfels
-                let pickup_rate = *pickup_rate / (interacting_surfel_idxs.len() as f32);
-                let transport_amount = pickup_rate * **surfel_material;
-
-                **surfel_material = (**surfel_material - transport_amount).max(0.0);
-                **ton_material = (**ton_material + transport_amount).min(1.0);
-            }
-        }
+        info!("Substance balance:\n{}", balance);
+        self.substance_balances.push(balance);
     }
 
-    fn deteriorate_motion_probabilities(ton: &mut Ton, surfel: &Surfel) {
-        ton.p_straight -= surfel.delta_straight;
-        if ton.p_straight < 0.0 {
-            ton.p_straight = 0.0;
+    /// Clamps the amounts of declared substances on all surfels into their ranges, booking
+    /// the amounts that were cut off in the balance of the iteration.
+    fn clamp_substances(&mut self) {
+        if self.substances.is_empty() {
+            return;
         }
 
-        ton.p_parabolic -= surfel.delta_parabolic;
-        if ton.p_parabolic < 0.0 {
-            ton.p_parabolic = 0.0;
-        }
+        let mut balance = self.substance_balances.last_mut();
+        for surfel in &mut self.surface.samples {
+            let unclamped = surfel.substances.clone();
+            self.substances.clamp(&mut surfel.substances);
 
-        // NOTE the original flow deterioration is max(kf + max(kp - deltaP, 0) - deltaF, 0)
-        ton.p_flow -= surfel.delta_flow;
-        if ton.p_flow < 0.0 {
-            ton.p_flow = 0.0;
+            if let Some(ref mut balance) = balance {
+                balance.book_surfel_clamping(&unclamped, &surfel.substances);
+            }
         }
     }
 
-    fn perform_iteration_effects(&mut self) {
+    fn perform_iteration_effects(&mut self, octree: &Octree<Triangle>) {
         for effect in &self.effects {
-            effect.perform(&mut self.scene, &mut self.surface, &self.output_path);
+            effect.perform_with_octree(&mut self.scene, &mut self.surface, octree, &self.output_path, self.time_step);
         }
     }
 
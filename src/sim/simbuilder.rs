@@ -4,24 +4,30 @@ use std::io::prelude::*;
 use std::io;
 use std::path::PathBuf;
 use std::iter;
+use std::collections::HashMap;
 
-use ::geom::surf::{Surface, SurfaceBuilder};
-use ::geom::scene::Scene;
+use ::geom::surf::{Surface, Surfel, SurfaceBuilder, SurfelProfile, SurfelMaterial, GeometricFeature, SurfelField, Proximity};
+use ::geom::scene::{Scene, Triangle};
+use ::geom::octree::Octree;
 use ::geom::spatial::Spatial;
 
 use ::sink::*;
 use ::sink::obj::ObjSink;
 use ::sink::mtl::MtlSink;
+use ::sink::gltf::GltfSink;
 
-use ::cgmath::Vector4;
 use ::cgmath::Vector3;
 use ::cgmath::prelude::*;
 
-use ::image;
+use super::sim::{Simulation, SimulationConfig};
+use super::ton::TonSourceBuilder;
+use ::substance::{Substance, SubstanceRef, SubstanceRegistry};
+use super::effect::expr::{Assignment, parse_program, parse_expr};
+use super::mapbuilder::SubstanceMapperBuilder;
+use super::effect::{Effect, SubstanceMapper, SurfelRule, ExpressionRule, SurfelEffect, Modulation, Condition, SunExposure, Light, MaterialTransition, PbrMaterial, FeatureRule, Diffusion};
 
-use super::sim::Simulation;
-use super::ton::{TonSourceBuilder, TonSource};
-use super::effect::{Effect, SubstanceMapper, Sampling, SubstanceColorEffect, SubstanceMapMaterialEffect, SurfelRule, Blend, Ramp, RampSegment};
+/// Makes an effect once the substances it refers to can be resolved.
+type EffectFactory = Box<FnOnce(&SubstanceRegistry) -> Box<Effect>>;
 
 /// Builds a simulation according to provided parameters and closures.
 ///
@@ -56,23 +62,53 @@ use super::effect::{Effect, SubstanceMapper, Sampling, SubstanceColorEffect, Sub
 ///     .build()
 ///     .run();
 /// ```
+///
+/// Substances can be declared with a name, so that sources and effects can refer to them
+/// by name instead of by index. References are checked when building the simulation.
+///
+/// ```rust,no_run
+/// use aitios::{SimulationBuilder, Substance};
+///
+/// SimulationBuilder::new()
+///     .add_substance(Substance::new("water").color(0.0, 0.0, 1.0))
+///     .add_substance(Substance::new("dirt").range(0.0, 2.0))
+///     .scene("test-scenes/buddha-scene/buddha-scene.obj", |s| s.delta_straight(1.0))
+///     .add_source(|s| {
+///         s.p_straight(1.0)
+///             .substance("water", 1.0)
+///             .pickup_rate("dirt", 0.1)
+///             .point_shaped(0.0, 2.0, 0.0)
+///     })
+///     .add_global_surfel_rule("dirt", "water", 0.5)
+///     .substance_map_size("dirt", 1024, 1024)
+///     .add_effect_density_map()
+///     .output_path("output")
+///     .build()
+///     .run();
+/// ```
 pub struct SimulationBuilder {
     // TODO this should hold SceneBuilder and SurfaceBuilder
     scene: Scene,
     scene_directory: PathBuf,
     surface: Option<Surface>,
+    /// Parameters of the material overrides of the surface, for material transitions
+    material_profiles: HashMap<String, SurfelProfile>,
     iterations: u32,
-    sources: Vec<TonSource>,
-    effects: Vec<Box<Effect>>,
-    substance_map_effects: Vec<Box<SubstanceMapMaterialEffect>>,
+    time_step: Option<f32>,
+    duration: Option<f32>,
+    substances: SubstanceRegistry,
+    sources: Vec<TonSourceBuilder>,
+    effects: Vec<EffectFactory>,
     scene_sinks: Vec<Box<SceneSink>>,
     hit_map_path: Option<PathBuf>,
     surfel_obj_path: Option<PathBuf>,
-    substance_idx: usize,
-    substance_map_width: usize,
-    substance_map_height: usize,
-    substance_map_sampling: Sampling,
-    output_path: Option<PathBuf>
+    /// The substance map configured directly on the simulation builder
+    substance_map: SubstanceMapperBuilder,
+    /// Additional substance maps
+    substance_mappers: Vec<SubstanceMapperBuilder>,
+    output_path: Option<PathBuf>,
+    conserve_substances: bool,
+    max_ton_loads: Vec<f32>
 }
 
 impl SimulationBuilder {
@@ -81,21 +117,35 @@ impl SimulationBuilder {
             scene: Scene::empty(),
             scene_directory: PathBuf::new(),
             surface: None,
+            material_profiles: HashMap::new(),
             iterations: 1,
+            time_step: None,
+            duration: None,
+            substances: SubstanceRegistry::new(),
             sources: Vec::new(),
             effects: Vec::new(),
-            substance_map_effects: Vec::new(),
             scene_sinks: Vec::new(),
             hit_map_path: None,
             surfel_obj_path: None,
-            substance_idx: 0,
-            substance_map_width: 4096,
-            substance_map_height: 4096,
-            substance_map_sampling: Sampling::NearestTriangle,
-            output_path: None
+            substance_map: SubstanceMapperBuilder::new(0),
+            substance_mappers: Vec::new(),
+            output_path: None,
+            conserve_substances: false,
+            max_ton_loads: Vec::new()
         }
     }
 
+    /// Declares a substance, so that it can be referred to by name. Substances are stored on
+    /// surfels and tons in the order they are declared.
+    ///
+    /// If any substances are declared, all of them must be declared before loading the scene,
+    /// so surfels can start with the initial amounts of the substances.
+    pub fn add_substance(mut self, substance: Substance) -> SimulationBuilder {
+        assert!(self.surface.is_none(), "Substance {} must be declared before loading the scene", substance.name());
+        self.substances.add(substance);
+        self
+    }
+
     pub fn scene<F>(mut self, scene_obj_file_path: &str, build_surface: F) -> SimulationBuilder
         where F: FnOnce(SurfaceBuilder) -> SurfaceBuilder
     {
@@ -108,7 +158,15 @@ impl SimulationBuilder {
 
         info!("Generating surface models from meshes... ");
         io::stdout().flush().unwrap();
-        let surface = build_surface(SurfaceBuilder::new())
+        let mut surface_builder = SurfaceBuilder::new();
+        if !self.substances.is_empty() {
+            surface_builder = surface_builder.declare_substances(&self.substances);
+        }
+
+        let surface_builder = build_surface(surface_builder);
+        self.material_profiles = surface_builder.material_profiles();
+
+        let surface = surface_builder
             .add_surface_from_scene(&scene)
             .build();
         info!("Ok, {} surfels", surface.samples.len());
@@ -134,6 +192,53 @@ impl SimulationBuilder {
         }
     }
 
+    /// Sets a field of all surfels from a geometric feature of the scene, mapping feature values
+    /// from 0..1 onto min..max, e.g. to seed dirt in cavities with ambient occlusion instead of
+    /// painting it by hand. Curvature values in -1..0 extrapolate below min.
+    ///
+    /// Must be called after loading the scene.
+    pub fn seed_from_feature(mut self, feature: GeometricFeature, field: SurfelField, min: f32, max: f32) -> SimulationBuilder {
+        let field = field.resolve(&self.substances);
+        let feature_values = self.compute_feature(feature);
+        let surface = self.surface.as_mut().unwrap();
+
+        for (surfel, value) in surface.samples.iter_mut().zip(feature_values) {
+            field.set(surfel, min + value * (max - min));
+        }
+
+        self
+    }
+
+    /// Adds a rule that adds `rate * feature` to the given substance on all surfels in each iteration,
+    /// e.g. a positive rate with curvature lets dirt collect in cavities, while a negative rate with
+    /// curvature on a wear substance lets edges wear off, since edges have negative curvature.
+    ///
+    /// Must be called after loading the scene.
+    pub fn add_feature_rule<S : Into<SubstanceRef>>(mut self, feature: GeometricFeature, substance: S, rate: f32) -> SimulationBuilder {
+        let substance = substance.into();
+
+        self.defer_effect(move |substances| Box::new(
+            FeatureRule::new(substances.resolve(&substance), rate, feature)
+        ));
+
+        self
+    }
+
+    fn defer_effect<F>(&mut self, make: F)
+        where F : FnOnce(&SubstanceRegistry) -> Box<Effect> + 'static
+    {
+        self.effects.push(Box::new(make));
+    }
+
+    fn compute_feature(&self, feature: GeometricFeature) -> Vec<f32> {
+        let surface = self.surface.as_ref()
+            .expect("Scene must be loaded before using geometric features");
+
+        info!("Computing {:?} for {} surfels...", feature, surface.samples.len());
+        let octree : Octree<Triangle> = self.scene.triangles().collect();
+        feature.compute(surface, &octree)
+    }
+
     pub fn output_path<S : Into<PathBuf>>(mut self, path: S) -> SimulationBuilder {
         self.output_path = Some(path.into());
         self
@@ -154,6 +259,45 @@ impl SimulationBuilder {
         self
     }
 
+    /// Sets the amount of simulated time that passes in each iteration, in the unit of time that
+    /// rates of rules and emission rates of sources are given in, e.g. years.
+    ///
+    /// Defaults to one unit of time per iteration.
+    pub fn time_step(mut self, time_step: f32) -> SimulationBuilder {
+        assert!(time_step > 0.0, "Time step must be positive, got {}", time_step);
+        self.time_step = Some(time_step);
+        self
+    }
+
+    /// Sets the total amount of simulated time, which is divided evenly between the iterations,
+    /// e.g. 20 years of weathering in 40 iterations of half a year each. Running more iterations
+    /// for the same duration gives comparable, but finer results.
+    pub fn duration(mut self, duration: f32) -> SimulationBuilder {
+        assert!(duration > 0.0, "Duration must be positive, got {}", duration);
+        self.duration = Some(duration);
+        self
+    }
+
+    /// If set to true, substances are neither created nor destroyed when transported
+    /// between tons and surfels. Settling tons lose what they deposit, excess that a saturated
+    /// surfel cannot hold carries over to the other interacting surfels or stays on the ton,
+    /// and tons only pick up as much as they can carry, see `max_ton_loads`.
+    ///
+    /// Defaults to false, where deposits are clamped at the capacity of surfels, pickups at the
+    /// maximum load of tons, and settling tons deposit without losing their load.
+    pub fn conserve_substances(mut self, conserve_substances: bool) -> SimulationBuilder {
+        self.conserve_substances = conserve_substances;
+        self
+    }
+
+    /// Sets the most of each substance that a ton can carry, in the order substances are
+    /// declared. Substances without an entry default to 1.0.
+    pub fn max_ton_loads(mut self, max_ton_loads: Vec<f32>) -> SimulationBuilder {
+        assert!(max_ton_loads.iter().all(|&l| l >= 0.0), "Maximum ton loads must not be negative, got {:?}", max_ton_loads);
+        self.max_ton_loads = max_ton_loads;
+        self
+    }
+
     pub fn add_environment_source<F>(mut self, build: F) -> SimulationBuilder
         where F: FnOnce(TonSourceBuilder) -> TonSourceBuilder
     {
@@ -164,7 +308,6 @@ impl SimulationBuilder {
         self.sources.push(
             build(TonSourceBuilder::new())
                 .hemisphere_shaped(bottom_center, radius)
-                .build()
         );
 
         self
@@ -175,7 +318,7 @@ impl SimulationBuilder {
     {
 
         self.sources.push(
-            build(TonSourceBuilder::new()).build()
+            build(TonSourceBuilder::new())
         );
 
         self
@@ -191,16 +334,20 @@ impl SimulationBuilder {
         self
     }*/
 
-    pub fn substance_map_size(mut self, substance_idx: usize, width: usize, height: usize) -> SimulationBuilder {
-        self.substance_idx = substance_idx;
-        self.substance_map_width = width;
-        self.substance_map_height = height;
-
+    pub fn substance_map_size<S : Into<SubstanceRef>>(mut self, substance: S, width: usize, height: usize) -> SimulationBuilder {
+        self.substance_map = self.substance_map.substance(substance).size(width, height);
         self
     }
 
     pub fn substance_map_rasterize(mut self, padding: f32) -> SimulationBuilder {
-        self.substance_map_sampling = Sampling::Rasterization(padding);
+        self.substance_map = self.substance_map.rasterize(padding);
+        self
+    }
+
+    /// Sets which surfels contribute to a texel of the substance map, e.g. to keep substances
+    /// from showing through thin walls. Defaults to `Proximity::Euclidean`.
+    pub fn substance_map_proximity(mut self, proximity: Proximity) -> SimulationBuilder {
+        self.substance_map = self.substance_map.proximity(proximity);
         self
     }
 
@@ -210,60 +357,303 @@ impl SimulationBuilder {
     }*/
 
     /// Adds a new surfel rule that gets performed before creating substance maps for surfels of all materials
-    pub fn add_global_surfel_rule(mut self, write_substance_idx: usize, read_substance_idx: usize, rate: f32) -> SimulationBuilder {
-        self.effects.push(
-            Box::new(SurfelRule::new(write_substance_idx, read_substance_idx, rate, iter::empty::<String>()))
-        );
+    pub fn add_global_surfel_rule<W, R>(mut self, write_substance: W, read_substance: R, rate: f32) -> SimulationBuilder
+        where W : Into<SubstanceRef>,
+            R : Into<SubstanceRef>
+    {
+        let write_substance = write_substance.into();
+        let read_substance = read_substance.into();
+
+        self.defer_effect(move |substances| Box::new(
+            SurfelRule::new(substances.resolve(&write_substance), substances.resolve(&read_substance), rate, iter::empty::<String>())
+        ));
 
         self
     }
 
-    pub fn add_material_surfel_rule(mut self, applicable_material: &str, write_substance_idx: usize, read_substance_idx: usize, rate: f32) -> SimulationBuilder {
-        self.effects.push(
-            Box::new(SurfelRule::new(write_substance_idx, read_substance_idx, rate, iter::once(applicable_material)))
-        );
+    pub fn add_material_surfel_rule<W, R>(mut self, applicable_material: &str, write_substance: W, read_substance: R, rate: f32) -> SimulationBuilder
+        where W : Into<SubstanceRef>,
+            R : Into<SubstanceRef>
+    {
+        let applicable_material = String::from(applicable_material);
+        let write_substance = write_substance.into();
+        let read_substance = read_substance.into();
+
+        self.defer_effect(move |substances| Box::new(
+            SurfelRule::new(substances.resolve(&write_substance), substances.resolve(&read_substance), rate, iter::once(applicable_material))
+        ));
 
         self
     }
 
-    pub fn add_effect_density_map(mut self) -> SimulationBuilder {
-        self.substance_map_effects.push(
-            Box::new(
-                SubstanceColorEffect::new(
-                    Vector4::new(1.0, 1.0, 1.0, 1.0), // substance = 0
-                    Vector4::new(0.0, 0.0, 0.0, 1.0), // substance = 1
-                    Vector4::new(0.0, 0.0, 1.0, 1.0),  // substance = NaN
-                )
+    /// Like `add_global_surfel_rule`, but with a rate that varies between surfels, e.g. with noise
+    /// so that rust grows in spots instead of evenly.
+    pub fn add_modulated_surfel_rule<W, R>(mut self, write_substance: W, read_substance: R, rate: f32, modulation: Modulation) -> SimulationBuilder
+        where W : Into<SubstanceRef>,
+            R : Into<SubstanceRef>
+    {
+        let write_substance = write_substance.into();
+        let read_substance = read_substance.into();
+
+        self.defer_effect(move |substances| Box::new(
+            SurfelRule::new(substances.resolve(&write_substance), substances.resolve(&read_substance), rate, iter::empty::<String>())
+                .modulation(modulation)
+        ));
+
+        self
+    }
+
+    /// Like `add_material_surfel_rule`, but with a rate that varies between surfels.
+    pub fn add_modulated_material_surfel_rule<W, R>(mut self, applicable_material: &str, write_substance: W, read_substance: R, rate: f32, modulation: Modulation) -> SimulationBuilder
+        where W : Into<SubstanceRef>,
+            R : Into<SubstanceRef>
+    {
+        let applicable_material = String::from(applicable_material);
+        let write_substance = write_substance.into();
+        let read_substance = read_substance.into();
+
+        self.defer_effect(move |substances| Box::new(
+            SurfelRule::new(substances.resolve(&write_substance), substances.resolve(&read_substance), rate, iter::once(applicable_material))
+                .modulation(modulation)
+        ));
+
+        self
+    }
+
+    /// Like `add_global_surfel_rule`, but limited to or scaled by the orientation, position or entity
+    /// of surfels, e.g. moss growing only on upward surfaces facing north:
+    ///
+    /// ```no_run
+    /// # use aitios::{SimulationBuilder, Condition};
+    /// # extern crate cgmath;
+    /// # use cgmath::Vector3;
+    /// let builder = SimulationBuilder::new()
+    ///     .add_conditional_surfel_rule("moss", "water", 0.1, vec![
+    ///         Condition::facing(Vector3::new(0.0, 1.0, 0.0), 1.0),
+    ///         Condition::weighted_facing(Vector3::new(0.0, 0.0, -1.0))
+    ///     ]);
+    /// ```
+    pub fn add_conditional_surfel_rule<W, R>(mut self, write_substance: W, read_substance: R, rate: f32, conditions: Vec<Condition>) -> SimulationBuilder
+        where W : Into<SubstanceRef>,
+            R : Into<SubstanceRef>
+    {
+        let write_substance = write_substance.into();
+        let read_substance = read_substance.into();
+
+        self.defer_effect(move |substances| Box::new(
+            conditions.into_iter().fold(
+                SurfelRule::new(substances.resolve(&write_substance), substances.resolve(&read_substance), rate, iter::empty::<String>()),
+                SurfelRule::condition
             )
-        );
+        ));
 
         self
     }
 
-    pub fn add_effect_ramp(mut self) -> SimulationBuilder {
-        let material_names = vec![String::from("bronze"), String::from("stone"), String::from("iron")];
-        let segments = vec![
-            RampSegment::new(0.0, 0.1, None, None),
-            RampSegment::new(0.1, 0.25, None, Some(image::open("test-scenes/buddha-scene-iron-concrete/RustPlain018_COL_VAR1_1K.jpg").unwrap())),
-            RampSegment::new(0.25, 1.0, Some(image::open("test-scenes/buddha-scene-iron-concrete/RustPlain018_COL_VAR1_1K.jpg").unwrap()), Some(image::open("test-scenes/buddha-scene-iron-concrete/RustPlain018_COL_VAR1_1K.jpg").unwrap()))
-        ];
+    /// Adds the light reaching each surfel in each iteration to the given substance, e.g. for rules
+    /// that bleach or fade sunlit surfaces or keep moss from growing there. Surfels in shadow of
+    /// the scene get no light from the sun.
+    pub fn add_sun_exposure<S : Into<SubstanceRef>>(mut self, substance: S, light: Light, intensity: f32) -> SimulationBuilder {
+        let substance = substance.into();
+        self.defer_effect(move |substances| Box::new(
+            SunExposure::new(substances.resolve(&substance), light, intensity)
+        ));
+        self
+    }
 
-        let ramp = Box::new(Ramp::new(material_names, PathBuf::from("test-scenes/buddha-scene-iron-concrete/"), segments));
+    /// Makes surfels behave like the given material once the given condition in the expression
+    /// language of `expr` holds, e.g. `"rust > 0.6"`. The material must be configured with
+    /// `override_material` on the surface builder, but does not need to occur in the scene.
+    ///
+    /// # Panics
+    /// If the scene was not loaded yet, the material has no override, or the condition is invalid.
+    pub fn add_material_transition(self, condition: &str, target_material: &str) -> SimulationBuilder {
+        self.add_transition(iter::empty(), condition, target_material)
+    }
 
-        self.substance_map_effects.push(ramp);
+    /// Like `add_material_transition`, but only for surfels sampled from the given material.
+    pub fn add_material_transition_from(self, source_material: &str, condition: &str, target_material: &str) -> SimulationBuilder {
+        self.add_transition(iter::once(String::from(source_material)), condition, target_material)
+    }
+
+    fn add_transition<M>(mut self, source_materials: M, condition: &str, target_material: &str) -> SimulationBuilder
+        where M : IntoIterator<Item = String> + 'static
+    {
+        assert!(self.surface.is_some(), "Scene must be loaded before adding material transitions");
 
+        let profile = self.material_profiles.get(target_material)
+            .cloned()
+            .unwrap_or_else(|| panic!("No override for material {} to transition to, add one with override_material", target_material));
+        let condition_expr = parse_expr(condition)
+            .unwrap_or_else(|err| panic!("Invalid transition condition \"{}\": {}", condition, err));
+
+        self.defer_effect(move |substances| Box::new(
+            MaterialTransition::new(condition_expr.resolve(substances), profile, source_materials)
+        ));
+
+        self
+    }
+
+    /// Adds a custom effect that gets performed after each iteration, before creating substance maps.
+    /// Effects are performed in the order they are added.
+    pub fn add_effect(mut self, effect: Box<Effect>) -> SimulationBuilder {
+        self.defer_effect(move |_| effect);
+        self
+    }
+
+    /// Adds a custom effect that calls the given function with each surfel and the scene after each
+    /// iteration, e.g. for ageing logic that cannot be expressed with rules:
+    ///
+    /// ```rust,no_run
+    /// use aitios::{SimulationBuilder, Surfel, Scene};
+    ///
+    /// let simulation = SimulationBuilder::new()
+    ///     .scene("test-scenes/buddha-scene/buddha-scene.obj", |s| s.substances(&vec![0.0, 0.0]))
+    ///     .add_surfel_effect(|surfel: &mut Surfel, _: &Scene| {
+    ///         // Water turns into rust, but only on the upper side of things
+    ///         if surfel.normal.y > 0.0 {
+    ///             surfel.substances[1] += 0.1 * surfel.substances[0];
+    ///         }
+    ///     })
+    ///     .output_path("output")
+    ///     .build();
+    /// ```
+    pub fn add_surfel_effect<F>(self, effect: F) -> SimulationBuilder
+        where F : Fn(&mut Surfel, &Scene) + 'static
+    {
+        self.add_effect(Box::new(SurfelEffect::new(effect)))
+    }
+
+    /// Adds a rule written in the expression language of `expr` that gets performed on surfels of
+    /// all materials before creating substance maps, e.g. `"rust += 0.2 * water * oxygen"`.
+    ///
+    /// # Panics
+    /// If the rule has a syntax error. Unknown substances are reported when building the simulation.
+    pub fn add_global_expression_rule(self, rule: &str) -> SimulationBuilder {
+        self.add_expression_rule(parse_rule(rule), iter::empty::<String>())
+    }
+
+    /// Like `add_global_expression_rule`, but only for surfels of the given material.
+    pub fn add_material_expression_rule(self, applicable_material: &str, rule: &str) -> SimulationBuilder {
+        self.add_expression_rule(parse_rule(rule), iter::once(applicable_material))
+    }
+
+    /// Adds a rule from assignments built without parsing, limited to the given materials,
+    /// or to all materials if none are given.
+    pub fn add_expression_rule<M, S>(mut self, assignments: Vec<Assignment>, applicable_materials: M) -> SimulationBuilder
+        where M : IntoIterator<Item = S>,
+            S : Into<String>
+    {
+        let applicable_materials : Vec<String> = applicable_materials.into_iter().map(|m| m.into()).collect();
+
+        self.defer_effect(move |substances| Box::new(
+            ExpressionRule::new(
+                assignments.iter().map(|a| a.resolve(substances)).collect(),
+                applicable_materials
+            )
+        ));
+
+        self
+    }
+
+    /// Adds an effect that spreads the given substance to neighbouring surfels in each iteration,
+    /// before creating substance maps. A rate of one evens out neighbouring surfels quickly, while
+    /// a low rate spreads slowly. A positive gravity bias makes the substance spread downwards
+    /// faster than upwards, `rate * (1 + gravity_bias)` must not exceed one.
+    ///
+    /// Surfels exchange the substance with up to 8 nearest neighbours at any distance that do not
+    /// face away from them, see `add_effect_diffusion_in_neighbourhood` to change that.
+    pub fn add_effect_diffusion<S : Into<SubstanceRef>>(self, substance: S, rate: f32, gravity_bias: f32) -> SimulationBuilder {
+        self.add_effect_diffusion_in_neighbourhood(substance, rate, gravity_bias, 8, f32::INFINITY, 0.0)
+    }
+
+    /// Like `add_effect_diffusion`, but surfels exchange the substance with up to the given amount
+    /// of nearest neighbours within the given distance, with normals that have a dot product of at
+    /// least `min_normal_dot` with their own, e.g. -1 to also spread around thin edges.
+    pub fn add_effect_diffusion_in_neighbourhood<S : Into<SubstanceRef>>(mut self, substance: S, rate: f32, gravity_bias: f32, neighbour_count: usize, max_distance: f32, min_normal_dot: f32) -> SimulationBuilder {
+        let substance = substance.into();
+
+        self.defer_effect(move |substances| Box::new(
+            Diffusion::new(substances.resolve(&substance), rate)
+                .gravity_bias(gravity_bias)
+                .neighbourhood(neighbour_count, max_distance, min_normal_dot)
+        ));
+
+        self
+    }
+
+    /// Adds a map that fades from white to the color of the mapped substance, or to black
+    /// if substances were not declared.
+    pub fn add_effect_density_map(mut self) -> SimulationBuilder {
+        self.substance_map = self.substance_map.add_effect_density_map();
+        self
+    }
+
+    pub fn add_effect_ramp(mut self) -> SimulationBuilder {
+        self.substance_map = self.substance_map.add_effect_ramp();
         self
     }
 
     pub fn add_effect_blend<P>(mut self, target_material_names: Vec<String>, texture_base_path: P, blend_target_image: P) -> SimulationBuilder
         where P : Into<PathBuf>
     {
-        self.substance_map_effects.push(
-            Box::new(
-                Blend::new(target_material_names, texture_base_path, &blend_target_image.into())
-            )
-        );
+        self.substance_map = self.substance_map.add_effect_blend(target_material_names, texture_base_path, blend_target_image);
+        self
+    }
 
+    /// Blends the given images over the diffuse textures of the target materials, each one weighted
+    /// by the concentration of its substance, see `SubstanceMapperBuilder::add_effect_layered_blend`.
+    /// The maps are gathered with the size and sampling set with `substance_map_size`.
+    pub fn add_effect_layered_blend<P, S>(mut self, target_material_names: Vec<String>, texture_base_path: P, layers: Vec<(S, P)>) -> SimulationBuilder
+        where P : Into<PathBuf>,
+            S : Into<SubstanceRef>
+    {
+        self.substance_map = self.substance_map.add_effect_layered_blend(target_material_names, texture_base_path, layers);
+        self
+    }
+
+    /// Synthesizes albedo, roughness, metalness, normal and occlusion textures for the target
+    /// materials, blended from the original material towards the weathered material by the
+    /// mapped concentration, see `PbrBlend`.
+    ///
+    /// ```no_run
+    /// # use aitios::{SimulationBuilder, PbrMaterial};
+    /// let rust = PbrMaterial::new()
+    ///     .albedo_texture("textures/rust.png")
+    ///     .roughness(0.9)
+    ///     .metalness(0.0);
+    ///
+    /// let simulation = SimulationBuilder::new()
+    ///     .scene("scene.obj", |s| s)
+    ///     .add_effect_pbr_blend(vec![String::from("iron")], "textures/", rust)
+    ///     .add_scene_sink_gltf("weathered.gltf")
+    ///     .build();
+    /// ```
+    pub fn add_effect_pbr_blend<P>(mut self, target_material_names: Vec<String>, texture_base_path: P, weathered: PbrMaterial) -> SimulationBuilder
+        where P : Into<PathBuf>
+    {
+        self.substance_map = self.substance_map.add_effect_pbr_blend(target_material_names, texture_base_path, weathered);
+        self
+    }
+
+    /// Adds another substance map with its own substance, size, sampling and effects, in addition
+    /// to the one configured with `substance_map_size` and the `add_effect_*` methods, e.g.
+    ///
+    /// ```no_run
+    /// # use aitios::SimulationBuilder;
+    /// let builder = SimulationBuilder::new()
+    ///     .add_substance_mapper("rust", |m| m.size(2048, 2048).add_effect_density_map())
+    ///     .add_substance_mapper("moss", |m| m.size(1024, 1024).rasterize(0.1).add_effect_density_map());
+    /// ```
+    ///
+    /// Mappers with the same size, sampling and proximity share their substance maps, so that the
+    /// map of each substance is only gathered once, even if several of them need it. If other mappers were added, the default
+    /// substance map is only gathered if effects were added to it.
+    pub fn add_substance_mapper<S, F>(mut self, substance: S, build: F) -> SimulationBuilder
+        where S : Into<SubstanceRef>,
+            F : FnOnce(SubstanceMapperBuilder) -> SubstanceMapperBuilder
+    {
+        self.substance_mappers.push(build(SubstanceMapperBuilder::new(substance)));
         self
     }
 
@@ -278,24 +668,159 @@ impl SimulationBuilder {
         self
     }
 
-    pub fn build(mut self) -> Simulation {
-        let substance_mapper = SubstanceMapper::new(
-            self.substance_idx, self.substance_map_sampling, self.substance_map_width, self.substance_map_height, self.substance_map_effects
-        );
+    /// Additionally writes the scene as glTF, with a binary buffer of the same name next to it.
+    pub fn add_scene_sink_gltf(mut self, gltf_file_path: &str) -> SimulationBuilder {
+        self.scene_sinks.push(Box::new(
+            GltfSink::new(gltf_file_path)
+        ));
 
-        self.effects.push(
-            Box::new(substance_mapper)
-        );
+        self
+    }
+
+    /// Builds the simulation, resolving all references to substances.
+    ///
+    /// # Panics
+    /// If substances were declared, but surfels or sources carry a different amount of substances,
+    /// surfels or material overrides have more deposition rates or capacities than substances or
+    /// delta modifiers for undeclared substances, if an unknown substance is referenced, or if both
+    /// a time step and a duration were set.
+    pub fn build(self) -> Simulation {
+        let time_step = match (self.time_step, self.duration) {
+            (Some(_), Some(_)) => panic!("Either a time step or a duration can be set, but not both"),
+            (Some(time_step), None) => time_step,
+            (None, Some(duration)) => duration / self.iterations.max(1) as f32,
+            (None, None) => 1.0
+        };
+
+        let substances = self.substances;
+        let surface = self.surface.expect("Scene must be loaded before building the simulation");
+
+        if !substances.is_empty() {
+            if let Some(surfel) = surface.samples.iter().find(|s| s.substances.len() != substances.len()) {
+                panic!(
+                    "Surfels carry {} substances, but {} substances are declared: {}",
+                    surfel.substances.len(), substances.len(), substances
+                );
+            }
+
+            for surfel in &surface.samples {
+                check_substance_parameters("Surfels", &surfel.deposition_rates, surface.material(surfel), &substances);
+            }
+
+            for (material, profile) in &self.material_profiles {
+                let owner = format!("Override of material {}", material);
+                check_substance_parameters(&owner, &profile.deposition_rates, &profile.material, &substances);
+            }
+
+            assert!(
+                self.max_ton_loads.len() <= substances.len(),
+                "Tons have {} maximum loads, but only {} substances are declared: {}",
+                self.max_ton_loads.len(), substances.len(), substances
+            );
+        }
 
-        Simulation::new(
+        let sources = self.sources.into_iter()
+            .map(|source| source.resolve_substances(&substances).resolve_time_step(time_step).build())
+            .collect();
+
+        let mut effects : Vec<Box<Effect>> = self.effects.into_iter()
+            .map(|make| make(&substances))
+            .collect();
+
+        let mut mapper_builders = self.substance_mappers;
+        if self.substance_map.has_effects() || mapper_builders.is_empty() {
+            mapper_builders.insert(0, self.substance_map);
+        }
+
+        // Mappers that gather maps alike share the maps of their substances
+        let mut mappers : Vec<SubstanceMapper> = Vec::new();
+        for mapper in mapper_builders.into_iter().map(|m| m.build(&substances)) {
+            match mappers.iter().position(|m| m.gathers_maps_alike(&mapper)) {
+                Some(idx) => mappers[idx].merge(mapper),
+                None => mappers.push(mapper)
+            }
+        }
+
+        for (mapper_idx, mapper) in mappers.into_iter().enumerate() {
+            // Keep the names of the first mapper from before there were multiple
+            let output_name = if mapper_idx == 0 {
+                String::from("effect")
+            } else {
+                format!("map-{}-effect", mapper_idx)
+            };
+
+            effects.push(
+                Box::new(mapper.output_name(output_name))
+            );
+        }
+
+        let config = SimulationConfig {
+            iterations: self.iterations,
+            time_step,
+            output_path: self.output_path.unwrap(),
+            hit_map_path: self.hit_map_path,
+            conserve_substances: self.conserve_substances,
+            max_ton_loads: self.max_ton_loads
+        };
+
+        Simulation::with_config(
             self.scene,
-            self.surface.unwrap(),
-            self.iterations,
-            self.sources,
-            self.effects,
+            surface,
+            substances,
+            sources,
+            effects,
             self.scene_sinks,
-            self.output_path.unwrap(),
-            self.hit_map_path
+            config
         )
     }
 }
+
+/// Checks that parameters stored per substance do not refer to more substances than declared.
+fn check_substance_parameters(owner: &str, deposition_rates: &[f32], material: &SurfelMaterial, substances: &SubstanceRegistry) {
+    let capacities = &material.capacities;
+
+    assert!(
+        deposition_rates.len() <= substances.len(),
+        "{} have {} deposition rates, but only {} substances are declared: {}",
+        owner, deposition_rates.len(), substances.len(), substances
+    );
+    assert!(
+        capacities.len() <= substances.len(),
+        "{} have {} capacities, but only {} substances are declared: {}",
+        owner, capacities.len(), substances.len(), substances
+    );
+
+    if let Some(modifier) = material.delta_modifiers.iter().find(|m| m.substance_idx() >= substances.len()) {
+        panic!(
+            "{} have a delta modifier for substance {}, but only {} substances are declared: {}",
+            owner, modifier.substance_idx(), substances.len(), substances
+        );
+    }
+}
+
+fn parse_rule(rule: &str) -> Vec<Assignment> {
+    parse_program(rule).unwrap_or_else(|err| panic!("Invalid surfel rule \"{}\": {}", rule, err))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    #[should_panic(expected = "2 deposition rates, but only 1 substances are declared")]
+    fn test_build_checks_deposition_rates() {
+        let mut builder = SimulationBuilder::new()
+            .add_substance(Substance::new("water"))
+            .output_path("output");
+
+        builder.surface = Some(
+            SurfaceBuilder::new()
+                .declare_substances(&builder.substances)
+                .deposition_rates(vec![0.5, 0.5])
+                .add_surface_from_points(vec![Vector3::new(0.0, 0.0, 0.0)])
+                .build()
+        );
+
+        builder.build();
+    }
+}
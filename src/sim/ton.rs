@@ -5,8 +5,13 @@ use ::cgmath::InnerSpace;
 use ::geom::sampling::TriangleBins;
 use ::geom::sampling::{uniform_on_unit_z_hemisphere, uniform_on_unit_sphere};
 use ::geom::scene::{Scene, Vertex};
+use ::geom::surf::Proximity;
+
+use super::kernel::InteractionKernel;
+use ::substance::{SubstanceRegistry, SubstanceRef};
 
 use std::f32::EPSILON;
+use std::mem;
 
 pub struct Ton {
     /// Probability of moving further in a straight line
@@ -18,6 +23,8 @@ pub struct Ton {
     pub p_flow: f32,
     /// Determines the radius around a ton where it interacts with surface elements.
     pub interaction_radius: f32,
+    /// Weights the substance exchange with surfels by their distance to the point of impact.
+    pub interaction_kernel: InteractionKernel,
     /// Determines the height of a vertical bounce
     pub parabola_height: f32,
     /// Distance that a flowing gammaton is pulled up, affects flow distance
@@ -27,7 +34,32 @@ pub struct Ton {
     /// Amount of substances currently being carried by this ton
     pub substances: Vec<f32>,
     /// Factor by which the gammaton picks up material from surfels
-    pub pickup_rates: Vec<f32>
+    pub pickup_rates: Vec<f32>,
+    /// Determines what happens when the ton misses geometry
+    pub miss_policy: MissPolicy,
+    /// Determines which surfels within the interaction radius the ton interacts with
+    pub proximity: Proximity
+}
+
+/// Determines what happens with a ton that misses the scene geometry after an
+/// interaction, or that hits geometry without any surfels in its interaction radius.
+///
+/// Only `Deposit` rescues tons without surfels in their interaction radius, with the other
+/// policies such tons escape.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MissPolicy {
+    /// The ton leaves the simulation and its substances are booked as escaped.
+    Escape,
+    /// The ton settles at its last point of contact and deposits its substances there.
+    /// If no surfels are within the interaction radius of a contact, the nearest surfel
+    /// receives the substances.
+    Deposit,
+    /// Failed straight or flow motion is turned into a parabolic fall starting at the last
+    /// point of contact. If the parabolic fall misses too, the ton escapes.
+    Parabolic,
+    /// Samples a new diffuse direction at the last point of contact and tries again, up to the
+    /// given number of attempts, before the ton escapes.
+    Retry(u32)
 }
 
 // TODO the sampling should be stratified, e.g. by subdividing the possible directions into patches and ensuring every one gets its turn
@@ -57,6 +89,8 @@ pub struct TonSource {
     p_flow: f32,
     /// Determines the radius around a ton where it interacts with surface elements.
     interaction_radius: f32,
+    /// Weights the substance exchange with surfels by their distance to the point of impact.
+    interaction_kernel: InteractionKernel,
     /// Determines the height of a vertical bounce
     parabola_height: f32,
     /// Distance that a flowing gammaton is pulled up, affects flow distance
@@ -66,7 +100,17 @@ pub struct TonSource {
     /// Amount of substances initially carried by tons emitted by this source
     substances: Vec<f32>,
     emission_count: u32,
-    pickup_rates: Vec<f32>
+    /// If set, overrides the emission count with a possibly fractional amount of tons per iteration
+    emissions_per_iteration: Option<f32>,
+    /// Fraction of a ton that was due in previous iterations, but not emitted yet
+    emission_remainder: f32,
+    /// Amount of tons emitted in the current iteration, set when advancing the source
+    due_emissions: u32,
+    pickup_rates: Vec<f32>,
+    /// Determines what happens when tons emitted by this source miss geometry
+    miss_policy: MissPolicy,
+    /// Determines which surfels within the interaction radius tons emitted by this source interact with
+    proximity: Proximity
 }
 
 pub struct TonSourceBuilder {
@@ -82,9 +126,23 @@ pub struct TonSourceBuilder {
     /// Amount of substances initially carried by tons emitted by this source
     substances: Vec<f32>,
     emission_count: u32,
+    /// If set, overrides the emission count with tons emitted per unit of simulated time
+    emission_rate: Option<f32>,
+    /// Emission rate multiplied with the time step, set when resolving the time step
+    emissions_per_iteration: Option<f32>,
     pickup_rates: Vec<f32>,
+    /// Determines what happens when tons emitted by this source miss geometry
+    miss_policy: MissPolicy,
+    /// Determines which surfels within the interaction radius tons emitted by this source interact with
+    proximity: Proximity,
+    /// Amounts of substances referenced by name or index, set when resolving substances
+    named_substances: Vec<(SubstanceRef, f32)>,
+    /// Pickup rates of substances referenced by name or index, set when resolving substances
+    named_pickup_rates: Vec<(SubstanceRef, f32)>,
     /// Determines the radius around a ton where it interacts with surface elements.
     interaction_radius: f32,
+    /// Weights the substance exchange with surfels by their distance to the point of impact.
+    interaction_kernel: InteractionKernel,
     /// Determines the height of a vertical bounce
     parabola_height: f32,
     /// Distance that a flowing gammaton is pulled up, affects flow distance
@@ -94,20 +152,25 @@ pub struct TonSourceBuilder {
 }
 
 impl TonSource {
-    /// Generates a new gammaton with associated ray origin and ray direction
+    /// Generates the gammatons of the current iteration with associated ray origin and ray direction.
+    ///
+    /// Sources with an emission rate emit no tons until advanced to the first iteration.
     pub fn emit<'a>(&'a self) -> Box<Iterator<Item = (Ton, Vector3<f32>, Vector3<f32>)> + 'a> {
         let p_straight = self.p_straight;
         let p_parabolic = self.p_parabolic;
         let p_flow = self.p_flow;
         let interaction_radius = self.interaction_radius;
+        let interaction_kernel = self.interaction_kernel;
         let parabola_height = self.parabola_height;
         let flow_upward_offset = self.flow_upward_offset;
         let flow_downward_pull = self.flow_downward_pull;
         let substances = self.substances.clone();
         let pickup_rates = self.pickup_rates.clone();
+        let miss_policy = self.miss_policy;
+        let proximity = self.proximity;
         //let shape = self.shape.clone();
 
-        let emissions = (0..self.emission_count).map(
+        let emissions = (0..self.due_emissions).map(
             move |_| {
                 let (origin, direction) = match &self.shape {
                     &Shape::Point { position } => (
@@ -136,11 +199,14 @@ impl TonSource {
                         p_parabolic,
                         p_flow,
                         interaction_radius,
+                        interaction_kernel,
                         parabola_height,
                         flow_upward_offset,
                         flow_downward_pull,
                         substances: substances.clone(),
-                        pickup_rates: pickup_rates.clone()
+                        pickup_rates: pickup_rates.clone(),
+                        miss_policy,
+                        proximity
                     },
                     origin,
                     direction
@@ -151,9 +217,27 @@ impl TonSource {
         Box::new(emissions)
     }
 
+    /// Gets the amount of tons emitted per iteration, rounded if the source has an emission rate.
     pub fn emission_count(&self) -> u32 {
         self.emission_count
     }
+
+    /// Advances the source to the next iteration, determining how many tons `emit` emits in it.
+    /// Call this once per iteration before emitting.
+    ///
+    /// With an emission rate, fractions of tons carry over to later iterations, so that sources
+    /// emitting less than one ton per iteration still emit tons every few iterations.
+    pub fn advance(&mut self) {
+        self.due_emissions = match self.emissions_per_iteration {
+            Some(per_iteration) => {
+                let due = per_iteration + self.emission_remainder;
+                let count = due.floor();
+                self.emission_remainder = due - count;
+                count as u32
+            },
+            None => self.emission_count
+        };
+    }
 }
 
 impl TonSourceBuilder {
@@ -165,11 +249,18 @@ impl TonSourceBuilder {
             substances: Vec::new(),
             shape: Shape::Point { position: Vector3::new(0.0, 0.0, 0.0) },
             emission_count: 10000,
+            emission_rate: None,
+            emissions_per_iteration: None,
             interaction_radius: 0.1,
+            interaction_kernel: InteractionKernel::Box,
             parabola_height: 0.05,
             flow_downward_pull: 0.01,
             flow_upward_offset: 0.002,
-            pickup_rates: Vec::new()
+            pickup_rates: Vec::new(),
+            miss_policy: MissPolicy::Escape,
+            proximity: Proximity::Euclidean,
+            named_substances: Vec::new(),
+            named_pickup_rates: Vec::new()
         }
     }
 
@@ -195,6 +286,13 @@ impl TonSourceBuilder {
         self
     }
 
+    /// Sets the amount of a single substance that emitted tons carry, referenced by name
+    /// or index. Substances that are not set are not carried.
+    pub fn substance<S : Into<SubstanceRef>>(mut self, substance: S, amount: f32) -> TonSourceBuilder {
+        self.named_substances.push((substance.into(), amount));
+        self
+    }
+
     pub fn point_shaped(mut self, pos_x: f32, pos_y: f32, pos_z: f32) -> TonSourceBuilder {
         self.shape = Shape::Point { position: Vector3::new(pos_x, pos_y, pos_z) };
         self
@@ -220,6 +318,17 @@ impl TonSourceBuilder {
 
     pub fn emission_count(mut self, emission_count: u32) -> TonSourceBuilder {
         self.emission_count = emission_count;
+        self.emission_rate = None;
+        self.emissions_per_iteration = None;
+        self
+    }
+
+    /// Sets the amount of tons emitted per unit of simulated time instead of per iteration, so
+    /// that the emission count follows the time step of the simulation. Fractions of tons that are
+    /// due in an iteration carry over to the next one.
+    pub fn emission_rate(mut self, emission_rate: f32) -> TonSourceBuilder {
+        assert!(emission_rate >= 0.0, "Emission rate must not be negative, got {}", emission_rate);
+        self.emission_rate = Some(emission_rate);
         self
     }
 
@@ -228,6 +337,13 @@ impl TonSourceBuilder {
         self
     }
 
+    /// Sets the kernel that distributes substances between the surfels within the
+    /// interaction radius. Defaults to `InteractionKernel::Box`, that is, equal shares.
+    pub fn interaction_kernel(mut self, interaction_kernel: InteractionKernel) -> TonSourceBuilder {
+        self.interaction_kernel = interaction_kernel;
+        self
+    }
+
     pub fn parabola_height(mut self, parabola_height: f32) -> TonSourceBuilder {
         self.parabola_height = parabola_height;
         self
@@ -248,21 +364,113 @@ impl TonSourceBuilder {
         self
     }
 
+    /// Sets the pickup rate of a single substance, referenced by name or index. Substances that
+    /// are not set are not picked up.
+    pub fn pickup_rate<S : Into<SubstanceRef>>(mut self, substance: S, pickup_rate: f32) -> TonSourceBuilder {
+        self.named_pickup_rates.push((substance.into(), pickup_rate));
+        self
+    }
+
+    /// Sets what happens to tons that miss geometry. Defaults to `MissPolicy::Escape`.
+    pub fn miss_policy(mut self, miss_policy: MissPolicy) -> TonSourceBuilder {
+        self.miss_policy = miss_policy;
+        self
+    }
+
+    /// Sets which surfels within the interaction radius tons interact with, e.g. to keep tons
+    /// from depositing on the far side of thin walls. Defaults to `Proximity::Euclidean`.
+    pub fn proximity(mut self, proximity: Proximity) -> TonSourceBuilder {
+        self.proximity = proximity;
+        self
+    }
+
+    /// Applies the amounts and pickup rates set with `substance` and `pickup_rate` and checks that
+    /// amounts and pickup rates are given for exactly the declared substances, if any.
+    pub fn resolve_substances(mut self, registry: &SubstanceRegistry) -> TonSourceBuilder {
+        let named_substances = mem::take(&mut self.named_substances);
+        let named_pickup_rates = mem::take(&mut self.named_pickup_rates);
+
+        if !registry.is_empty() {
+            if self.substances.is_empty() {
+                self.substances = vec![0.0; registry.len()];
+            }
+            if self.pickup_rates.is_empty() {
+                self.pickup_rates = vec![0.0; registry.len()];
+            }
+
+            assert_eq!(
+                self.substances.len(), registry.len(),
+                "Ton source carries {} substances, but {} substances are declared: {}",
+                self.substances.len(), registry.len(), registry
+            );
+            assert_eq!(
+                self.pickup_rates.len(), registry.len(),
+                "Ton source has {} pickup rates, but {} substances are declared: {}",
+                self.pickup_rates.len(), registry.len(), registry
+            );
+        }
+
+        for (substance, amount) in named_substances {
+            let idx = registry.resolve(&substance);
+            Self::set_at(&mut self.substances, idx, amount);
+        }
+
+        for (substance, pickup_rate) in named_pickup_rates {
+            let idx = registry.resolve(&substance);
+            Self::set_at(&mut self.pickup_rates, idx, pickup_rate);
+        }
+
+        self
+    }
+
+    /// Turns an emission rate, if any, into the emission count for iterations that each stand for
+    /// the given amount of simulated time.
+    pub fn resolve_time_step(mut self, time_step: f32) -> TonSourceBuilder {
+        if let Some(emission_rate) = self.emission_rate.take() {
+            let per_iteration = emission_rate * time_step;
+            self.emission_count = per_iteration.round() as u32;
+            self.emissions_per_iteration = Some(per_iteration);
+        }
+
+        self
+    }
+
+    /// Sets the value at the index, growing the values with zeroes if necessary.
+    fn set_at(values: &mut Vec<f32>, idx: usize, value: f32) {
+        if values.len() <= idx {
+            values.resize(idx + 1, 0.0);
+        }
+        values[idx] = value;
+    }
+
     pub fn build(self) -> TonSource {
-        assert_eq!(self.pickup_rates.len(), self.substances.len());
+        // Applies substances set by index if they were not resolved against declared substances before
+        let source = self.resolve_substances(&SubstanceRegistry::new())
+            .resolve_time_step(1.0);
+
+        assert_eq!(source.pickup_rates.len(), source.substances.len());
 
         TonSource {
-            shape: self.shape,
-            p_straight: self.p_straight,
-            p_parabolic: self.p_parabolic,
-            p_flow: self.p_flow,
-            interaction_radius: self.interaction_radius,
-            parabola_height: self.parabola_height,
-            flow_upward_offset: self.flow_upward_offset,
-            flow_downward_pull: self.flow_downward_pull,
-            substances: self.substances,
-            emission_count: self.emission_count,
-            pickup_rates: self.pickup_rates
+            shape: source.shape,
+            p_straight: source.p_straight,
+            p_parabolic: source.p_parabolic,
+            p_flow: source.p_flow,
+            interaction_radius: source.interaction_radius,
+            interaction_kernel: source.interaction_kernel,
+            parabola_height: source.parabola_height,
+            flow_upward_offset: source.flow_upward_offset,
+            flow_downward_pull: source.flow_downward_pull,
+            substances: source.substances,
+            emission_count: source.emission_count,
+            emissions_per_iteration: source.emissions_per_iteration,
+            emission_remainder: 0.0,
+            due_emissions: match source.emissions_per_iteration {
+                Some(_) => 0,
+                None => source.emission_count
+            },
+            pickup_rates: source.pickup_rates,
+            miss_policy: source.miss_policy,
+            proximity: source.proximity
         }
     }
 }
@@ -270,6 +478,7 @@ impl TonSourceBuilder {
 #[cfg(test)]
 mod test {
     use super::*;
+    use ::substance::Substance;
 
     #[test]
     fn test_shoot_from_mesh() {
@@ -282,4 +491,52 @@ mod test {
         assert_eq!(src.emit().count(), 10);
         assert!(src.emit().all(|(ton, origin, direction)| ton.p_flow == 0.2 && origin.y > 0.1 && direction.y < 0.0));
     }
+
+    #[test]
+    fn test_named_substances_are_resolved() {
+        let mut registry = SubstanceRegistry::new();
+        registry.add(Substance::new("water"));
+        registry.add(Substance::new("dust"));
+
+        let src = TonSourceBuilder::new()
+            .emission_count(1)
+            .substance("dust", 0.5)
+            .pickup_rate("water", 0.1)
+            .resolve_substances(&registry)
+            .build();
+
+        let (ton, _, _) = src.emit().next().unwrap();
+        assert_eq!(ton.substances, vec![0.0, 0.5]);
+        assert_eq!(ton.pickup_rates, vec![0.1, 0.0]);
+    }
+
+    #[test]
+    fn test_emission_rate_follows_time_step() {
+        let src = TonSourceBuilder::new()
+            .emission_rate(100.0)
+            .resolve_time_step(0.25)
+            .build();
+        assert_eq!(src.emission_count(), 25);
+
+        let src = TonSourceBuilder::new()
+            .emission_rate(100.0)
+            .build();
+        assert_eq!(src.emission_count(), 100);
+    }
+
+    #[test]
+    fn test_low_emission_rate_carries_over_fractions() {
+        let mut src = TonSourceBuilder::new()
+            .emission_rate(1.0)
+            .resolve_time_step(0.25)
+            .build();
+
+        let counts : Vec<usize> = (0..8)
+            .map(|_| {
+                src.advance();
+                src.emit().count()
+            })
+            .collect();
+        assert_eq!(counts, vec![0, 0, 0, 1, 0, 0, 0, 1]);
+    }
 }
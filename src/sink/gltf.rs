@@ -0,0 +1,358 @@
+
+use super::Result;
+use super::SceneSink;
+use super::{ROUGHNESS_PARAM, METALNESS_PARAM, ORM_MAP_PARAM, normal_map};
+
+use ::geom::scene::{Scene, Entity};
+
+use ::tobj::Material;
+
+use std::path::{Path, PathBuf};
+use std::fs::File;
+use std::io::Write;
+use std::f32;
+
+/// glTF constant for float components of accessors
+const FLOAT : u32 = 5126;
+/// glTF constant for unsigned int components of accessors
+const UNSIGNED_INT : u32 = 5125;
+/// glTF buffer view target for vertex attributes
+const ARRAY_BUFFER : u32 = 34962;
+/// glTF buffer view target for indices
+const ELEMENT_ARRAY_BUFFER : u32 = 34963;
+
+/// Writes the scene as a glTF 2.0 file with metallic-roughness materials and a binary buffer
+/// with the same name next to it, e.g. `weathered.gltf` and `weathered.bin`.
+///
+/// Textures are referenced by the same relative paths as in MTL output. Roughness, metalness
+/// and occlusion maps are only exported if an effect packed them into a single texture,
+/// as done by `PbrBlend`.
+pub struct GltfSink {
+    gltf_path: PathBuf
+}
+
+/// Binary buffer and JSON descriptions of its views and accessors, built up entity by entity.
+struct Buffers {
+    data: Vec<u8>,
+    views: Vec<String>,
+    accessors: Vec<String>
+}
+
+impl GltfSink {
+    pub fn new(gltf_path: &str) -> GltfSink {
+        let gltf_path = PathBuf::from(gltf_path);
+
+        assert!(
+            gltf_path.extension().map(|e| e == "gltf").unwrap_or(false),
+            "Expected a glTF path that ends with the extension .gltf, got {:?}", gltf_path
+        );
+
+        GltfSink { gltf_path }
+    }
+}
+
+impl SceneSink for GltfSink {
+    fn serialize(&self, scene: &Scene, output_prefix: &Path) -> Result<()> {
+        let mut output_path = PathBuf::from(output_prefix);
+        output_path.push(&self.gltf_path);
+
+        let bin_path = output_path.with_extension("bin");
+        let bin_uri = String::from(bin_path.file_name().unwrap().to_str().unwrap());
+
+        info!("Writing glTF output file {:?}...", output_path);
+
+        let mut buffers = Buffers { data: Vec::new(), views: Vec::new(), accessors: Vec::new() };
+        let meshes : Vec<String> = scene.entities.iter()
+            .map(|entity| mesh_json(entity, &mut buffers))
+            .collect();
+
+        let nodes : Vec<String> = scene.entities.iter()
+            .enumerate()
+            .map(|(idx, entity)| format!("{{\"name\":{},\"mesh\":{}}}", json_string(&entity.name), idx))
+            .collect();
+
+        let node_idxs : Vec<String> = (0..nodes.len()).map(|idx| idx.to_string()).collect();
+
+        let mut images = Vec::new();
+        let materials : Vec<String> = scene.materials.iter()
+            .map(|material| material_json(material, &mut images))
+            .collect();
+
+        let image_jsons : Vec<String> = images.iter()
+            .map(|uri| format!("{{\"uri\":{}}}", json_string(uri)))
+            .collect();
+
+        let textures : Vec<String> = (0..images.len())
+            .map(|idx| format!("{{\"source\":{}}}", idx))
+            .collect();
+
+        let buffer = format!("{{\"uri\":{},\"byteLength\":{}}}", json_string(&bin_uri), buffers.data.len());
+        let scenes = format!("{{\"nodes\":[{}]}}", node_idxs.join(","));
+
+        // glTF does not allow empty arrays, leave them out instead
+        let properties : Vec<String> = vec![
+            ("scenes", vec![scenes]),
+            ("nodes", nodes),
+            ("meshes", meshes),
+            ("materials", materials),
+            ("textures", textures),
+            ("images", image_jsons),
+            ("accessors", buffers.accessors),
+            ("bufferViews", buffers.views),
+            ("buffers", vec![buffer])
+        ].into_iter()
+            .filter(|(_, items)| !items.is_empty())
+            .map(|(key, items)| format!("\"{}\":[{}]", key, items.join(",")))
+            .collect();
+
+        let gltf = format!(
+            "{{\"asset\":{{\"version\":\"2.0\",\"generator\":\"aitios\"}},\"scene\":0,{}}}\n",
+            properties.join(",")
+        );
+
+        File::create(&output_path)?.write_all(gltf.as_bytes())?;
+
+        info!("Writing glTF buffer {:?}...", bin_path);
+        File::create(&bin_path)?.write_all(&buffers.data)?;
+
+        Ok(())
+    }
+}
+
+impl Buffers {
+    /// Appends the values as a new buffer view with an accessor and returns the index of the accessor.
+    fn push(&mut self, bytes: Vec<u8>, target: u32, component_type: u32, count: usize, accessor_type: &str, bounds: Option<(Vec<f32>, Vec<f32>)>) -> usize {
+        self.views.push(format!(
+            "{{\"buffer\":0,\"byteOffset\":{},\"byteLength\":{},\"target\":{}}}",
+            self.data.len(), bytes.len(), target
+        ));
+        self.data.extend(bytes);
+
+        let bounds = match bounds {
+            Some((min, max)) => format!(",\"min\":[{}],\"max\":[{}]", json_numbers(&min), json_numbers(&max)),
+            None => String::new()
+        };
+
+        self.accessors.push(format!(
+            "{{\"bufferView\":{},\"componentType\":{},\"count\":{},\"type\":\"{}\"{}}}",
+            self.views.len() - 1, component_type, count, accessor_type, bounds
+        ));
+
+        self.accessors.len() - 1
+    }
+}
+
+fn mesh_json(entity: &Entity, buffers: &mut Buffers) -> String {
+    let mesh = &entity.mesh;
+    let vertex_count = mesh.positions.len() / 3;
+
+    let (min, max) = mesh.positions.chunks(3).fold(
+        (vec![f32::INFINITY; 3], vec![f32::NEG_INFINITY; 3]),
+        |(mut min, mut max), position| {
+            for axis in 0..3 {
+                min[axis] = min[axis].min(position[axis]);
+                max[axis] = max[axis].max(position[axis]);
+            }
+            (min, max)
+        }
+    );
+
+    let positions = buffers.push(float_bytes(mesh.positions.iter().cloned()), ARRAY_BUFFER, FLOAT, vertex_count, "VEC3", Some((min, max)));
+    let mut attributes = format!("\"POSITION\":{}", positions);
+
+    if mesh.normals.len() == mesh.positions.len() {
+        let normals = buffers.push(float_bytes(mesh.normals.iter().cloned()), ARRAY_BUFFER, FLOAT, vertex_count, "VEC3", None);
+        attributes.push_str(&format!(",\"NORMAL\":{}", normals));
+    }
+
+    if mesh.texcoords.len() == vertex_count * 2 {
+        // OBJ has v pointing up, glTF has it pointing down
+        let flipped = mesh.texcoords.chunks(2).flat_map(|t| vec![t[0], 1.0 - t[1]]);
+        let texcoords = buffers.push(float_bytes(flipped), ARRAY_BUFFER, FLOAT, vertex_count, "VEC2", None);
+        attributes.push_str(&format!(",\"TEXCOORD_0\":{}", texcoords));
+    }
+
+    let index_bytes = mesh.indices.iter().flat_map(|i| i.to_le_bytes().to_vec()).collect();
+    let indices = buffers.push(index_bytes, ELEMENT_ARRAY_BUFFER, UNSIGNED_INT, mesh.indices.len(), "SCALAR", None);
+
+    format!(
+        "{{\"name\":{},\"primitives\":[{{\"attributes\":{{{}}},\"indices\":{},\"material\":{}}}]}}",
+        json_string(&entity.name), attributes, indices, entity.material_idx
+    )
+}
+
+/// Converts the material into metallic-roughness form, adding its textures to the given images
+/// if not already contained.
+fn material_json(material: &Material, images: &mut Vec<String>) -> String {
+    let mut texture = |uri: &str| match images.iter().position(|u| u == uri) {
+        Some(idx) => idx,
+        None => {
+            images.push(String::from(uri));
+            images.len() - 1
+        }
+    };
+
+    let orm_map = material.unknown_param.get(ORM_MAP_PARAM);
+
+    // glTF multiplies the texture with the factors, so with a texture they must not scale it down
+    let factor = |key: &str, default: f32| match orm_map {
+        Some(_) => 1.0,
+        None => material.unknown_param.get(key)
+            .and_then(|v| v.trim().parse::<f32>().ok())
+            .unwrap_or(default)
+    };
+
+    let mut pbr = format!(
+        "\"baseColorFactor\":[{},{}],\"metallicFactor\":{},\"roughnessFactor\":{}",
+        json_numbers(&material.diffuse), material.dissolve, factor(METALNESS_PARAM, 0.0), factor(ROUGHNESS_PARAM, 1.0)
+    );
+    let mut extra = String::new();
+
+    if !material.diffuse_texture.is_empty() {
+        pbr.push_str(&format!(",\"baseColorTexture\":{{\"index\":{}}}", texture(&material.diffuse_texture)));
+    }
+
+    if let Some(orm) = orm_map {
+        let orm = texture(orm);
+        pbr.push_str(&format!(",\"metallicRoughnessTexture\":{{\"index\":{}}}", orm));
+        extra.push_str(&format!(",\"occlusionTexture\":{{\"index\":{}}}", orm));
+    }
+
+    // The normal texture of materials holds map_Ns, which is not a normal map
+    if let Some(normal_map) = normal_map(material) {
+        extra.push_str(&format!(",\"normalTexture\":{{\"index\":{}}}", texture(normal_map)));
+    }
+
+    format!("{{\"name\":{},\"pbrMetallicRoughness\":{{{}}}{}}}", json_string(&material.name), pbr, extra)
+}
+
+fn float_bytes<I : Iterator<Item = f32>>(values: I) -> Vec<u8> {
+    values.flat_map(|v| v.to_le_bytes().to_vec()).collect()
+}
+
+fn json_numbers(values: &[f32]) -> String {
+    let values : Vec<String> = values.iter().map(|v| v.to_string()).collect();
+    values.join(",")
+}
+
+/// Quotes the string for use in JSON, escaping quotes, backslashes and control characters.
+fn json_string(string: &str) -> String {
+    let mut quoted = String::from("\"");
+
+    for c in string.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c)
+        }
+    }
+
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::geom::scene::Mesh;
+    use ::sink::NORMAL_MAP_PARAM;
+    use ::sim::{PbrBlend, PbrMaterial, SubstanceMap, SubstanceMapMaterialEffect};
+    use std::collections::HashMap;
+    use std::fs;
+
+    fn material() -> Material {
+        let mut unknown_param = HashMap::new();
+        unknown_param.insert(String::from(ORM_MAP_PARAM), String::from("rust-orm.png"));
+        unknown_param.insert(String::from(NORMAL_MAP_PARAM), String::from("rust-normal.png"));
+
+        Material {
+            name: String::from("rusty \"iron\""),
+            ambient: [1.0; 3],
+            diffuse: [1.0; 3],
+            specular: [0.0; 3],
+            shininess: 0.0,
+            dissolve: 1.0,
+            optical_density: 1.0,
+            ambient_texture: String::new(),
+            diffuse_texture: String::from("rust-albedo.png"),
+            specular_texture: String::new(),
+            normal_texture: String::new(),
+            dissolve_texture: String::new(),
+            illumination_model: None,
+            unknown_param
+        }
+    }
+
+    /// Scene with a single triangle without normals, made of the test material
+    fn triangle() -> Scene {
+        let mut scene = Scene::with_mesh(Mesh {
+            indices: vec![0, 1, 2],
+            positions: vec![0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0],
+            normals: Vec::new(),
+            texcoords: vec![0.0, 0.0, 1.0, 0.0, 0.0, 1.0]
+        });
+        scene.materials.push(material());
+        scene
+    }
+
+    #[test]
+    fn test_writes_gltf_and_buffer() {
+        let scene = triangle();
+
+        let output_dir = Path::new("test-output/gltf-sink");
+        fs::create_dir_all(output_dir).unwrap();
+
+        GltfSink::new("triangle.gltf").serialize(&scene, output_dir).unwrap();
+
+        let gltf = fs::read_to_string(output_dir.join("triangle.gltf")).unwrap();
+        assert!(gltf.contains("\"name\":\"rusty \\\"iron\\\"\""));
+        assert!(gltf.contains("\"metallicFactor\":1,"));
+        assert!(gltf.contains("\"metallicRoughnessTexture\":{\"index\":1}"));
+        assert!(gltf.contains("\"occlusionTexture\":{\"index\":1}"));
+        assert!(gltf.contains("\"normalTexture\":{\"index\":2}"));
+        assert!(gltf.contains("\"max\":[1,1,0]"));
+        assert!(!gltf.contains("NORMAL"));
+
+        // Positions, texcoords and indices
+        let buffer = fs::read(output_dir.join("triangle.bin")).unwrap();
+        assert_eq!(buffer.len(), 9 * 4 + 6 * 4 + 3 * 4);
+        assert!(gltf.contains(&format!("\"byteLength\":{}}}]", buffer.len())));
+    }
+
+    #[test]
+    fn test_blended_material_keeps_full_metalness_factor() {
+        let output_dir = Path::new("test-output/gltf-pbr-blend");
+        fs::create_dir_all(output_dir).unwrap();
+
+        let mut iron = material();
+        iron.name = String::from("iron");
+        iron.unknown_param = HashMap::new();
+
+        let steel = PbrMaterial::new().roughness(0.2).metalness(1.0);
+        let rust = PbrMaterial::new().roughness(0.9).metalness(0.0);
+        let blend = PbrBlend::new(vec![String::from("iron")], output_dir, rust).clean(steel);
+        let clean_everywhere = SubstanceMap::new(2, 2, 0, 0, vec![0.0; 4]);
+
+        let blended = blend.perform(&triangle().entities[0], &iron, &clean_everywhere, &output_dir.join("iron"))
+            .expect("Expected PbrBlend to produce a material for iron");
+
+        let json = material_json(&blended, &mut Vec::new());
+        assert!(json.contains("\"metallicFactor\":1,"), "Metalness of the texture scaled down: {}", json);
+        assert!(json.contains("\"roughnessFactor\":1,"), "Roughness of the texture scaled down: {}", json);
+        assert!(json.contains("\"metallicRoughnessTexture\""));
+        assert!(json.contains("\"normalTexture\""), "Blended normal map not exported: {}", json);
+    }
+
+    #[test]
+    fn test_specular_exponent_and_bump_maps_are_no_normal_texture() {
+        let mut iron = material();
+        iron.unknown_param = HashMap::new();
+        // The MTL loader stores map_Ns here
+        iron.normal_texture = String::from("iron-shininess.png");
+        iron.unknown_param.insert(String::from("map_Bump"), String::from("iron-height.png"));
+
+        let json = material_json(&iron, &mut Vec::new());
+        assert!(!json.contains("normalTexture"), "Exported map_Ns or map_Bump as normal map: {}", json);
+    }
+}
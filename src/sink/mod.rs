@@ -1,6 +1,8 @@
 
 use ::geom::scene::Scene;
 
+use ::tobj::Material;
+
 use std::fmt;
 use std::error;
 use std::result;
@@ -8,9 +10,33 @@ use std::io;
 
 use std::path::Path;
 
+pub mod gltf;
 pub mod mtl;
 pub mod obj;
 
+/// Material parameter with a roughness texture, as in the PBR extension of MTL.
+pub const ROUGHNESS_MAP_PARAM : &str = "map_Pr";
+/// Material parameter with a metalness texture, as in the PBR extension of MTL.
+pub const METALNESS_MAP_PARAM : &str = "map_Pm";
+/// Material parameter with a constant roughness, as in the PBR extension of MTL.
+pub const ROUGHNESS_PARAM : &str = "Pr";
+/// Material parameter with a constant metalness, as in the PBR extension of MTL.
+pub const METALNESS_PARAM : &str = "Pm";
+/// Material parameter with a texture that packs ambient occlusion, roughness and metalness into
+/// the red, green and blue channels, for formats like glTF that expect them combined. Not
+/// written to MTL files.
+pub const ORM_MAP_PARAM : &str = "map_ORM";
+/// Material parameter with a tangent space normal map. The MTL loader keeps it as an unknown
+/// parameter, since it stores `map_Ns` in the normal texture of materials.
+pub const NORMAL_MAP_PARAM : &str = "norm";
+
+/// Gets the file name of the normal map of the material from `norm`, skipping options that
+/// precede the file name. `map_Bump` and `bump` usually hold height maps and are not used.
+pub fn normal_map(material: &Material) -> Option<&str> {
+    material.unknown_param.get(NORMAL_MAP_PARAM)
+        .and_then(|value| value.split_whitespace().last())
+}
+
 pub type Result<T> = result::Result<T, Error>;
 
 pub trait SceneSink {
@@ -1,6 +1,7 @@
 
 use super::Result;
 use super::SceneSink;
+use super::{ROUGHNESS_PARAM, METALNESS_PARAM, ROUGHNESS_MAP_PARAM, METALNESS_MAP_PARAM, normal_map};
 
 use ::geom::scene::Scene;
 
@@ -54,8 +55,20 @@ impl SceneSink for MtlSink {
                 mtl.write(format!("map_Ks {}\n", material.specular_texture).as_bytes())?;
             }
 
+            // The loader reads map_Ns into the normal texture, so write it back there
             if !material.normal_texture.is_empty() {
-                mtl.write(format!("norm {}\n", material.normal_texture).as_bytes())?;
+                mtl.write(format!("map_Ns {}\n", material.normal_texture).as_bytes())?;
+            }
+
+            if let Some(normal_map) = normal_map(material) {
+                mtl.write_all(format!("norm {}\n", normal_map).as_bytes())?;
+            }
+
+            // PBR extension, e.g. for weathered materials with roughness and metalness maps
+            for key in &[ROUGHNESS_PARAM, METALNESS_PARAM, ROUGHNESS_MAP_PARAM, METALNESS_MAP_PARAM] {
+                if let Some(value) = material.unknown_param.get(*key) {
+                    mtl.write_all(format!("{} {}\n", key, value).as_bytes())?;
+                }
             }
         }
 
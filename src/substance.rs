@@ -0,0 +1,231 @@
+//! Declares the substances of a simulation by name, so builders can refer to them
+//! without knowing their position in the amounts stored on surfels and tons.
+
+use std::fmt;
+use std::slice;
+
+use ::cgmath::Vector4;
+
+/// A substance that surfels and tons can carry, e.g. water, dust or rust.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Substance {
+    name: String,
+    initial: f32,
+    min: f32,
+    max: f32,
+    color: Vector4<f32>
+}
+
+/// Refers to a declared substance, either by its name or by its position in the
+/// amounts stored on surfels and tons.
+///
+/// Builders accept anything that converts into a reference, so both `0` and
+/// `"water"` can be passed where a substance is expected.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SubstanceRef {
+    Idx(usize),
+    Name(String)
+}
+
+/// The substances of a simulation, in the order of the amounts stored on surfels and tons.
+///
+/// If no substances are declared, substances can only be referred to by index and are not
+/// validated, as in simulations built before substances had names.
+#[derive(Debug, Clone, Default)]
+pub struct SubstanceRegistry {
+    substances: Vec<Substance>
+}
+
+impl Substance {
+    /// Declares a substance with the given name that surfels initially carry none of, that is
+    /// clamped into 0..1 and shows as black in debug output.
+    pub fn new<S : Into<String>>(name: S) -> Substance {
+        Substance {
+            name: name.into(),
+            initial: 0.0,
+            min: 0.0,
+            max: 1.0,
+            color: Vector4::new(0.0, 0.0, 0.0, 1.0)
+        }
+    }
+
+    /// Sets the amount that surfels carry before the simulation, unless set on the surface.
+    pub fn initial(mut self, initial: f32) -> Substance {
+        self.initial = initial;
+        self
+    }
+
+    /// Sets the range that amounts on surfels are clamped into after tracing in each iteration.
+    pub fn range(mut self, min: f32, max: f32) -> Substance {
+        assert!(min <= max, "Minimum {} of substance {} exceeds maximum {}", min, self.name, max);
+
+        self.min = min;
+        self.max = max;
+        self
+    }
+
+    /// Sets the color that the maximum amount of the substance shows as in density maps and
+    /// other debug output, with components in 0..1.
+    pub fn color(mut self, red: f32, green: f32, blue: f32) -> Substance {
+        self.color = Vector4::new(red, green, blue, 1.0);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn initial_amount(&self) -> f32 {
+        self.initial
+    }
+
+    pub fn min(&self) -> f32 {
+        self.min
+    }
+
+    pub fn max(&self) -> f32 {
+        self.max
+    }
+
+    pub fn debug_color(&self) -> Vector4<f32> {
+        self.color
+    }
+
+    /// Clamps the given amount into the range of the substance.
+    pub fn clamp(&self, amount: f32) -> f32 {
+        amount.clamp(self.min, self.max)
+    }
+}
+
+impl SubstanceRegistry {
+    pub fn new() -> SubstanceRegistry {
+        SubstanceRegistry { substances: Vec::new() }
+    }
+
+    /// Declares another substance and returns its index.
+    ///
+    /// # Panics
+    /// If a substance with the same name was declared before.
+    pub fn add(&mut self, substance: Substance) -> usize {
+        assert!(
+            self.substances.iter().all(|s| s.name != substance.name),
+            "Substance {} was declared twice", substance.name
+        );
+
+        self.substances.push(substance);
+        self.substances.len() - 1
+    }
+
+    pub fn len(&self) -> usize {
+        self.substances.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.substances.is_empty()
+    }
+
+    pub fn get(&self, idx: usize) -> Option<&Substance> {
+        self.substances.get(idx)
+    }
+
+    pub fn iter<'a>(&'a self) -> slice::Iter<'a, Substance> {
+        self.substances.iter()
+    }
+
+    /// Finds the index of the referenced substance.
+    ///
+    /// # Panics
+    /// If no substance with the name was declared, or if the index is out of range of
+    /// the declared substances. Indexes are not checked if no substances were declared.
+    pub fn resolve(&self, substance: &SubstanceRef) -> usize {
+        match *substance {
+            SubstanceRef::Idx(idx) => {
+                assert!(
+                    self.is_empty() || idx < self.len(),
+                    "Substance index {} out of range, only {} substances declared", idx, self.len()
+                );
+                idx
+            },
+            SubstanceRef::Name(ref name) => self.substances.iter()
+                .position(|s| &s.name == name)
+                .unwrap_or_else(|| panic!("Unknown substance {}, declared substances: {}", name, self))
+        }
+    }
+
+    /// Gets the initial amount of each declared substance, in order.
+    pub fn initial_amounts(&self) -> Vec<f32> {
+        self.substances.iter()
+            .map(Substance::initial_amount)
+            .collect()
+    }
+
+    /// Clamps each amount into the range of the substance at the same index.
+    pub fn clamp(&self, amounts: &mut [f32]) {
+        for (amount, substance) in amounts.iter_mut().zip(&self.substances) {
+            *amount = substance.clamp(*amount);
+        }
+    }
+}
+
+impl fmt::Display for SubstanceRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.substances.is_empty() {
+            return write!(f, "none");
+        }
+
+        let names : Vec<&str> = self.substances.iter().map(Substance::name).collect();
+        write!(f, "{}", names.join(", "))
+    }
+}
+
+impl From<usize> for SubstanceRef {
+    fn from(idx: usize) -> SubstanceRef {
+        SubstanceRef::Idx(idx)
+    }
+}
+
+impl<'a> From<&'a str> for SubstanceRef {
+    fn from(name: &'a str) -> SubstanceRef {
+        SubstanceRef::Name(String::from(name))
+    }
+}
+
+impl From<String> for SubstanceRef {
+    fn from(name: String) -> SubstanceRef {
+        SubstanceRef::Name(name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn registry() -> SubstanceRegistry {
+        let mut registry = SubstanceRegistry::new();
+        registry.add(Substance::new("water"));
+        registry.add(Substance::new("dust").initial(0.2).range(0.0, 2.0));
+        registry
+    }
+
+    #[test]
+    fn test_resolve_by_name_and_index() {
+        let registry = registry();
+
+        assert_eq!(registry.resolve(&"dust".into()), 1);
+        assert_eq!(registry.resolve(&0.into()), 0);
+        assert_eq!(registry.initial_amounts(), vec![0.0, 0.2]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown substance rust")]
+    fn test_unknown_name_panics() {
+        registry().resolve(&"rust".into());
+    }
+
+    #[test]
+    fn test_clamp_uses_ranges() {
+        let mut amounts = vec![1.5, 1.5];
+        registry().clamp(&mut amounts);
+        assert_eq!(amounts, vec![1.0, 1.5]);
+    }
+}
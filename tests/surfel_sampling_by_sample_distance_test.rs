@@ -5,6 +5,8 @@ extern crate chrono;
 
 mod common;
 
+use std::fs::File;
+
 #[cfg_attr(not(feature = "expensive_tests"), ignore)]
 #[test]
 fn surfel_sampling_by_sample_distance_test() {
@@ -24,7 +26,9 @@ fn surfel_sampling_by_sample_distance_test() {
     surfels_path.push("surfels_by_distance");
     surfels_path.set_extension("obj");
 
-    aitios::SimulationBuilder::new()
+    let heatmap_directory = directory.clone();
+
+    let mut simulation = aitios::SimulationBuilder::new()
         .surfel_obj_path(surfels_path)
         .scene(
             &model_obj_path,
@@ -86,6 +90,15 @@ fn surfel_sampling_by_sample_distance_test() {
         .output_path(directory)
         .hit_map_path(hit_map_path)
         .iterations(3)
-        .build()
-        .run();
+        .build();
+
+    let report = simulation.sampling_report();
+    info!("Sampling quality:\n{}", report);
+
+    let mut heatmap_path = heatmap_directory;
+    heatmap_path.push("nearest_neighbour_heatmap_by_sample_distance");
+    heatmap_path.set_extension("ply");
+    report.write_heatmap_ply(simulation.surface(), &mut File::create(heatmap_path).unwrap()).unwrap();
+
+    simulation.run();
 }